@@ -0,0 +1,176 @@
+use std::io;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    chunk::{Chunk, ChunkRef},
+    limits::DecodeLimits,
+    png::Png,
+    stream::{chunk_frame, LazyChunk, ReadState, StreamError},
+};
+
+/// The async version of `ChunkReader`, reading the chunks of a PNG from a stream one at a
+/// time and checking their CRCs. Reading stops after IEND.
+#[derive(Debug)]
+pub struct AsyncChunkReader<R> {
+    inner: R,
+    state: ReadState,
+}
+
+impl<R: AsyncRead + Unpin> AsyncChunkReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self::with_limits(inner, DecodeLimits::UNLIMITED)
+    }
+
+    /// Creates a reader that fails with `LimitExceeded` when the stream exceeds the limits.
+    pub fn with_limits(inner: R, limits: DecodeLimits) -> Self {
+        Self {
+            inner,
+            state: ReadState::new(limits),
+        }
+    }
+
+    /// Reads the next chunk, or returns `None` after IEND or at the end of the stream.
+    pub async fn next_chunk(&mut self) -> Result<Option<Chunk>, StreamError> {
+        while !self.state.is_done() {
+            let read = self.inner.read(self.state.buf()).await?;
+            if read == 0 {
+                self.state.end_of_stream()?;
+                return Ok(None);
+            }
+            if let Some(LazyChunk::Loaded(chunk)) = self.state.feed(read)? {
+                return Ok(Some(chunk));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns the underlying reader, positioned after the last chunk read.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+/// Reads a whole PNG from an async stream, including any bytes after IEND.
+pub async fn read_png_async<R: AsyncRead + Unpin>(reader: R) -> Result<Png<'static>, StreamError> {
+    read_png_async_with_limits(reader, &DecodeLimits::UNLIMITED).await
+}
+
+/// Reads a whole PNG from an untrusted async stream, failing with `LimitExceeded` as soon
+/// as the stream exceeds the limits.
+pub async fn read_png_async_with_limits<R: AsyncRead + Unpin>(
+    reader: R,
+    limits: &DecodeLimits,
+) -> Result<Png<'static>, StreamError> {
+    let mut reader = AsyncChunkReader::with_limits(reader, *limits);
+    let mut chunks = Vec::new();
+    while let Some(chunk) = reader.next_chunk().await? {
+        chunks.push(chunk);
+    }
+
+    // One byte past the limit is enough to tell that it was exceeded
+    let mut trailing = Vec::new();
+    let limit = reader.state.remaining_size().saturating_add(1);
+    (&mut reader.inner)
+        .take(limit)
+        .read_to_end(&mut trailing)
+        .await?;
+    reader.state.finish(chunks, trailing)
+}
+
+/// The async version of `PngWriter`, writing a PNG to a stream chunk by chunk.
+#[derive(Debug)]
+pub struct AsyncPngWriter<W> {
+    inner: W,
+}
+
+impl<W: AsyncWrite + Unpin> AsyncPngWriter<W> {
+    /// Creates a writer, writing the PNG signature.
+    pub async fn new(mut inner: W) -> io::Result<Self> {
+        inner.write_all(&Png::STANDARD_HEADER).await?;
+        Ok(Self { inner })
+    }
+
+    pub async fn write_chunk(&mut self, chunk: &ChunkRef<'_>) -> io::Result<()> {
+        let (header, crc) = chunk_frame(chunk);
+        self.inner.write_all(&header).await?;
+        self.inner.write_all(chunk.data()).await?;
+        self.inner.write_all(&crc).await
+    }
+
+    /// Writes bytes after the last chunk, which should be IEND.
+    pub async fn write_trailing_data(&mut self, data: &[u8]) -> io::Result<()> {
+        self.inner.write_all(data).await
+    }
+
+    /// Flushes the writer and returns the underlying stream.
+    pub async fn finish(mut self) -> io::Result<W> {
+        self.inner.flush().await?;
+        Ok(self.inner)
+    }
+}
+
+/// Writes a whole PNG to an async stream, including any bytes after IEND.
+pub async fn write_png_async<W: AsyncWrite + Unpin>(writer: W, png: &Png<'_>) -> io::Result<W> {
+    let mut writer = AsyncPngWriter::new(writer).await?;
+    for chunk in png.chunks() {
+        writer.write_chunk(&chunk).await?;
+    }
+    writer.write_trailing_data(png.trailing_data()).await?;
+    writer.finish().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        chunk::ChunkDecodeError,
+        png::PngDecodeError,
+        stream::{read_png, write_png},
+    };
+
+    const DICE: &[u8] = include_bytes!("../tests/data/dice.png");
+
+    #[tokio::test]
+    async fn test_round_trip() {
+        let mut bytes = DICE.to_vec();
+        bytes.extend(b"trailing");
+
+        let png = read_png_async(bytes.as_slice()).await.unwrap();
+        assert_eq!(
+            png.as_bytes(),
+            read_png(bytes.as_slice()).unwrap().as_bytes()
+        );
+        let written = write_png_async(Vec::new(), &png).await.unwrap();
+        assert_eq!(written, write_png(Vec::new(), &png).unwrap());
+        assert_eq!(written, bytes);
+    }
+
+    #[tokio::test]
+    async fn test_errors() {
+        let mut bytes = DICE.to_vec();
+        bytes[100] ^= 1;
+        assert!(matches!(
+            read_png_async(bytes.as_slice()).await,
+            Err(StreamError::Decode(PngDecodeError::ChunkDecode(
+                ChunkDecodeError::CrcMismatch { offset: 83, .. }
+            )))
+        ));
+
+        let limits = DecodeLimits {
+            max_chunk_length: 4000,
+            ..DecodeLimits::default()
+        };
+        let mut reader = AsyncChunkReader::with_limits(DICE, limits);
+        for _ in 0..4 {
+            assert!(reader.next_chunk().await.unwrap().is_some());
+        }
+        assert!(matches!(
+            reader.next_chunk().await,
+            Err(StreamError::Decode(PngDecodeError::ChunkDecode(
+                ChunkDecodeError::LimitExceeded { offset: 83, .. }
+            )))
+        ));
+        assert!(reader.next_chunk().await.unwrap().is_none());
+    }
+}
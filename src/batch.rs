@@ -0,0 +1,433 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt::{self, Display},
+    fs, io,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc,
+    },
+    thread,
+};
+
+/// Characters that make an input a glob pattern rather than a path.
+const GLOB_CHARS: [char; 3] = ['*', '?', '['];
+
+#[derive(Debug)]
+pub enum BatchError {
+    Io(io::Error),
+    Pattern(glob::PatternError),
+    NoMatches(String),
+    /// Two inputs would be saved to the same output path.
+    DuplicateOutput {
+        output: PathBuf,
+        first: PathBuf,
+        second: PathBuf,
+    },
+}
+
+impl Display for BatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => writeln!(f, "File error: {err}"),
+            Self::Pattern(err) => writeln!(f, "Invalid glob pattern: {err}"),
+            Self::NoMatches(input) => writeln!(f, "No PNG files found for {input}"),
+            Self::DuplicateOutput {
+                output,
+                first,
+                second,
+            } => writeln!(
+                f,
+                "{} and {} would both be saved to {}",
+                first.display(),
+                second.display(),
+                output.display()
+            ),
+        }
+    }
+}
+
+impl From<io::Error> for BatchError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<glob::PatternError> for BatchError {
+    fn from(err: glob::PatternError) -> Self {
+        Self::Pattern(err)
+    }
+}
+
+/// How a command runs over several files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchOptions {
+    /// The maximum number of files processed at once.
+    pub jobs: usize,
+    /// Where results are saved, mirroring the layout of the inputs. Results replace their
+    /// inputs if not given.
+    pub output_dir: Option<PathBuf>,
+}
+
+impl Default for BatchOptions {
+    fn default() -> Self {
+        Self {
+            jobs: default_jobs(),
+            output_dir: None,
+        }
+    }
+}
+
+/// A file to process in a batch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchInput {
+    pub path: PathBuf,
+    /// The path relative to the directory or glob it was found through, used to mirror the
+    /// input layout in an output directory.
+    pub relative: PathBuf,
+}
+
+impl BatchInput {
+    /// Where the result for this input goes: under `output_dir` if given, otherwise over the
+    /// input itself.
+    pub fn output_path(&self, output_dir: Option<&Path>) -> PathBuf {
+        match output_dir {
+            Some(dir) => dir.join(&self.relative),
+            None => self.path.clone(),
+        }
+    }
+}
+
+/// The outcome of a batch.
+#[derive(Debug, Default)]
+pub struct BatchSummary {
+    pub succeeded: usize,
+    /// The inputs that failed, with their errors.
+    pub failed: Vec<(PathBuf, String)>,
+}
+
+impl Display for BatchSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Processed {} files: {} succeeded, {} failed",
+            self.succeeded + self.failed.len(),
+            self.succeeded,
+            self.failed.len()
+        )
+    }
+}
+
+fn is_glob(input: &str) -> bool {
+    input.contains(GLOB_CHARS)
+}
+
+fn is_png(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("png"))
+}
+
+/// Returns true if the inputs need batch processing: more than one input, a directory or a
+/// glob.
+pub fn is_batch(inputs: &[String]) -> bool {
+    match inputs {
+        [input] => is_glob(input) || Path::new(input).is_dir(),
+        _ => true,
+    }
+}
+
+/// Collects the PNG files under a directory, recursively and in a stable order.
+fn walk_dir(root: &Path, dir: &Path, files: &mut Vec<BatchInput>) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    entries.sort();
+
+    for path in entries {
+        if path.is_dir() {
+            walk_dir(root, &path, files)?;
+        } else if is_png(&path) {
+            let relative = path.strip_prefix(root).unwrap_or(&path).to_path_buf();
+            files.push(BatchInput { path, relative });
+        }
+    }
+    Ok(())
+}
+
+/// The leading directories of a glob pattern that contain no glob characters.
+fn glob_root(pattern: &str) -> PathBuf {
+    Path::new(pattern)
+        .components()
+        .take_while(|c| !c.as_os_str().to_string_lossy().contains(GLOB_CHARS))
+        .collect()
+}
+
+/// Expands files, directories and glob patterns into the PNG files to process. Directories
+/// are searched recursively for `.png` files; files given by name or matched by a glob are
+/// taken as they are. A file found through several inputs is only processed once, as found
+/// first, so that no two workers write it at the same time.
+pub fn expand_inputs(inputs: &[String]) -> Result<Vec<BatchInput>, BatchError> {
+    let mut files = Vec::new();
+    for input in inputs {
+        let found = files.len();
+        let path = Path::new(input);
+
+        if is_glob(input) {
+            let root = glob_root(input);
+            for path in glob::glob(input)? {
+                let path = path.map_err(io::Error::from)?;
+                if path.is_dir() {
+                    walk_dir(&root, &path, &mut files)?;
+                } else {
+                    let relative = path.strip_prefix(&root).unwrap_or(&path).to_path_buf();
+                    files.push(BatchInput { path, relative });
+                }
+            }
+        } else if path.is_dir() {
+            walk_dir(path, path, &mut files)?;
+        } else {
+            let relative = PathBuf::from(path.file_name().unwrap_or(path.as_os_str()));
+            files.push(BatchInput {
+                path: path.to_path_buf(),
+                relative,
+            });
+        }
+
+        if files.len() == found {
+            return Err(BatchError::NoMatches(input.clone()));
+        }
+    }
+
+    let mut seen = HashSet::new();
+    files.retain(|file| seen.insert(fs::canonicalize(&file.path).unwrap_or(file.path.clone())));
+    Ok(files)
+}
+
+/// Checks that no two inputs would be saved to the same output path, which happens when
+/// files with the same name are given from different directories. Run before starting a
+/// batch, so that no result is silently overwritten by another.
+pub fn check_outputs(inputs: &[BatchInput], output_dir: Option<&Path>) -> Result<(), BatchError> {
+    let mut outputs = HashMap::new();
+    for input in inputs {
+        let output = input.output_path(output_dir);
+        if let Some(first) = outputs.insert(output.clone(), &input.path) {
+            return Err(BatchError::DuplicateOutput {
+                output,
+                first: first.clone(),
+                second: input.path.clone(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// The default number of parallel workers.
+pub fn default_jobs() -> usize {
+    thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+/// Runs `task` for every input on up to `jobs` worker threads. Each task writes its output
+/// to its own buffer; the buffers are written to `out` in input order as tasks finish, and
+/// errors are reported on `err` without stopping the batch.
+pub fn run<O, W, E, F>(
+    inputs: &[BatchInput],
+    jobs: usize,
+    out: &mut O,
+    err: &mut W,
+    task: F,
+) -> io::Result<BatchSummary>
+where
+    O: Write,
+    W: Write,
+    E: Display,
+    F: Fn(&BatchInput, &mut Vec<u8>) -> Result<(), E> + Sync,
+{
+    let next = AtomicUsize::new(0);
+    let (sender, receiver) = mpsc::channel();
+
+    thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, inputs.len().max(1)) {
+            let sender = sender.clone();
+            let (next, task) = (&next, &task);
+            scope.spawn(move || loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                let Some(input) = inputs.get(index) else {
+                    break;
+                };
+                let mut output = Vec::new();
+                let result = task(input, &mut output).map_err(|e| e.to_string());
+                if sender.send((index, output, result)).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(sender);
+
+        let mut summary = BatchSummary::default();
+        let mut pending = BTreeMap::new();
+        let mut next_to_write = 0;
+        for (index, output, result) in receiver {
+            pending.insert(index, (output, result));
+            while let Some((output, result)) = pending.remove(&next_to_write) {
+                out.write_all(&output)?;
+                match result {
+                    Ok(()) => summary.succeeded += 1,
+                    Err(e) => {
+                        let path = inputs[next_to_write].path.clone();
+                        write!(err, "Error processing {}: {e}", path.display())?;
+                        summary.failed.push((path, e));
+                    }
+                }
+                next_to_write += 1;
+            }
+        }
+        out.flush()?;
+        Ok(summary)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn touch(path: &Path) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, b"").unwrap();
+    }
+
+    #[test]
+    fn test_expand_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        touch(&dir.path().join("b.png"));
+        touch(&dir.path().join("a.PNG"));
+        touch(&dir.path().join("notes.txt"));
+        touch(&dir.path().join("sub/c.png"));
+
+        let inputs = expand_inputs(&[dir.path().to_str().unwrap().to_string()]).unwrap();
+        let relative: Vec<_> = inputs.iter().map(|i| i.relative.clone()).collect();
+        assert_eq!(
+            relative,
+            [
+                PathBuf::from("a.PNG"),
+                PathBuf::from("b.png"),
+                PathBuf::from("sub/c.png")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_expand_glob() {
+        let dir = tempfile::tempdir().unwrap();
+        touch(&dir.path().join("one/a.png"));
+        touch(&dir.path().join("two/b.png"));
+        touch(&dir.path().join("two/c.jpg"));
+
+        let pattern = format!("{}/*/*.png", dir.path().display());
+        let inputs = expand_inputs(&[pattern]).unwrap();
+        let relative: Vec<_> = inputs.iter().map(|i| i.relative.clone()).collect();
+        assert_eq!(
+            relative,
+            [PathBuf::from("one/a.png"), PathBuf::from("two/b.png")]
+        );
+        assert_eq!(
+            inputs[0].output_path(Some(Path::new("out"))),
+            PathBuf::from("out/one/a.png")
+        );
+        assert_eq!(inputs[0].output_path(None), inputs[0].path);
+
+        let pattern = format!("{}/*.gif", dir.path().display());
+        assert!(matches!(
+            expand_inputs(&[pattern]),
+            Err(BatchError::NoMatches(_))
+        ));
+    }
+
+    #[test]
+    fn test_expand_overlapping() {
+        let dir = tempfile::tempdir().unwrap();
+        touch(&dir.path().join("a.png"));
+        touch(&dir.path().join("b.png"));
+
+        let dir_input = dir.path().to_str().unwrap().to_string();
+        let file_input = format!("{}/./a.png", dir.path().display());
+        let pattern = format!("{}/*.png", dir.path().display());
+        let inputs = expand_inputs(&[dir_input, file_input, pattern]).unwrap();
+        let relative: Vec<_> = inputs.iter().map(|i| i.relative.clone()).collect();
+        assert_eq!(relative, [PathBuf::from("a.png"), PathBuf::from("b.png")]);
+    }
+
+    #[test]
+    fn test_check_outputs() {
+        let dir = tempfile::tempdir().unwrap();
+        touch(&dir.path().join("x/a.png"));
+        touch(&dir.path().join("y/a.png"));
+
+        let inputs = expand_inputs(&[
+            format!("{}/x/a.png", dir.path().display()),
+            format!("{}/y/a.png", dir.path().display()),
+        ])
+        .unwrap();
+        assert!(check_outputs(&inputs, None).is_ok());
+        match check_outputs(&inputs, Some(Path::new("out"))) {
+            Err(BatchError::DuplicateOutput {
+                output,
+                first,
+                second,
+            }) => {
+                assert_eq!(output, PathBuf::from("out/a.png"));
+                assert_eq!(first, inputs[0].path);
+                assert_eq!(second, inputs[1].path);
+            }
+            result => panic!("expected a duplicate output, got {result:?}"),
+        }
+
+        let inputs = expand_inputs(&[dir.path().to_str().unwrap().to_string()]).unwrap();
+        assert!(check_outputs(&inputs, Some(Path::new("out"))).is_ok());
+    }
+
+    #[test]
+    fn test_is_batch() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("a.png");
+        touch(&file);
+        let file = file.to_str().unwrap().to_string();
+
+        assert!(!is_batch(std::slice::from_ref(&file)));
+        assert!(is_batch(&[file.clone(), file]));
+        assert!(is_batch(&[dir.path().to_str().unwrap().to_string()]));
+        assert!(is_batch(&[String::from("*.png")]));
+    }
+
+    #[test]
+    fn test_run_in_order_with_errors() {
+        let inputs: Vec<_> = (0..20)
+            .map(|i| BatchInput {
+                path: PathBuf::from(format!("{i}.png")),
+                relative: PathBuf::from(format!("{i}.png")),
+            })
+            .collect();
+
+        let mut out = Vec::new();
+        let mut err = Vec::new();
+        let summary = run(&inputs, 4, &mut out, &mut err, |input, output| {
+            let name = input.path.display().to_string();
+            writeln!(output, "{name}").unwrap();
+            match name.starts_with('1') {
+                true => Err(format!("failed {name}\n")),
+                false => Ok(()),
+            }
+        })
+        .unwrap();
+
+        let expected: String = (0..20).map(|i| format!("{i}.png\n")).collect();
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+        assert_eq!(summary.succeeded, 9);
+        assert_eq!(summary.failed.len(), 11);
+        assert_eq!(summary.failed[0].0, PathBuf::from("1.png"));
+        assert!(String::from_utf8(err)
+            .unwrap()
+            .starts_with("Error processing 1.png: failed 1.png\n"));
+    }
+}
@@ -0,0 +1,169 @@
+use crate::{chunk, chunk_type::CHUNK_TYPE_SIZE};
+
+/// The reversed CRC-32 polynomial used by PNG.
+const POLY: u32 = 0xedb8_8320;
+
+/// How hard `correct_bits` searches for flipped bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BitFixOptions {
+    /// The longest run of bits an error may span. 1 only corrects single flipped bits.
+    pub max_burst: u32,
+    /// The maximum number of error patterns to try before giving up.
+    pub max_checks: u64,
+}
+
+impl Default for BitFixOptions {
+    fn default() -> Self {
+        Self {
+            max_burst: 1,
+            max_checks: 1 << 26,
+        }
+    }
+}
+
+/// A bit flipped back by `correct_bits`, with bits counted from the least significant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlippedBit {
+    pub byte: usize,
+    pub bit: u8,
+}
+
+/// Advances the CRC register by one zero bit.
+fn shift(crc: u32) -> u32 {
+    match crc & 1 {
+        1 => (crc >> 1) ^ POLY,
+        _ => crc >> 1,
+    }
+}
+
+/// Finds and flips back the bits of a damaged chunk type and data that make them match
+/// `crc`, the CRC stored with them. Errors spanning up to `max_burst` consecutive bits are
+/// tried, closest to the end first, and the search gives up after `max_checks` patterns.
+/// Returns the flipped bits, with byte 0 being the first byte of the chunk type, or `None`
+/// if no error pattern was found. A `message` that already matches needs no flips.
+///
+/// CRCs are linear, so flipping a bit changes the CRC by a value that only depends on how
+/// many bits follow it. That value is computed for each bit in turn from the end, without
+/// recomputing the CRC of the whole message.
+pub fn correct_bits(
+    message: &mut [u8],
+    crc: u32,
+    options: &BitFixOptions,
+) -> Option<Vec<FlippedBit>> {
+    let syndrome = checksum(message) ^ crc;
+    if syndrome == 0 {
+        return Some(Vec::new());
+    }
+
+    let max_burst = options.max_burst.clamp(1, 32) as usize;
+    let bits = message.len() * 8;
+    // The CRC changes of the bits from the current one on, nearest first
+    let mut effects = Vec::with_capacity(max_burst);
+    let mut effect = POLY;
+    let mut checks = 0;
+
+    for first in (0..bits).rev() {
+        effects.insert(0, effect);
+        effects.truncate(max_burst);
+        effect = shift(effect);
+
+        // Patterns start with the current bit and end within the burst length
+        for pattern in (1..1u64 << effects.len()).step_by(2) {
+            checks += 1;
+            if checks > options.max_checks {
+                return None;
+            }
+            let change = (0..effects.len())
+                .filter(|&i| pattern & (1 << i) != 0)
+                .fold(0, |change, i| change ^ effects[i]);
+            if change == syndrome {
+                let flipped: Vec<_> = (0..effects.len())
+                    .filter(|&i| pattern & (1 << i) != 0)
+                    .map(|i| FlippedBit {
+                        byte: (first + i) / 8,
+                        bit: ((first + i) % 8) as u8,
+                    })
+                    .collect();
+                for flip in &flipped {
+                    message[flip.byte] ^= 1 << flip.bit;
+                }
+                return Some(flipped);
+            }
+        }
+    }
+    None
+}
+
+/// Returns true if the stored CRC differs from the calculated one in a single bit, which
+/// means the CRC itself is more likely damaged than the chunk.
+pub fn is_crc_bit_flip(message: &[u8], crc: u32) -> bool {
+    (checksum(message) ^ crc).count_ones() == 1
+}
+
+fn checksum(message: &[u8]) -> u32 {
+    let (chunk_type, data) = message.split_at(CHUNK_TYPE_SIZE);
+    chunk::checksum(chunk_type.try_into().unwrap(), data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message() -> Vec<u8> {
+        b"tEXtComment\0a chunk with a few bits flipped".to_vec()
+    }
+
+    #[test]
+    fn test_single_bit() {
+        let original = message();
+        let crc = checksum(&original);
+
+        for (byte, bit) in [(0, 0), (2, 5), (20, 7), (original.len() - 1, 3)] {
+            let mut damaged = original.clone();
+            damaged[byte] ^= 1 << bit;
+            let flipped = correct_bits(&mut damaged, crc, &BitFixOptions::default()).unwrap();
+            assert_eq!(flipped, [FlippedBit { byte, bit }]);
+            assert_eq!(damaged, original);
+        }
+    }
+
+    #[test]
+    fn test_burst() {
+        let original = message();
+        let crc = checksum(&original);
+        let mut damaged = original.clone();
+        damaged[10] ^= 0b1100_0000;
+        damaged[11] ^= 0b0000_0101;
+
+        let single = BitFixOptions::default();
+        assert!(correct_bits(&mut damaged.clone(), crc, &single).is_none());
+
+        let burst = BitFixOptions {
+            max_burst: 5,
+            ..Default::default()
+        };
+        let flipped = correct_bits(&mut damaged, crc, &burst).unwrap();
+        assert_eq!(flipped.len(), 4);
+        assert_eq!(damaged, original);
+    }
+
+    #[test]
+    fn test_limits() {
+        let original = message();
+        let crc = checksum(&original);
+        let mut damaged = original.clone();
+        damaged[4] ^= 1;
+
+        let limited = BitFixOptions {
+            max_checks: 10,
+            ..Default::default()
+        };
+        assert!(correct_bits(&mut damaged, crc, &limited).is_none());
+        assert_eq!(
+            correct_bits(&mut original.clone(), crc, &limited),
+            Some(vec![])
+        );
+        assert!(is_crc_bit_flip(&original, crc ^ 0x100));
+        assert!(!is_crc_bit_flip(&damaged, crc));
+    }
+}
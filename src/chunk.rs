@@ -1,15 +1,49 @@
-use std::{
+use alloc::{
+    string::{FromUtf8Error, String},
+    vec::Vec,
+};
+use core::{
     fmt::{self, Display},
-    string::FromUtf8Error,
+    ops::Range,
 };
 
-use super::chunk_type::{ChunkType, ChunkTypeDecodeError, CHUNK_TYPE_SIZE};
+use super::{
+    chunk_type::{ChunkType, ChunkTypeDecodeError, CHUNK_TYPE_SIZE},
+    decoder::{ChunkDecoder, DecodeEvent},
+    limits::{DecodeLimits, LimitError},
+    png::PngDecodeError,
+};
 
 pub const LEN_SIZE: usize = 4;
 pub const CRC_SIZE: usize = 4;
 pub const MIN_CHUNK_SIZE: usize = LEN_SIZE + CHUNK_TYPE_SIZE + CRC_SIZE;
 
-const MAX_LEN: usize = std::i32::MAX as usize;
+/// The maximum length of chunk data allowed by the PNG spec.
+pub const MAX_LEN: usize = i32::MAX as usize;
+
+/// The CRC-32 algorithm used by PNG.
+pub(crate) static HDLC: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+
+/// Calculates the CRC of the bytes of a chunk type followed by its data, as described by the
+/// PNG spec. The type bytes are taken as they are, so damaged chunks can be checked too.
+pub(crate) fn checksum(chunk_type: &[u8; CHUNK_TYPE_SIZE], data: &[u8]) -> u32 {
+    let mut digest = HDLC.digest();
+    digest.update(chunk_type);
+    digest.update(data);
+    digest.finalize()
+}
+
+/// Serializes a chunk into the byte sequence described by the PNG spec.
+fn serialize(chunk_type: &ChunkType, data: &[u8], crc: u32) -> Vec<u8> {
+    (data.len() as u32)
+        .to_be_bytes()
+        .iter()
+        .chain(chunk_type.bytes().iter())
+        .chain(data.iter())
+        .chain(crc.to_be_bytes().iter())
+        .copied()
+        .collect()
+}
 
 /// A validated PNG chunk. See the PNG Spec for more details
 /// http://www.libpng.org/pub/png/spec/1.2/PNG-Structure.html
@@ -45,15 +79,7 @@ impl Chunk {
 
     /// The CRC of this chunk
     pub fn crc(&self) -> u32 {
-        const HDLC: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
-        let v: Vec<_> = self
-            .chunk_type()
-            .bytes()
-            .iter()
-            .cloned()
-            .chain(self.data().iter().cloned())
-            .collect();
-        HDLC.checksum(&v)
+        checksum(&self.chunk_type.bytes(), &self.data)
     }
 
     // Returns the data stored in this chunk as a `String`. This function will return an error
@@ -69,14 +95,102 @@ impl Chunk {
     /// 3. The data itself *(`length` bytes)*
     /// 4. The CRC of the chunk type and data *(4 bytes)*
     pub fn as_bytes(&self) -> Vec<u8> {
-        self.length()
-            .to_be_bytes()
-            .iter()
-            .cloned()
-            .chain(self.chunk_type().bytes().iter().cloned())
-            .chain(self.data().iter().cloned())
-            .chain(self.crc().to_be_bytes().iter().cloned())
-            .collect()
+        serialize(&self.chunk_type, &self.data, self.crc())
+    }
+
+    /// Returns a borrowed view of this chunk.
+    pub fn as_chunk_ref(&self) -> ChunkRef<'_> {
+        ChunkRef {
+            chunk_type: self.chunk_type,
+            data: &self.data,
+            crc: self.crc(),
+            offset: None,
+        }
+    }
+}
+
+/// A PNG chunk that borrows its data from the buffer it was parsed from instead of copying it.
+/// The CRC read from the buffer is only checked against the chunk contents when
+/// `verify_crc` is called.
+#[derive(Debug, Clone, Copy)]
+pub struct ChunkRef<'a> {
+    chunk_type: ChunkType,
+    data: &'a [u8],
+    crc: u32,
+    offset: Option<u64>,
+}
+
+impl<'a> ChunkRef<'a> {
+    /// The length of the data portion of this chunk.
+    pub fn length(&self) -> u32 {
+        self.data.len() as u32
+    }
+
+    /// The `ChunkType` of this chunk
+    pub fn chunk_type(&self) -> &ChunkType {
+        &self.chunk_type
+    }
+
+    /// The raw data contained in this chunk in bytes
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    /// The CRC stored with this chunk. This is not guaranteed to match the chunk contents
+    /// unless `verify_crc` has succeeded.
+    pub fn crc(&self) -> u32 {
+        self.crc
+    }
+
+    /// The byte offset of the start of this chunk (its length field) within the source it was
+    /// parsed from, or `None` if the chunk was not parsed.
+    pub fn offset(&self) -> Option<u64> {
+        self.offset
+    }
+
+    /// The range of bytes this chunk occupies in its source, from its length field to the
+    /// end of its CRC.
+    pub fn span(&self) -> Option<Range<u64>> {
+        self.offset
+            .map(|offset| offset..offset + (MIN_CHUNK_SIZE + self.data.len()) as u64)
+    }
+
+    /// Checks the stored CRC against the one calculated from the chunk type and data.
+    pub fn verify_crc(&self) -> Result<(), ChunkDecodeError> {
+        let calculated_crc = checksum(&self.chunk_type.bytes(), self.data);
+        if calculated_crc != self.crc {
+            return Err(ChunkDecodeError::CrcMismatch {
+                offset: self.offset.unwrap_or(0),
+                expected_crc: calculated_crc,
+                given_crc: self.crc,
+            });
+        }
+        Ok(())
+    }
+
+    /// Returns the data stored in this chunk as a `String`. This function will return an error
+    /// if the stored data is not valid UTF-8.
+    pub fn data_as_string(&self) -> Result<String, FromUtf8Error> {
+        String::from_utf8(self.data.to_vec())
+    }
+
+    /// Returns this chunk as a byte sequence described by the PNG spec, using the stored CRC.
+    pub fn as_bytes(&self) -> Vec<u8> {
+        serialize(&self.chunk_type, self.data, self.crc)
+    }
+
+    /// Copies the borrowed data into an owned `Chunk`.
+    pub fn to_chunk(&self) -> Chunk {
+        Chunk {
+            chunk_type: self.chunk_type,
+            data: self.data.to_vec(),
+        }
+    }
+}
+
+impl From<ChunkRef<'_>> for Chunk {
+    fn from(chunk: ChunkRef<'_>) -> Self {
+        chunk.to_chunk()
     }
 }
 
@@ -85,60 +199,95 @@ fn segment4(bytes: &[u8]) -> [u8; 4] {
     bytes.try_into().unwrap()
 }
 
+/// Errors from decoding a chunk. Each one carries the byte offset of the start of the chunk
+/// it was found in, relative to the source being parsed.
 #[derive(Debug)]
 pub enum ChunkDecodeError {
-    InvalidChunkSize(usize),
-    DataExceedMaximumLength(usize),
+    InvalidChunkSize {
+        offset: u64,
+        size: usize,
+    },
+    DataExceedMaximumLength {
+        offset: u64,
+        length: usize,
+    },
     LengthMismatch {
+        offset: u64,
         data_length: usize,
         given_length: usize,
     },
     CrcMismatch {
+        offset: u64,
         expected_crc: u32,
         given_crc: u32,
     },
-    ChunkTypeDecode(ChunkTypeDecodeError),
+    ChunkTypeDecode {
+        offset: u64,
+        err: ChunkTypeDecodeError,
+    },
+    LimitExceeded {
+        offset: u64,
+        err: LimitError,
+    },
+}
+
+impl ChunkDecodeError {
+    /// The byte offset of the start of the chunk this error was found in.
+    pub fn offset(&self) -> u64 {
+        match self {
+            Self::InvalidChunkSize { offset, .. }
+            | Self::DataExceedMaximumLength { offset, .. }
+            | Self::LengthMismatch { offset, .. }
+            | Self::CrcMismatch { offset, .. }
+            | Self::ChunkTypeDecode { offset, .. }
+            | Self::LimitExceeded { offset, .. } => *offset,
+        }
+    }
 }
 
 impl Display for ChunkDecodeError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let offset = self.offset();
         match self {
-            Self::InvalidChunkSize(size) => writeln!(f, "Invalid chunk size: {size}"),
-            Self::DataExceedMaximumLength(length) => {
-                writeln!(f, "Chunk data exceed maximum length: {length}")
+            Self::InvalidChunkSize { size, .. } => {
+                writeln!(f, "Invalid chunk size at offset {offset:#x}: {size}")
             }
+            Self::DataExceedMaximumLength { length, .. } => writeln!(
+                f,
+                "Chunk data exceed maximum length at offset {offset:#x}: {length}"
+            ),
             Self::LengthMismatch {
                 data_length,
                 given_length,
+                ..
             } => writeln!(
                 f,
-                "Data length mismatch: {data_length} (actual) vs {given_length} (given)"
+                "Data length mismatch at offset {offset:#x}: {data_length} (actual) vs {given_length} (given)"
             ),
             Self::CrcMismatch {
                 expected_crc,
                 given_crc,
+                ..
             } => writeln!(
                 f,
-                "CRC mismatch: {expected_crc} (expected) vs {given_crc} (given)"
+                "CRC mismatch at offset {offset:#x}: {expected_crc} (expected) vs {given_crc} (given)"
             ),
-            Self::ChunkTypeDecode(err) => writeln!(f, "Chunk type error: {err}"),
+            Self::ChunkTypeDecode { err, .. } => {
+                writeln!(f, "Chunk type error at offset {offset:#x}: {err}")
+            }
+            Self::LimitExceeded { err, .. } => {
+                write!(f, "Limit exceeded at offset {offset:#x}: {err}")
+            }
         }
     }
 }
 
-impl From<ChunkTypeDecodeError> for ChunkDecodeError {
-    fn from(err: ChunkTypeDecodeError) -> Self {
-        Self::ChunkTypeDecode(err)
-    }
-}
-
-impl TryFrom<&[u8]> for Chunk {
-    type Error = ChunkDecodeError;
-
-    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+impl<'a> ChunkRef<'a> {
+    /// Parses a chunk that starts at `offset` in its source, without checking its CRC.
+    pub fn parse_at(bytes: &'a [u8], offset: u64) -> Result<Self, ChunkDecodeError> {
         let size = bytes.len();
         if size < MIN_CHUNK_SIZE {
-            return Err(ChunkDecodeError::InvalidChunkSize(size));
+            return Err(ChunkDecodeError::InvalidChunkSize { offset, size });
         }
 
         let data_length = u32::from_be_bytes(segment4(&bytes[0..4])) as usize;
@@ -147,36 +296,61 @@ impl TryFrom<&[u8]> for Chunk {
         let crc = u32::from_be_bytes(segment4(&bytes[size - 4..size]));
 
         if data.len() > MAX_LEN {
-            return Err(ChunkDecodeError::DataExceedMaximumLength(data.len()));
+            return Err(ChunkDecodeError::DataExceedMaximumLength {
+                offset,
+                length: data.len(),
+            });
         }
 
-        if data.len() != data_length as usize {
+        if data.len() != data_length {
             return Err(ChunkDecodeError::LengthMismatch {
+                offset,
                 data_length: data.len(),
                 given_length: data_length,
             });
         }
 
-        let s = Self {
-            chunk_type: ChunkType::try_from(chunk_type)?,
-            data: data.to_vec(),
-        };
+        Ok(Self {
+            chunk_type: ChunkType::try_from(chunk_type)
+                .map_err(|err| ChunkDecodeError::ChunkTypeDecode { offset, err })?,
+            data,
+            crc,
+            offset: Some(offset),
+        })
+    }
+}
 
-        let calculated_crc = s.crc();
-        if calculated_crc != crc {
-            return Err(ChunkDecodeError::CrcMismatch {
-                expected_crc: calculated_crc,
-                given_crc: crc,
-            });
-        }
+impl<'a> TryFrom<&'a [u8]> for ChunkRef<'a> {
+    type Error = ChunkDecodeError;
 
-        Ok(s)
+    /// Parses the length, chunk type and data of a chunk without checking its CRC.
+    fn try_from(bytes: &'a [u8]) -> Result<Self, Self::Error> {
+        Self::parse_at(bytes, 0)
+    }
+}
+
+impl TryFrom<&[u8]> for Chunk {
+    type Error = ChunkDecodeError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let chunk = ChunkRef::try_from(bytes)?;
+        chunk.verify_crc()?;
+        Ok(chunk.to_chunk())
     }
 }
 
 impl Display for Chunk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.as_chunk_ref().fmt(f)
+    }
+}
+
+impl Display for ChunkRef<'_> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         writeln!(f, "Chunk {{",)?;
+        if let Some(offset) = self.offset() {
+            writeln!(f, "  Offset: {offset} ({offset:#x})")?;
+        }
         writeln!(f, "  Length: {}", self.length())?;
         writeln!(f, "  Type: {}", self.chunk_type())?;
         writeln!(f, "  Data: {} bytes", self.data().len())?;
@@ -186,52 +360,120 @@ impl Display for Chunk {
     }
 }
 
+/// Iterates over the chunks in a buffer without copying their data. CRCs are checked as
+/// chunks are yielded unless the iterator is created with `unverified`, and chunks are
+/// checked against the limits set with `with_limits`. Parsing is done by a `ChunkDecoder`
+/// fed the whole buffer.
 #[derive(Debug)]
 pub struct ChunkIter<'a> {
+    /// The bytes from the start of the next chunk on.
     cur: &'a [u8],
-    corrupted: bool,
+    decoder: ChunkDecoder,
 }
 
 impl<'a> ChunkIter<'a> {
     pub fn new(cur: &'a [u8]) -> Self {
         Self {
             cur,
-            corrupted: false,
+            decoder: ChunkDecoder::chunks_only(),
         }
     }
+
+    /// Sets the offset of the start of `cur` within its source, so that chunk offsets and
+    /// errors are reported relative to the source rather than to `cur`.
+    pub fn starting_at(self, offset: u64) -> Self {
+        Self {
+            decoder: self.decoder.starting_at(offset),
+            ..self
+        }
+    }
+
+    /// Creates an iterator that leaves CRC verification to the caller.
+    pub fn unverified(cur: &'a [u8]) -> Self {
+        Self {
+            cur,
+            decoder: ChunkDecoder::chunks_only().unverified(),
+        }
+    }
+
+    /// Leaves the CRCs of IDAT chunks unchecked, so that iterating over a memory-mapped
+    /// file does not read its image data.
+    pub fn lazy_image_data(self) -> Self {
+        Self {
+            decoder: self.decoder.lazy_image_data(),
+            ..self
+        }
+    }
+
+    /// Sets the limits chunks are checked against. Only the chunk length, count and total
+    /// size limits apply to single chunks.
+    pub fn with_limits(self, limits: DecodeLimits) -> Self {
+        Self {
+            decoder: self.decoder.with_limits(limits),
+            ..self
+        }
+    }
+
+    /// Returns the bytes that have not been read as chunks yet.
+    pub fn remaining(&self) -> &'a [u8] {
+        self.cur
+    }
 }
 
 impl<'a> Iterator for ChunkIter<'a> {
-    type Item = Result<Chunk, ChunkDecodeError>;
+    type Item = Result<ChunkRef<'a>, ChunkDecodeError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.corrupted || self.cur.len() == 0 {
+        if self.decoder.is_done() || self.cur.is_empty() {
             return None;
         }
 
-        let data_length = u32::from_be_bytes(segment4(&self.cur[..LEN_SIZE]));
-        let end = MIN_CHUNK_SIZE + data_length as usize;
-        if self.cur.len() < end {
-            self.corrupted = true;
-            return Some(Err(ChunkDecodeError::InvalidChunkSize(self.cur.len())));
+        let mut used = 0;
+        let mut header = None;
+        loop {
+            let event = match self.decoder.decode(&self.cur[used..]) {
+                Ok((n, event)) => {
+                    used += n;
+                    event
+                }
+                Err(err) => return Some(Err(chunk_error(err))),
+            };
+            match event {
+                Some(DecodeEvent::ChunkHeader {
+                    offset, chunk_type, ..
+                }) => header = Some((offset, chunk_type)),
+                Some(DecodeEvent::ChunkEnd { crc }) => {
+                    let (offset, chunk_type) = header.unwrap();
+                    let data = &self.cur[LEN_SIZE + CHUNK_TYPE_SIZE..used - CRC_SIZE];
+                    self.cur = &self.cur[used..];
+                    return Some(Ok(ChunkRef {
+                        chunk_type,
+                        data,
+                        crc,
+                        offset: Some(offset),
+                    }));
+                }
+                Some(_) => {}
+                // The buffer ends within this chunk
+                None => return self.decoder.finish().err().map(|err| Err(chunk_error(err))),
+            }
         }
+    }
+}
 
-        let bytes = &self.cur[0..end];
-        self.cur = &self.cur[end..];
-
-        Chunk::try_from(bytes).map_or_else(
-            |err| {
-                self.corrupted = true;
-                Some(Err(err))
-            },
-            |chunk| Some(Ok(chunk)),
-        )
+/// Unwraps an error from a decoder made with `ChunkDecoder::chunks_only`, which only fails
+/// on chunks.
+fn chunk_error(err: PngDecodeError) -> ChunkDecodeError {
+    match err {
+        PngDecodeError::ChunkDecode(err) => err,
+        _ => unreachable!("chunks_only decoders do not read a signature"),
     }
 }
 
 #[cfg(test)]
 mod iter_tests {
     use super::*;
+    use alloc::{string::ToString, vec};
 
     fn valid_chunk() -> Vec<u8> {
         let data_length: u32 = 11;
@@ -306,8 +548,8 @@ mod iter_tests {
         let big_chunk: Vec<u8> = 12345678u32
             .to_be_bytes()
             .into_iter()
-            .chain((&bytes[4..]).iter().copied())
-            .chain(valid_chunk().into_iter())
+            .chain(bytes[4..].iter().copied())
+            .chain(valid_chunk())
             .collect();
 
         let mut iter = ChunkIter::new(big_chunk.as_slice());
@@ -320,12 +562,122 @@ mod iter_tests {
     fn test_empty_buffer() {
         assert!(ChunkIter::new(&[]).next().is_none());
     }
+
+    #[test]
+    fn test_chunk_offsets() {
+        let bytes: Vec<u8> = vec![valid_chunk(), valid_chunk()]
+            .into_iter()
+            .flatten()
+            .collect();
+
+        let offsets: Vec<_> = ChunkIter::new(bytes.as_slice())
+            .starting_at(8)
+            .map(|chunk| chunk.unwrap().span().unwrap())
+            .collect();
+        assert_eq!(offsets, vec![8..31, 31..54]);
+    }
+
+    #[test]
+    fn test_error_offsets() {
+        let bytes: Vec<u8> = vec![valid_chunk(), invalid_chunk()]
+            .into_iter()
+            .flatten()
+            .collect();
+
+        let err = ChunkIter::new(bytes.as_slice())
+            .nth(1)
+            .unwrap()
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            ChunkDecodeError::CrcMismatch { offset: 23, .. }
+        ));
+        assert!(err.to_string().contains("0x17"));
+
+        let err = ChunkIter::new(&bytes[..30]).nth(1).unwrap().unwrap_err();
+        assert!(matches!(
+            err,
+            ChunkDecodeError::InvalidChunkSize {
+                offset: 23,
+                size: 7
+            }
+        ));
+    }
+
+    #[test]
+    fn test_unverified_chunks() {
+        let bytes: Vec<u8> = vec![valid_chunk(), invalid_chunk(), valid_chunk()]
+            .into_iter()
+            .flatten()
+            .collect();
+
+        let chunks: Vec<_> = ChunkIter::unverified(bytes.as_slice())
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        assert_eq!(chunks.len(), 3);
+        assert!(chunks[0].verify_crc().is_ok());
+        assert!(chunks[1].verify_crc().is_err());
+    }
+
+    #[test]
+    fn test_limits() {
+        let bytes: Vec<u8> = vec![valid_chunk(), valid_chunk(), valid_chunk()]
+            .into_iter()
+            .flatten()
+            .collect();
+
+        let limits = DecodeLimits {
+            max_chunk_count: 2,
+            ..DecodeLimits::default()
+        };
+        let mut iter = ChunkIter::new(bytes.as_slice()).with_limits(limits);
+        assert!(iter.next().unwrap().is_ok());
+        assert!(iter.next().unwrap().is_ok());
+        assert!(matches!(
+            iter.next().unwrap(),
+            Err(ChunkDecodeError::LimitExceeded {
+                offset: 46,
+                err: LimitError::ChunkCount { limit: 2 }
+            })
+        ));
+        assert!(iter.next().is_none());
+
+        // The length is rejected before the data is looked at, even if it isn't there
+        let limits = DecodeLimits {
+            max_chunk_length: 10,
+            ..DecodeLimits::default()
+        };
+        let err = ChunkIter::new(&bytes[..12])
+            .with_limits(limits)
+            .next()
+            .unwrap()
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            ChunkDecodeError::LimitExceeded {
+                offset: 0,
+                err: LimitError::ChunkLength {
+                    length: 11,
+                    limit: 10
+                }
+            }
+        ));
+    }
+
+    #[test]
+    fn test_chunks_borrow_input() {
+        let bytes = valid_chunk();
+        let chunk = ChunkIter::new(bytes.as_slice()).next().unwrap().unwrap();
+        assert!(core::ptr::eq(chunk.data().as_ptr(), bytes[8..].as_ptr()));
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::str::FromStr;
+    use alloc::{format, string::ToString};
+    use core::str::FromStr;
 
     fn testing_chunk() -> Chunk {
         let data_length: u32 = 42;
@@ -450,4 +802,29 @@ mod tests {
 
         let _chunk_string = format!("{}", chunk);
     }
+
+    #[test]
+    fn test_chunk_ref_from_bytes() {
+        let chunk = testing_chunk();
+        let bytes = chunk.as_bytes();
+        let chunk_ref = ChunkRef::try_from(bytes.as_ref()).unwrap();
+
+        assert_eq!(chunk_ref.length(), 42);
+        assert_eq!(chunk_ref.chunk_type().to_string(), String::from("RuSt"));
+        assert_eq!(chunk_ref.crc(), 2882656334);
+        assert!(chunk_ref.verify_crc().is_ok());
+        assert_eq!(chunk_ref.as_bytes(), bytes);
+        assert_eq!(chunk_ref.to_chunk().as_bytes(), bytes);
+    }
+
+    #[test]
+    fn test_chunk_ref_lazy_crc() {
+        let mut bytes = testing_chunk().as_bytes();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+
+        let chunk_ref = ChunkRef::try_from(bytes.as_ref()).unwrap();
+        assert!(chunk_ref.verify_crc().is_err());
+        assert!(Chunk::try_from(bytes.as_ref()).is_err());
+    }
 }
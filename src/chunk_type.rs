@@ -1,4 +1,4 @@
-use std::{
+use core::{
     fmt::{self, Display},
     str::FromStr,
 };
@@ -7,7 +7,7 @@ pub const CHUNK_TYPE_SIZE: usize = 4;
 
 /// A validated PNG chunk type. See the PNG spec for more details.
 /// http://www.libpng.org/pub/png/spec/1.2/PNG-Structure.html
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct ChunkType {
     bytes: [u8; CHUNK_TYPE_SIZE],
 }
@@ -102,7 +102,7 @@ impl Display for ChunkType {
         write!(
             f,
             "{}",
-            std::str::from_utf8(&self.bytes()).map_err(|_| fmt::Error)?
+            core::str::from_utf8(&self.bytes()).map_err(|_| fmt::Error)?
         )
     }
 }
@@ -110,8 +110,9 @@ impl Display for ChunkType {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::convert::TryFrom;
-    use std::str::FromStr;
+    use alloc::{format, string::ToString};
+    use core::convert::TryFrom;
+    use core::str::FromStr;
 
     #[test]
     pub fn test_chunk_type_from_bytes() {
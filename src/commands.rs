@@ -1,15 +1,29 @@
 use std::{
     fmt::{self, Display},
-    fs::File,
-    io::{self, Read, Write},
+    io::{self, Write},
     path::Path,
     str::FromStr,
 };
 
 use crate::{
+    batch::{self, BatchError, BatchOptions},
     chunk::{Chunk, ChunkDecodeError},
     chunk_type::{ChunkType, ChunkTypeDecodeError},
-    png::{ChunkNotFoundError, Png, PngDecodeError},
+    copy::{self, CopyError},
+    diff,
+    exif::ExifError,
+    in_place::{append_in_place, remove_in_place, InPlaceError, InPlaceOutcome},
+    input::{self, is_stdio, InputMode, PngSource},
+    limits::DecodeLimits,
+    manifest::{Manifest, ManifestError, ManifestFormat},
+    output::{self, SaveOptions},
+    png::{ChunkNotFoundError, ChunkPlacement, Png, PngDecodeError},
+    repair::{self, RepairError, RepairOptions},
+    report::{self, OutputFormat},
+    selector::{ChunkSelector, SelectorParseError},
+    stream::{self, StreamError},
+    strip::{self, StripPolicy, StripPreset},
+    verify,
 };
 
 #[derive(Debug)]
@@ -19,6 +33,17 @@ pub enum CommandError {
     ChunkDecode(ChunkDecodeError),
     PngDecode(PngDecodeError),
     ChunkNotFound(ChunkNotFoundError),
+    InPlace(InPlaceError),
+    Manifest(ManifestError),
+    Exif(ExifError),
+    Copy(CopyError),
+    Selector(SelectorParseError),
+    Repair(RepairError),
+    Batch(BatchError),
+    /// The number of files that failed in a batch.
+    BatchFailed(usize),
+    /// The number of problems found by `verify`.
+    VerifyFailed(usize),
 }
 
 impl Display for CommandError {
@@ -29,6 +54,15 @@ impl Display for CommandError {
             Self::ChunkDecode(err) => writeln!(f, "Chunk decode error: {err}"),
             Self::PngDecode(err) => writeln!(f, "PNG decode error: {err}"),
             Self::ChunkNotFound(err) => writeln!(f, "{err}"),
+            Self::InPlace(err) => writeln!(f, "In-place update error: {err}"),
+            Self::Manifest(err) => writeln!(f, "Manifest error: {err}"),
+            Self::Exif(err) => writeln!(f, "Exif error: {err}"),
+            Self::Copy(err) => writeln!(f, "Copy error: {err}"),
+            Self::Selector(err) => writeln!(f, "Selector error: {err}"),
+            Self::Repair(err) => writeln!(f, "Repair error: {err}"),
+            Self::Batch(err) => writeln!(f, "Batch error: {err}"),
+            Self::BatchFailed(count) => writeln!(f, "{count} files failed"),
+            Self::VerifyFailed(count) => writeln!(f, "{count} problems found"),
         }
     }
 }
@@ -63,67 +97,516 @@ impl From<ChunkNotFoundError> for CommandError {
     }
 }
 
-fn read_png<P: AsRef<Path>>(file_path: P) -> Result<Png, CommandError> {
-    let mut file = File::open(file_path)?;
+impl From<StreamError> for CommandError {
+    fn from(err: StreamError) -> Self {
+        match err {
+            StreamError::Io(err) => Self::File(err),
+            StreamError::Decode(err) => Self::PngDecode(err),
+        }
+    }
+}
+
+impl From<InPlaceError> for CommandError {
+    fn from(err: InPlaceError) -> Self {
+        Self::InPlace(err)
+    }
+}
+
+impl From<ManifestError> for CommandError {
+    fn from(err: ManifestError) -> Self {
+        Self::Manifest(err)
+    }
+}
+
+impl From<ExifError> for CommandError {
+    fn from(err: ExifError) -> Self {
+        Self::Exif(err)
+    }
+}
+
+impl From<CopyError> for CommandError {
+    fn from(err: CopyError) -> Self {
+        Self::Copy(err)
+    }
+}
+
+impl From<SelectorParseError> for CommandError {
+    fn from(err: SelectorParseError) -> Self {
+        Self::Selector(err)
+    }
+}
+
+impl From<RepairError> for CommandError {
+    fn from(err: RepairError) -> Self {
+        Self::Repair(err)
+    }
+}
+
+impl From<BatchError> for CommandError {
+    fn from(err: BatchError) -> Self {
+        Self::Batch(err)
+    }
+}
+
+/// Returns the directory containing `file_path`, or the current directory for bare file names.
+fn parent_dir(file_path: &str) -> &Path {
+    match Path::new(file_path).parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+/// Where to print status messages: `out`, or stderr if the PNG itself is saved to stdout.
+fn status_out<'a>(
+    out: &'a mut dyn Write,
+    output: &str,
+    stderr: &'a mut io::Stderr,
+) -> &'a mut dyn Write {
+    match is_stdio(output) {
+        true => stderr,
+        false => out,
+    }
+}
 
-    let mut buf: Vec<u8> = Vec::new();
-    file.read_to_end(&mut buf)?;
+/// Prints the chunks a PNG dropped because its image data was changed.
+fn report_dropped(out: &mut dyn Write, png: &Png) -> io::Result<()> {
+    for chunk in png.dropped_chunks() {
+        writeln!(
+            out,
+            "Dropped unsafe-to-copy chunk {} because the image data changed",
+            chunk.chunk_type()
+        )?;
+    }
+    Ok(())
+}
 
-    Ok(Png::try_from(buf.as_ref())?)
+fn path_str(path: &Path) -> Result<&str, CommandError> {
+    path.to_str().ok_or_else(|| {
+        let message = format!("{} is not valid UTF-8", path.display());
+        io::Error::new(io::ErrorKind::InvalidInput, message).into()
+    })
 }
 
-fn save_png<P: AsRef<Path>>(file_path: P, png: Png) -> Result<(), CommandError> {
-    let mut file = File::options().truncate(true).write(true).open(file_path)?;
+/// Runs a command for every input, passing it where to write its report, the input file and
+/// where to save its result. A single file runs once with stdout and no output path, leaving
+/// the command's own options to decide where to save. Several files, directories, globs or an
+/// output directory make a batch: each result goes under the output directory, mirroring the
+/// input layout, or over its input, and a summary is printed to stderr once every file is
+/// processed.
+pub fn for_each_input<F>(
+    inputs: &[String],
+    options: &BatchOptions,
+    command: F,
+) -> Result<(), CommandError>
+where
+    F: Fn(&mut dyn Write, &str, Option<&str>) -> Result<(), CommandError> + Sync,
+{
+    if !batch::is_batch(inputs) && options.output_dir.is_none() {
+        return command(&mut io::stdout().lock(), &inputs[0], None);
+    }
+
+    let inputs = batch::expand_inputs(inputs)?;
+    batch::check_outputs(&inputs, options.output_dir.as_deref())?;
+    let summary = batch::run(
+        &inputs,
+        options.jobs,
+        &mut io::stdout().lock(),
+        &mut io::stderr().lock(),
+        |input, out| {
+            let output = input.output_path(options.output_dir.as_deref());
+            if options.output_dir.is_some() {
+                std::fs::create_dir_all(parent_dir(path_str(&output)?))?;
+            }
+            command(out, path_str(&input.path)?, Some(path_str(&output)?))
+        },
+    )?;
 
-    file.write_all(&png.as_bytes())?;
+    eprint!("{summary}");
+    match summary.failed.len() {
+        0 => Ok(()),
+        failed => Err(CommandError::BatchFailed(failed)),
+    }
+}
+
+/// Reads the PNG file at `file_path` into `source` and parses it with `parse`. Stdin, pipes
+/// and other special files are instead read chunk by chunk with `stream::ChunkReader`, under
+/// the default `DecodeLimits`, so a bad or oversized stream is rejected as soon as it is seen.
+fn open_png<'a>(
+    source: &'a mut Option<PngSource>,
+    file_path: &str,
+    mode: InputMode,
+    parse: fn(&'a [u8]) -> Result<Png<'a>, PngDecodeError>,
+) -> Result<Png<'a>, CommandError> {
+    if let Some(stream) = input::open_stream(file_path)? {
+        return Ok(stream::read_png_with_limits(
+            stream,
+            &DecodeLimits::default(),
+        )?);
+    }
+    let source = source.insert(PngSource::open_with(file_path, mode)?);
+    Ok(parse(source.bytes())?)
+}
+
+/// Saves a PNG to `file_path`, writing it chunk by chunk with `stream::PngWriter` if it is
+/// stdout or a special file.
+fn save_png<P: AsRef<Path>>(
+    file_path: P,
+    png: &Png,
+    options: &SaveOptions,
+) -> Result<(), CommandError> {
+    match output::open_stream(&file_path)? {
+        Some(stream) => {
+            stream::write_png(stream, png)?;
+        }
+        None => output::save_atomic(file_path, &png.as_bytes(), options)?,
+    }
     Ok(())
 }
 
-/// Encodes a message into a PNG file and saves the result
+/// Encodes a message into a PNG file and saves the result. With `in_place`, the message is
+/// written over the end of the input file instead of rewriting all of it, when possible.
+/// With `after_iend`, the message chunk is hidden after the IEND chunk, where decoders don't
+/// look, instead of before it.
+#[allow(clippy::too_many_arguments)]
 pub fn encode(
+    out: &mut dyn Write,
     file_path: &str,
     chunk_type: &str,
     message: &str,
     output: Option<&str>,
+    options: &SaveOptions,
+    in_place: bool,
+    after_iend: bool,
 ) -> Result<(), CommandError> {
-    let mut png = read_png(file_path)?;
     let chunk_type = ChunkType::from_str(chunk_type)?;
     let chunk = Chunk::new(chunk_type, message.as_bytes().to_vec());
+    let placement = ChunkPlacement::BeforeIend;
 
-    png.append_chunk(chunk);
     let output = match output {
         Some(o) => o,
         None => file_path,
     };
-    save_png(output, png)
+    let mut stderr = io::stderr();
+    let out = status_out(out, output, &mut stderr);
+    // Chunks describing the image need the whole file to drop unsafe-to-copy chunks
+    if in_place && output == file_path && !is_stdio(file_path) && !Png::is_image_chunk(&chunk_type)
+    {
+        let outcome = append_in_place(file_path, std::slice::from_ref(&chunk), placement)?;
+        if outcome == InPlaceOutcome::Updated {
+            return Ok(());
+        }
+    }
+
+    let mut source = None;
+    let mut png = open_png(&mut source, file_path, InputMode::Buffered, Png::try_from)?;
+
+    if after_iend {
+        png.append_trailing_chunk(&chunk);
+    } else {
+        png.insert_chunk(chunk, placement);
+    }
+    save_png(output, &png, options)?;
+    report_dropped(out, &png)?;
+    Ok(())
 }
 
-/// Searches for a message hidden in a PNG file and prints the message if one is found
-pub fn decode(file_path: &str, chunk_type: &str) -> Result<(), CommandError> {
-    let png = read_png(file_path)?;
+/// Searches for a message hidden in a PNG file and prints the message of the first chunk
+/// matching the selector if one is found
+pub fn decode(
+    mut out: &mut dyn Write,
+    file_path: &str,
+    select: &str,
+    format: OutputFormat,
+    after_iend: bool,
+) -> Result<(), CommandError> {
+    let selector = ChunkSelector::from_str(select)?;
+    let mut source = None;
+    let mut png = open_png(
+        &mut source,
+        file_path,
+        InputMode::Auto,
+        Png::from_bytes_lazy,
+    )?;
+    // Chunks hidden after IEND are searched as a PNG of their own
+    if after_iend {
+        let hidden = png.trailing_chunks().map_while(Result::ok);
+        png = Png::from_chunks(hidden.map(|c| c.to_chunk()).collect());
+    }
 
-    match png.chunk_by_type(chunk_type) {
-        Some(chunk) => println!("Message found: {}", chunk.data_as_string().unwrap()),
-        None => println!("No message found"),
+    report::write_decoded(
+        &mut out,
+        format,
+        file_path,
+        select,
+        selector.select(&png).next().map(|(_, chunk)| chunk),
+    )?;
+    out.flush()?;
+    Ok(())
+}
+
+/// Removes the first chunk matching the selector from a PNG file, or every matching chunk
+/// with `all`, and saves the result to `output`, or over the input file if not given. With
+/// `in_place`, a chunk of an exact type after the image data is removed by rewriting only the
+/// end of the file.
+pub fn remove(
+    out: &mut dyn Write,
+    file_path: &str,
+    select: &str,
+    all: bool,
+    output: Option<&str>,
+    options: &SaveOptions,
+    in_place: bool,
+) -> Result<(), CommandError> {
+    let selector = ChunkSelector::from_str(select)?;
+    let output = output.unwrap_or(file_path);
+    let mut stderr = io::stderr();
+    let out = status_out(out, output, &mut stderr);
+    if let (true, false, Some(chunk_type)) = (in_place, all, selector.exact_type()) {
+        if output == file_path && !is_stdio(file_path) {
+            if let Some(chunk) = remove_in_place(file_path, &chunk_type.to_string())? {
+                writeln!(out, "Removed chunk: {chunk}")?;
+                return Ok(());
+            }
+        }
+    }
+
+    let mut source = None;
+    let mut png = open_png(&mut source, file_path, InputMode::Buffered, Png::try_from)?;
+
+    let mut index = 0;
+    let mut found = false;
+    let removed = png.retain_chunks(|chunk| {
+        let remove = (all || !found) && selector.matches(index, chunk);
+        found |= remove;
+        index += 1;
+        !remove
+    });
+    if removed.is_empty() {
+        return Err(ChunkNotFoundError::new(select).into());
+    }
+    save_png(output, &png, options)?;
+
+    for (_, chunk) in removed {
+        writeln!(out, "Removed chunk: {chunk}")?;
     }
+    report_dropped(out, &png)?;
     Ok(())
 }
 
-/// Removes a chunk from a PNG file and saves the result
-pub fn remove(file_path: &str, chunk_type: &str) -> Result<(), CommandError> {
-    let mut png = read_png(file_path)?;
+/// Prints the chunks in a PNG file, or only those matching `select`, with up to `preview`
+/// bytes of each chunk's data in the JSON formats
+pub fn print_chunks(
+    mut out: &mut dyn Write,
+    file_path: &str,
+    select: Option<&str>,
+    format: OutputFormat,
+    preview: Option<usize>,
+) -> Result<(), CommandError> {
+    let selector = select.map(ChunkSelector::from_str).transpose()?;
+    let mut source = None;
+    let png = open_png(
+        &mut source,
+        file_path,
+        InputMode::Auto,
+        Png::from_bytes_lazy,
+    )?;
 
-    let chunk = png.remove_chunk(chunk_type)?;
-    save_png(file_path, png)?;
+    report::write_chunks(
+        &mut out,
+        format,
+        file_path,
+        &png,
+        selector.as_ref(),
+        preview,
+    )?;
+    out.flush()?;
+    Ok(())
+}
+
+/// Exports the chunks of a PNG file into a manifest, written to `out` unless `output` is
+/// given. With `data_dir`, chunk data is written to files in that directory, relative to
+/// the manifest, instead of being inlined as base64.
+pub fn export(
+    out: &mut dyn Write,
+    file_path: &str,
+    output: Option<&str>,
+    data_dir: Option<&str>,
+    format: Option<ManifestFormat>,
+) -> Result<(), CommandError> {
+    let mut source = None;
+    let png = open_png(&mut source, file_path, InputMode::Auto, Png::try_from)?;
+
+    let format =
+        format.unwrap_or_else(|| output.map_or_else(Default::default, ManifestFormat::from_path));
+    let base_dir = output.map_or(Path::new("."), parent_dir);
+    let manifest = match data_dir {
+        Some(data_dir) => Manifest::from_png_with_files(&png, base_dir, data_dir)?,
+        None => Manifest::from_png(&png),
+    };
+
+    let text = manifest.to_text(format)?;
+    match output {
+        Some(output) => output::save(output, text.as_bytes(), &SaveOptions::default())?,
+        None => out.write_all(text.as_bytes())?,
+    }
+    Ok(())
+}
+
+/// Rebuilds a PNG file from a manifest and saves it to `output`
+pub fn import(
+    manifest_path: &str,
+    output: &str,
+    format: Option<ManifestFormat>,
+    options: &SaveOptions,
+) -> Result<(), CommandError> {
+    let text = match is_stdio(manifest_path) {
+        true => io::read_to_string(io::stdin().lock())?,
+        false => std::fs::read_to_string(manifest_path)?,
+    };
+    let format = format.unwrap_or_else(|| ManifestFormat::from_path(manifest_path));
+
+    let png = Manifest::parse(&text, format)?.to_png(parent_dir(manifest_path))?;
+    save_png(output, &png, options)
+}
+
+/// Compares the chunks of two PNG files and prints what was added, removed, moved or changed.
+/// With `pixels`, image data is compared by its decoded pixels instead of its bytes.
+pub fn diff(
+    mut out: &mut dyn Write,
+    old_path: &str,
+    new_path: &str,
+    pixels: bool,
+    format: OutputFormat,
+) -> Result<(), CommandError> {
+    let (mut old_source, mut new_source) = (None, None);
+    let old = open_png(&mut old_source, old_path, InputMode::Auto, Png::try_from)?;
+    let new = open_png(&mut new_source, new_path, InputMode::Auto, Png::try_from)?;
 
-    println!("Removed chunk: {chunk}");
+    diff::write_diff(
+        &mut out,
+        format,
+        old_path,
+        new_path,
+        &diff::diff(&old, &new, pixels),
+    )?;
+    out.flush()?;
     Ok(())
 }
 
-/// Prints all of the chunks in a PNG file
-pub fn print_chunks(file_path: &str) -> Result<(), CommandError> {
-    let png = read_png(file_path)?;
+/// Strips metadata from a PNG file and prints what was removed. Chunks are selected by a
+/// preset, a selector or, with `keep`, by an allow-list of types; `gps` removes the GPS fields
+/// of eXIf chunks and `trailing` any bytes after IEND. The result is saved to `output`, or over
+/// the input file if not given.
+#[allow(clippy::too_many_arguments)]
+pub fn strip(
+    out: &mut dyn Write,
+    file_path: &str,
+    output: Option<&str>,
+    preset: Option<StripPreset>,
+    select: Option<&str>,
+    keep: &[String],
+    gps: bool,
+    trailing: bool,
+    format: OutputFormat,
+    options: &SaveOptions,
+) -> Result<(), CommandError> {
+    let policy = match (preset, select) {
+        (Some(preset), _) => Some(StripPolicy::Preset(preset)),
+        (None, Some(select)) => Some(StripPolicy::Select(ChunkSelector::from_str(select)?)),
+        (None, None) if !keep.is_empty() => Some(StripPolicy::Keep(
+            keep.iter()
+                .map(|t| ChunkType::from_str(t))
+                .collect::<Result<_, _>>()?,
+        )),
+        (None, None) => None,
+    };
+
+    let mut source = None;
+    let mut png = open_png(&mut source, file_path, InputMode::Buffered, Png::try_from)?;
+    let strip_report = strip::strip(&mut png, policy.as_ref(), gps, trailing)?;
+    let output = output.unwrap_or(file_path);
+    // A file is only rewritten if something changed, but stdout always gets the PNG
+    if !strip_report.is_empty() || output != file_path || is_stdio(output) {
+        save_png(output, &png, options)?;
+    }
+
+    let mut stderr = io::stderr();
+    let mut out = status_out(out, output, &mut stderr);
+    strip::write_report(&mut out, format, file_path, &strip_report)?;
+    out.flush()?;
+    Ok(())
+}
+
+/// Copies the ancillary chunks matching the selector from one PNG file into another and
+/// prints what was copied. The result is saved to `output`, or over `dst_path` if not given.
+pub fn copy_chunks(
+    out: &mut dyn Write,
+    src_path: &str,
+    dst_path: &str,
+    select: &str,
+    output: Option<&str>,
+    format: OutputFormat,
+    options: &SaveOptions,
+) -> Result<(), CommandError> {
+    let selector = ChunkSelector::from_str(select)?;
+
+    let (mut src_source, mut dst_source) = (None, None);
+    let src = open_png(&mut src_source, src_path, InputMode::Auto, Png::try_from)?;
+    let mut dst = open_png(
+        &mut dst_source,
+        dst_path,
+        InputMode::Buffered,
+        Png::try_from,
+    )?;
+
+    let copy_report = copy::copy_chunks(&src, &mut dst, &selector)?;
+    let output = output.unwrap_or(dst_path);
+    save_png(output, &dst, options)?;
+
+    let mut stderr = io::stderr();
+    let mut out = status_out(out, output, &mut stderr);
+    copy::write_report(&mut out, format, src_path, dst_path, &copy_report)?;
+    out.flush()?;
+    Ok(())
+}
+
+/// Checks a PNG file for every integrity problem and prints them. Fails if any is found.
+pub fn verify(
+    mut out: &mut dyn Write,
+    file_path: &str,
+    format: OutputFormat,
+) -> Result<(), CommandError> {
+    let source = PngSource::open(file_path)?;
+    let verify_report = verify::verify(source.bytes());
+
+    verify::write_report(&mut out, format, file_path, &verify_report)?;
+    out.flush()?;
+    match verify_report.problems.len() {
+        0 => Ok(()),
+        count => Err(CommandError::VerifyFailed(count)),
+    }
+}
+
+/// Repairs a damaged PNG file and prints every fix made. The result is saved to `output`, or
+/// over the input file if not given; an intact input file is left untouched.
+pub fn repair(
+    out: &mut dyn Write,
+    file_path: &str,
+    output: Option<&str>,
+    repair_options: &RepairOptions,
+    format: OutputFormat,
+    options: &SaveOptions,
+) -> Result<(), CommandError> {
+    let source = PngSource::open_with(file_path, InputMode::Buffered)?;
+    let (repaired, repair_report) = repair::repair(source.bytes(), repair_options)?;
+    let output = output.unwrap_or(file_path);
+    if !repair_report.is_unchanged() || output != file_path || is_stdio(output) {
+        output::save(output, &repaired, options)?;
+    }
 
-    println!("{png}");
+    let mut stderr = io::stderr();
+    let mut out = status_out(out, output, &mut stderr);
+    repair::write_report(&mut out, format, file_path, &repair_report)?;
+    out.flush()?;
     Ok(())
 }
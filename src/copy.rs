@@ -0,0 +1,324 @@
+use std::{
+    fmt::{self, Display},
+    io::{self, Write},
+};
+
+use serde::Serialize;
+
+use crate::{
+    chunk::ChunkRef,
+    chunk_type::ChunkType,
+    png::{ChunkPlacement, Png},
+    report::{self, OutputFormat, SCHEMA_VERSION},
+    selector::ChunkSelector,
+};
+
+/// Ancillary chunks that must come before PLTE and IDAT.
+const BEFORE_PLTE: [&str; 8] = [
+    "cHRM", "gAMA", "iCCP", "sBIT", "sRGB", "cICP", "mDCV", "cLLI",
+];
+/// Ancillary chunks that must come after PLTE and before IDAT.
+const AFTER_PLTE: [&str; 3] = ["bKGD", "hIST", "tRNS"];
+/// Other ancillary chunks that must come before IDAT.
+const BEFORE_IDAT: [&str; 4] = ["pHYs", "sPLT", "eXIf", "acTL"];
+/// Ancillary chunks that may appear anywhere between IHDR and IEND.
+const ANYWHERE: [&str; 4] = ["tIME", "tEXt", "zTXt", "iTXt"];
+/// Ancillary chunks that may appear more than once.
+const REPEATABLE: [&str; 4] = ["sPLT", "tEXt", "zTXt", "iTXt"];
+
+#[derive(Debug)]
+pub enum CopyError {
+    /// Critical chunks describe the image itself and are never copied.
+    CriticalChunk(ChunkType),
+}
+
+impl Display for CopyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::CriticalChunk(chunk_type) => {
+                writeln!(f, "Refusing to copy critical chunk {chunk_type}")
+            }
+        }
+    }
+}
+
+/// Why a selected chunk was not copied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+    /// The chunk is unknown and not safe to copy, and the critical chunks of the two PNGs
+    /// differ.
+    UnsafeToCopy,
+}
+
+/// A chunk `copy_chunks` copied or skipped. The index is the chunk's position in the source.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CopiedChunk {
+    pub index: usize,
+    #[serde(rename = "type")]
+    pub chunk_type: String,
+    /// Set when the chunk replaced a chunk of the same type that may only appear once.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub replaced: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub skipped: Option<SkipReason>,
+}
+
+/// What `copy_chunks` did with each selected chunk.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct CopyReport {
+    pub chunks: Vec<CopiedChunk>,
+    /// The types of the unknown unsafe-to-copy chunks of the destination dropped because the
+    /// image changed.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub dropped: Vec<String>,
+}
+
+/// Returns where a chunk copied from `src` belongs in another PNG. Known chunks follow the
+/// ordering rules of the PNG spec; unknown chunks keep their side of the image data.
+fn placement(src: &Png, index: usize, chunk: &ChunkRef) -> ChunkPlacement {
+    let chunk_type = chunk.chunk_type().to_string();
+    if BEFORE_PLTE.contains(&chunk_type.as_str()) {
+        ChunkPlacement::BeforePlte
+    } else if AFTER_PLTE.contains(&chunk_type.as_str())
+        || BEFORE_IDAT.contains(&chunk_type.as_str())
+    {
+        ChunkPlacement::BeforeIdat
+    } else if ANYWHERE.contains(&chunk_type.as_str()) {
+        ChunkPlacement::BeforeIend
+    } else {
+        match src
+            .chunks()
+            .position(|c| c.chunk_type().to_string() == "IDAT")
+        {
+            Some(idat) if index < idat => ChunkPlacement::BeforeIdat,
+            _ => ChunkPlacement::BeforeIend,
+        }
+    }
+}
+
+fn critical_chunks<'a>(png: &'a Png) -> impl Iterator<Item = ChunkRef<'a>> + 'a {
+    png.chunks().filter(|c| c.chunk_type().is_critical())
+}
+
+/// Copies the chunks of `src` matching the selector into `dst`, each in a position the PNG
+/// spec allows. If the critical chunks of the two PNGs differ, unknown chunks that are not
+/// safe to copy are skipped, since they may depend on the image they came from. Selecting a
+/// critical chunk is an error.
+pub fn copy_chunks(
+    src: &Png,
+    dst: &mut Png,
+    selector: &ChunkSelector,
+) -> Result<CopyReport, CopyError> {
+    let selected: Vec<_> = selector.select(src).collect();
+    if let Some((_, chunk)) = selected.iter().find(|(_, c)| c.chunk_type().is_critical()) {
+        return Err(CopyError::CriticalChunk(*chunk.chunk_type()));
+    }
+
+    let same_image = critical_chunks(src)
+        .map(|c| (*c.chunk_type(), c.data()))
+        .eq(critical_chunks(dst).map(|c| (*c.chunk_type(), c.data())));
+
+    let mut report = CopyReport::default();
+    let dropped_before = dst.dropped_chunks().len();
+    for (index, chunk) in selected {
+        let chunk_type = *chunk.chunk_type();
+
+        let mut copied = CopiedChunk {
+            index,
+            chunk_type: chunk_type.to_string(),
+            replaced: false,
+            skipped: None,
+        };
+        if !same_image && Png::is_unknown_unsafe_to_copy(&chunk_type) {
+            copied.skipped = Some(SkipReason::UnsafeToCopy);
+            report.chunks.push(copied);
+            continue;
+        }
+
+        // Only the first chunk of a type from `src` replaces the ones already in `dst`
+        let first_of_type = src
+            .chunks()
+            .position(|c| *c.chunk_type() == chunk_type)
+            .is_some_and(|first| first == index);
+        if !REPEATABLE.contains(&copied.chunk_type.as_str()) && first_of_type {
+            copied.replaced = !dst
+                .retain_chunks(|c| *c.chunk_type() != chunk_type)
+                .is_empty();
+        }
+        dst.insert_chunk(chunk.to_chunk(), placement(src, index, &chunk));
+        report.chunks.push(copied);
+    }
+
+    report.dropped = dst.dropped_chunks()[dropped_before..]
+        .iter()
+        .map(|chunk| chunk.chunk_type().to_string())
+        .collect();
+    Ok(report)
+}
+
+#[derive(Debug, Serialize)]
+struct CopyDocument<'a> {
+    schema_version: u32,
+    source: &'a str,
+    file: &'a str,
+    #[serde(flatten)]
+    report: &'a CopyReport,
+}
+
+/// Writes what was copied between two PNGs in the given format.
+pub fn write_report<W: Write>(
+    w: &mut W,
+    format: OutputFormat,
+    source: &str,
+    file: &str,
+    copy_report: &CopyReport,
+) -> io::Result<()> {
+    match format {
+        OutputFormat::Text | OutputFormat::Table => {
+            for chunk in &copy_report.chunks {
+                match (chunk.skipped, chunk.replaced) {
+                    (Some(SkipReason::UnsafeToCopy), _) => writeln!(
+                        w,
+                        "Skipped {} chunk #{}: not safe to copy into a different image",
+                        chunk.chunk_type, chunk.index
+                    )?,
+                    (None, true) => writeln!(
+                        w,
+                        "Copied {} chunk #{}, replacing the existing one",
+                        chunk.chunk_type, chunk.index
+                    )?,
+                    (None, false) => {
+                        writeln!(w, "Copied {} chunk #{}", chunk.chunk_type, chunk.index)?
+                    }
+                }
+            }
+            for chunk_type in &copy_report.dropped {
+                writeln!(
+                    w,
+                    "Dropped unsafe-to-copy chunk {chunk_type} because the image data changed"
+                )?;
+            }
+            if copy_report.chunks.is_empty() {
+                writeln!(w, "No matching chunks to copy")?;
+            }
+            Ok(())
+        }
+        OutputFormat::Json | OutputFormat::Ndjson => report::write_json(
+            w,
+            &CopyDocument {
+                schema_version: SCHEMA_VERSION,
+                source,
+                file,
+                report: copy_report,
+            },
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    use crate::chunk::Chunk;
+
+    fn png(chunks: &[(&str, &str)]) -> Png<'static> {
+        Png::from_chunks(
+            chunks
+                .iter()
+                .map(|(t, d)| Chunk::new(ChunkType::from_str(t).unwrap(), d.as_bytes().to_vec()))
+                .collect(),
+        )
+    }
+
+    fn select(selector: &str) -> ChunkSelector {
+        ChunkSelector::from_str(selector).unwrap()
+    }
+
+    fn chunk_types(png: &Png) -> Vec<String> {
+        png.chunks().map(|c| c.chunk_type().to_string()).collect()
+    }
+
+    #[test]
+    fn test_spec_positions() {
+        let src = png(&[
+            ("IHDR", "a"),
+            ("gAMA", "g"),
+            ("PLTE", "a"),
+            ("tRNS", "t"),
+            ("prIv", "p"),
+            ("IDAT", "a"),
+            ("tEXt", "x"),
+            ("laTe", "l"),
+            ("IEND", ""),
+        ]);
+        let mut dst = png(&[("IHDR", "a"), ("PLTE", "a"), ("IDAT", "a"), ("IEND", "")]);
+        copy_chunks(&src, &mut dst, &select("gAMA || tEXt || prIv || laTe")).unwrap();
+
+        assert_eq!(
+            chunk_types(&dst),
+            ["IHDR", "gAMA", "PLTE", "prIv", "IDAT", "tEXt", "laTe", "IEND"]
+        );
+    }
+
+    #[test]
+    fn test_unsafe_to_copy() {
+        let src = png(&[
+            ("IHDR", "a"),
+            ("sRGB", "s"),
+            ("prIV", "p"),
+            ("IDAT", "a"),
+            ("IEND", ""),
+        ]);
+
+        // Known chunks are copied even though they are not safe to copy
+        let mut different = png(&[("IHDR", "b"), ("IDAT", "b"), ("IEND", "")]);
+        let report = copy_chunks(&src, &mut different, &select("sRGB || prIV")).unwrap();
+        assert_eq!(report.chunks[0].skipped, None);
+        assert_eq!(report.chunks[1].skipped, Some(SkipReason::UnsafeToCopy));
+        assert_eq!(chunk_types(&different), ["IHDR", "sRGB", "IDAT", "IEND"]);
+
+        let mut same = png(&[("IHDR", "a"), ("IDAT", "a"), ("IEND", "")]);
+        let report = copy_chunks(&src, &mut same, &select("sRGB || prIV")).unwrap();
+        assert!(report.chunks.iter().all(|c| c.skipped.is_none()));
+        assert_eq!(chunk_types(&same), ["IHDR", "sRGB", "prIV", "IDAT", "IEND"]);
+    }
+
+    #[test]
+    fn test_replace_single_chunks() {
+        let src = png(&[
+            ("IHDR", "a"),
+            ("pHYs", "new"),
+            ("IDAT", "a"),
+            ("tEXt", "2"),
+            ("IEND", ""),
+        ]);
+        let mut dst = png(&[
+            ("IHDR", "b"),
+            ("pHYs", "old"),
+            ("IDAT", "b"),
+            ("tEXt", "1"),
+            ("IEND", ""),
+        ]);
+        let report = copy_chunks(&src, &mut dst, &select("pHYs,tEXt")).unwrap();
+
+        assert!(report.chunks[0].replaced);
+        assert!(!report.chunks[1].replaced);
+        assert_eq!(dst.chunk_by_type("pHYs").unwrap().data(), b"new");
+        assert_eq!(
+            chunk_types(&dst),
+            ["IHDR", "pHYs", "IDAT", "tEXt", "tEXt", "IEND"]
+        );
+    }
+
+    #[test]
+    fn test_refuse_critical() {
+        let src = png(&[("IHDR", "a"), ("IEND", "")]);
+        let mut dst = src.clone();
+        assert!(matches!(
+            copy_chunks(&src, &mut dst, &select("IHDR")),
+            Err(CopyError::CriticalChunk(_))
+        ));
+    }
+}
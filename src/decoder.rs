@@ -0,0 +1,507 @@
+use core::fmt;
+
+use crate::{
+    chunk::{ChunkDecodeError, CRC_SIZE, HDLC, LEN_SIZE, MAX_LEN, MIN_CHUNK_SIZE},
+    chunk_type::{ChunkType, CHUNK_TYPE_SIZE},
+    limits::DecodeLimits,
+    png::{Png, PngDecodeError},
+};
+
+/// A step of a PNG stream completed by `ChunkDecoder::decode`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeEvent<'a> {
+    /// The PNG signature was read and is valid.
+    Signature,
+    /// The length and type of a chunk were read and are within the limits.
+    ChunkHeader {
+        offset: u64,
+        length: u32,
+        chunk_type: ChunkType,
+    },
+    /// A piece of the data of the current chunk, borrowed from the input. A chunk's data
+    /// may arrive in any number of pieces, and in none if it is empty.
+    ChunkData(&'a [u8]),
+    /// The CRC of the current chunk was read. It matches the chunk unless the decoder is
+    /// `unverified`.
+    ChunkEnd { crc: u32 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Signature,
+    Header,
+    Data {
+        remaining: u32,
+    },
+    Crc,
+    /// After IEND, the end of the input or an error.
+    Done,
+}
+
+/// A push parser for PNG streams that does no IO of its own. Bytes are handed to `decode`
+/// in pieces of any size as they arrive, and each call reports how many it consumed and the
+/// event they completed. Chunk lengths and counts are checked against the limits as soon as
+/// a chunk header is read, and CRCs are checked as the data goes by, so a bad stream is
+/// rejected without buffering it.
+///
+/// ```
+/// use pngme::decoder::{ChunkDecoder, DecodeEvent};
+///
+/// let bytes = std::fs::read("tests/data/dice.png").unwrap();
+/// let mut decoder = ChunkDecoder::new();
+/// let mut types = Vec::new();
+/// for mut piece in bytes.chunks(100) {
+///     while !piece.is_empty() && !decoder.is_done() {
+///         let (used, event) = decoder.decode(piece).unwrap();
+///         if let Some(DecodeEvent::ChunkHeader { chunk_type, .. }) = event {
+///             types.push(chunk_type.to_string());
+///         }
+///         piece = &piece[used..];
+///     }
+/// }
+/// decoder.finish().unwrap();
+/// assert_eq!(types.first().unwrap(), "IHDR");
+/// assert_eq!(types.last().unwrap(), "IEND");
+/// ```
+#[derive(Clone)]
+pub struct ChunkDecoder {
+    state: State,
+    /// The bytes of a signature, chunk header or CRC read so far.
+    buf: [u8; 8],
+    filled: usize,
+    /// The offset of the next byte of input.
+    position: u64,
+    /// The offset of the chunk being read, or of the next one.
+    chunk_offset: u64,
+    chunk_type: Option<ChunkType>,
+    digest: Option<crc::Digest<'static, u32>>,
+    count: usize,
+    limits: DecodeLimits,
+    verify_crc: bool,
+    /// Whether the CRCs of IDAT chunks are checked too.
+    verify_image_data: bool,
+    /// Whether this decodes a PNG file, from its signature up to IEND, rather than a bare
+    /// sequence of chunks.
+    whole_file: bool,
+}
+
+impl ChunkDecoder {
+    /// Creates a decoder for a PNG file, which starts with the signature and ends after
+    /// IEND.
+    pub fn new() -> Self {
+        Self {
+            state: State::Signature,
+            buf: [0; 8],
+            filled: 0,
+            position: 0,
+            chunk_offset: 0,
+            chunk_type: None,
+            digest: None,
+            count: 0,
+            limits: DecodeLimits::UNLIMITED,
+            verify_crc: true,
+            verify_image_data: true,
+            whole_file: true,
+        }
+    }
+
+    /// Creates a decoder for a bare sequence of chunks, without a signature and without
+    /// stopping at IEND.
+    pub fn chunks_only() -> Self {
+        Self {
+            state: State::Header,
+            whole_file: false,
+            ..Self::new()
+        }
+    }
+
+    /// Sets the offset of the first byte of input within its source, so that chunk offsets
+    /// and errors are reported relative to the source.
+    pub fn starting_at(self, offset: u64) -> Self {
+        Self {
+            position: offset,
+            chunk_offset: offset,
+            ..self
+        }
+    }
+
+    /// Leaves CRC verification to the caller.
+    pub fn unverified(self) -> Self {
+        Self {
+            verify_crc: false,
+            ..self
+        }
+    }
+
+    /// Leaves the CRCs of IDAT chunks unchecked, so that their data is never read. Callers
+    /// that only need metadata can then skip over the image data, which is the bulk of
+    /// most files, with `skip_data` or by not touching the data of a mapped file.
+    pub fn lazy_image_data(self) -> Self {
+        Self {
+            verify_image_data: false,
+            ..self
+        }
+    }
+
+    /// Sets the limits chunks are checked against. Besides the chunk length and count, a
+    /// chunk that would end past the total size limit is rejected.
+    pub fn with_limits(self, limits: DecodeLimits) -> Self {
+        Self { limits, ..self }
+    }
+
+    /// The offset of the next byte of input.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Returns true once there is nothing more to decode: after IEND, after `finish`, or
+    /// after an error. `decode` consumes nothing from then on.
+    pub fn is_done(&self) -> bool {
+        self.state == State::Done
+    }
+
+    /// The number of bytes needed to complete the current step, which is never more than
+    /// what is left of the current chunk. Reading no more than this keeps a reader from
+    /// consuming bytes past IEND.
+    pub fn wanted(&self) -> usize {
+        match self.state {
+            State::Signature => Png::STANDARD_HEADER.len() - self.filled,
+            State::Header => LEN_SIZE + CHUNK_TYPE_SIZE - self.filled,
+            State::Data { remaining } => remaining as usize,
+            State::Crc => CRC_SIZE - self.filled,
+            State::Done => 0,
+        }
+    }
+
+    /// Consumes bytes from the start of `input`, returning how many were consumed and the
+    /// event they completed, if any. Input left over after an event should be passed to the
+    /// next call. No event with all input consumed means more input is needed.
+    pub fn decode<'a>(
+        &mut self,
+        input: &'a [u8],
+    ) -> Result<(usize, Option<DecodeEvent<'a>>), PngDecodeError> {
+        let result = self.step(input);
+        if result.is_err() {
+            self.state = State::Done;
+        }
+        result
+    }
+
+    /// Skips what is left of the data of the current chunk, for callers that seek past it
+    /// instead of passing it in. The CRC of the chunk is not checked. Returns the number of
+    /// bytes skipped, which is 0 outside chunk data.
+    pub fn skip_data(&mut self) -> u64 {
+        match self.state {
+            State::Data { remaining } => {
+                self.position += remaining as u64;
+                self.digest = None;
+                self.state = State::Crc;
+                remaining as u64
+            }
+            _ => 0,
+        }
+    }
+
+    /// Signals the end of the input. A PNG may end between chunks, even without IEND, but
+    /// not within the signature or a chunk.
+    pub fn finish(&mut self) -> Result<(), PngDecodeError> {
+        let state = core::mem::replace(&mut self.state, State::Done);
+        match state {
+            State::Signature => Err(PngDecodeError::TooShort(self.buf[..self.filled].to_vec())),
+            State::Header if self.filled == 0 => Ok(()),
+            State::Header | State::Data { .. } | State::Crc => {
+                Err(ChunkDecodeError::InvalidChunkSize {
+                    offset: self.chunk_offset,
+                    size: (self.position - self.chunk_offset) as usize,
+                }
+                .into())
+            }
+            State::Done => Ok(()),
+        }
+    }
+
+    fn step<'a>(
+        &mut self,
+        input: &'a [u8],
+    ) -> Result<(usize, Option<DecodeEvent<'a>>), PngDecodeError> {
+        let used = self.wanted().min(input.len());
+        if used == 0 {
+            return Ok((0, None));
+        }
+        self.position += used as u64;
+
+        if let State::Data { remaining } = self.state {
+            let data = &input[..used];
+            if let Some(digest) = &mut self.digest {
+                digest.update(data);
+            }
+            self.state = match remaining as usize - used {
+                0 => State::Crc,
+                remaining => State::Data {
+                    remaining: remaining as u32,
+                },
+            };
+            return Ok((used, Some(DecodeEvent::ChunkData(data))));
+        }
+
+        self.buf[self.filled..self.filled + used].copy_from_slice(&input[..used]);
+        self.filled += used;
+        if self.wanted() > 0 {
+            return Ok((used, None));
+        }
+        self.filled = 0;
+        let event = match self.state {
+            State::Signature => self.end_signature()?,
+            State::Header => self.end_header()?,
+            _ => self.end_chunk()?,
+        };
+        Ok((used, Some(event)))
+    }
+
+    fn end_signature(&mut self) -> Result<DecodeEvent<'static>, PngDecodeError> {
+        if self.buf != Png::STANDARD_HEADER {
+            return Err(PngDecodeError::InvalidHeader(self.buf));
+        }
+        self.chunk_offset = self.position;
+        self.state = State::Header;
+        Ok(DecodeEvent::Signature)
+    }
+
+    fn end_header(&mut self) -> Result<DecodeEvent<'static>, PngDecodeError> {
+        let offset = self.chunk_offset;
+        let length = u32::from_be_bytes(self.buf[..LEN_SIZE].try_into().unwrap());
+        self.count += 1;
+        self.limits
+            .check_chunk(length, self.count)
+            .and_then(|_| {
+                self.limits
+                    .check_total_size(offset + MIN_CHUNK_SIZE as u64 + length as u64)
+            })
+            .map_err(|err| ChunkDecodeError::LimitExceeded { offset, err })?;
+        if length as usize > MAX_LEN {
+            return Err(ChunkDecodeError::DataExceedMaximumLength {
+                offset,
+                length: length as usize,
+            }
+            .into());
+        }
+
+        let type_bytes: [u8; CHUNK_TYPE_SIZE] = self.buf[LEN_SIZE..].try_into().unwrap();
+        let chunk_type = ChunkType::try_from(type_bytes)
+            .map_err(|err| ChunkDecodeError::ChunkTypeDecode { offset, err })?;
+        self.chunk_type = Some(chunk_type);
+        let verify = self.verify_crc && (self.verify_image_data || &type_bytes != b"IDAT");
+        self.digest = verify.then(|| {
+            let mut digest = HDLC.digest();
+            digest.update(&type_bytes);
+            digest
+        });
+        self.state = match length {
+            0 => State::Crc,
+            remaining => State::Data { remaining },
+        };
+        Ok(DecodeEvent::ChunkHeader {
+            offset,
+            length,
+            chunk_type,
+        })
+    }
+
+    fn end_chunk(&mut self) -> Result<DecodeEvent<'static>, PngDecodeError> {
+        let crc = u32::from_be_bytes(self.buf[..CRC_SIZE].try_into().unwrap());
+        if let Some(digest) = self.digest.take() {
+            let calculated_crc = digest.finalize();
+            if calculated_crc != crc {
+                return Err(ChunkDecodeError::CrcMismatch {
+                    offset: self.chunk_offset,
+                    expected_crc: calculated_crc,
+                    given_crc: crc,
+                }
+                .into());
+            }
+        }
+        let is_iend = self.chunk_type.take().map(|chunk_type| chunk_type.bytes()) == Some(*b"IEND");
+        self.chunk_offset = self.position;
+        self.state = match self.whole_file && is_iend {
+            true => State::Done,
+            false => State::Header,
+        };
+        Ok(DecodeEvent::ChunkEnd { crc })
+    }
+}
+
+impl Default for ChunkDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for ChunkDecoder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChunkDecoder")
+            .field("state", &self.state)
+            .field("position", &self.position)
+            .field("chunk_offset", &self.chunk_offset)
+            .field("count", &self.count)
+            .field("limits", &self.limits)
+            .field("verify_crc", &self.verify_crc)
+            .field("verify_image_data", &self.verify_image_data)
+            .field("whole_file", &self.whole_file)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::{
+        format,
+        string::{String, ToString},
+        vec::Vec,
+    };
+
+    const DICE: &[u8] = include_bytes!("../tests/data/dice.png");
+
+    /// Feeds `bytes` to a decoder in pieces of `size`, collecting the events as strings.
+    fn events(
+        decoder: &mut ChunkDecoder,
+        bytes: &[u8],
+        size: usize,
+    ) -> Result<Vec<String>, PngDecodeError> {
+        let mut events = Vec::new();
+        for mut piece in bytes.chunks(size) {
+            while !piece.is_empty() && !decoder.is_done() {
+                let (used, event) = decoder.decode(piece)?;
+                piece = &piece[used..];
+                events.extend(event.map(|event| match event {
+                    DecodeEvent::Signature => "signature".to_string(),
+                    DecodeEvent::ChunkHeader {
+                        offset, chunk_type, ..
+                    } => format!("{chunk_type}@{offset}"),
+                    DecodeEvent::ChunkData(data) => format!("{}", data.len()),
+                    DecodeEvent::ChunkEnd { .. } => "end".to_string(),
+                }));
+            }
+        }
+        decoder.finish()?;
+        Ok(events)
+    }
+
+    #[test]
+    fn test_events() {
+        let mut bytes = DICE.to_vec();
+        bytes.extend(b"trailing");
+        let events = events(&mut ChunkDecoder::new(), &bytes, 4096).unwrap();
+        assert_eq!(
+            events[..8],
+            [
+                "signature",
+                "IHDR@8",
+                "13",
+                "end",
+                "sRGB@33",
+                "1",
+                "end",
+                "gAMA@46"
+            ]
+        );
+        // The IDAT data is split where the input is
+        let idat = events.iter().position(|e| e == "IDAT@83").unwrap();
+        assert_eq!(events[idat + 1..idat + 4], ["4005", "676", "end"]);
+        assert_eq!(events[events.len() - 2..], ["IEND@4791", "end"]);
+    }
+
+    #[test]
+    fn test_piece_sizes() {
+        let whole = events(&mut ChunkDecoder::new(), DICE, DICE.len()).unwrap();
+        for size in [1, 3, 8, 13] {
+            let events = events(&mut ChunkDecoder::new(), DICE, size).unwrap();
+            let headers: Vec<_> = events.iter().filter(|e| e.contains('@')).collect();
+            assert_eq!(
+                headers,
+                whole.iter().filter(|e| e.contains('@')).collect::<Vec<_>>()
+            );
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut bytes = DICE.to_vec();
+        bytes[100] ^= 1;
+        assert!(matches!(
+            events(&mut ChunkDecoder::new(), &bytes, 7),
+            Err(PngDecodeError::ChunkDecode(ChunkDecodeError::CrcMismatch {
+                offset: 83,
+                ..
+            }))
+        ));
+        let mut decoder = ChunkDecoder::new().unverified();
+        assert!(events(&mut decoder, &bytes, 7).is_ok());
+
+        assert!(matches!(
+            events(&mut ChunkDecoder::new(), &DICE[..5], 7),
+            Err(PngDecodeError::TooShort(bytes)) if bytes.len() == 5
+        ));
+        assert!(matches!(
+            events(&mut ChunkDecoder::new(), &DICE[..100], 7),
+            Err(PngDecodeError::ChunkDecode(
+                ChunkDecodeError::InvalidChunkSize {
+                    offset: 83,
+                    size: 17
+                }
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_lazy_image_data() {
+        let mut bytes = DICE.to_vec();
+        bytes[100] ^= 1;
+        let mut decoder = ChunkDecoder::new().lazy_image_data();
+        assert!(events(&mut decoder, &bytes, 7).is_ok());
+
+        // Other chunks are still checked
+        bytes[40] ^= 1;
+        let mut decoder = ChunkDecoder::new().lazy_image_data();
+        assert!(events(&mut decoder, &bytes, 7).is_err());
+    }
+
+    #[test]
+    fn test_skip_data() {
+        let mut decoder = ChunkDecoder::new();
+        let mut input = &DICE[..91];
+        while !input.is_empty() {
+            let (used, _) = decoder.decode(input).unwrap();
+            input = &input[used..];
+        }
+        assert_eq!(decoder.skip_data(), 4681);
+        assert_eq!(decoder.skip_data(), 0);
+        assert_eq!(decoder.position(), 4772);
+
+        let events = events(&mut decoder, &DICE[4772..], 100).unwrap();
+        assert_eq!(events, ["end", "RuSt@4776", "3", "end", "IEND@4791", "end"]);
+    }
+
+    #[test]
+    fn test_rejects_early() {
+        let limits = DecodeLimits {
+            max_chunk_length: 4000,
+            ..DecodeLimits::default()
+        };
+        let mut decoder = ChunkDecoder::new().with_limits(limits);
+        // The IDAT header at 83 is enough to reject the file
+        let mut input = &DICE[..91];
+        let err = loop {
+            match decoder.decode(input) {
+                Ok((used, _)) => input = &input[used..],
+                Err(err) => break err,
+            }
+        };
+        assert!(matches!(
+            err,
+            PngDecodeError::ChunkDecode(ChunkDecodeError::LimitExceeded { offset: 83, .. })
+        ));
+        assert!(decoder.is_done());
+        assert_eq!(decoder.decode(&DICE[91..]).unwrap(), (0, None));
+    }
+}
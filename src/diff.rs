@@ -0,0 +1,577 @@
+use std::io::{self, Write};
+
+use serde::Serialize;
+
+use crate::{
+    chunk::ChunkRef,
+    image::{self, ImageError},
+    limits::DecodeLimits,
+    png::Png,
+    report::{self, OutputFormat, SCHEMA_VERSION},
+};
+
+/// One line of a text diff.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "op", content = "line", rename_all = "lowercase")]
+pub enum LineChange {
+    Same(String),
+    Removed(String),
+    Added(String),
+}
+
+/// A difference between the chunks of two PNGs. Indices refer to the position of a chunk in
+/// its PNG.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "change", rename_all = "lowercase")]
+pub enum ChunkChange {
+    /// A chunk only present in the second PNG.
+    Added {
+        #[serde(rename = "type")]
+        chunk_type: String,
+        index: usize,
+    },
+    /// A chunk only present in the first PNG.
+    Removed {
+        #[serde(rename = "type")]
+        chunk_type: String,
+        index: usize,
+    },
+    /// A chunk present in both PNGs with the same data, but in a different order.
+    Moved {
+        #[serde(rename = "type")]
+        chunk_type: String,
+        from: usize,
+        to: usize,
+    },
+    /// A chunk whose data differs between the two PNGs. Text-like chunks come with a line
+    /// diff of their data.
+    Changed {
+        #[serde(rename = "type")]
+        chunk_type: String,
+        from: usize,
+        to: usize,
+        old_length: u32,
+        new_length: u32,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        text_diff: Option<Vec<LineChange>>,
+    },
+}
+
+/// The result of comparing the decoded pixels of two PNGs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PixelComparison {
+    Identical,
+    Different,
+    /// The image data of at least one PNG could not be decoded.
+    Undecodable,
+}
+
+/// The differences between the chunks of two PNGs.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ChunkDiff {
+    pub changes: Vec<ChunkChange>,
+    /// Set when IDAT chunks were compared by their decoded pixels instead of their data.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pixels: Option<PixelComparison>,
+}
+
+impl ChunkDiff {
+    /// Returns true if the PNGs have the same chunks in the same order, and the same pixels
+    /// if they were compared.
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty() && self.pixels.is_none_or(|p| p == PixelComparison::Identical)
+    }
+}
+
+/// The most cells `lcs` allocates for its table. Images split into many small IDAT chunks
+/// would otherwise need gigabytes to align.
+const MAX_LCS_CELLS: usize = 1 << 22;
+
+/// Returns the pairs of indices of a common subsequence of `a` and `b`. The common prefix
+/// and suffix are always aligned; what is left between them is aligned by a longest common
+/// subsequence if its table fits in `MAX_LCS_CELLS`, and left unaligned otherwise.
+fn lcs<T, F: Fn(&T, &T) -> bool>(a: &[T], b: &[T], eq: F) -> Vec<(usize, usize)> {
+    let prefix = a.iter().zip(b).take_while(|(x, y)| eq(x, y)).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(x, y)| eq(x, y))
+        .count();
+    let a_middle = &a[prefix..a.len() - suffix];
+    let b_middle = &b[prefix..b.len() - suffix];
+
+    let mut pairs: Vec<_> = (0..prefix).map(|i| (i, i)).collect();
+    if (a_middle.len() + 1).saturating_mul(b_middle.len() + 1) <= MAX_LCS_CELLS {
+        pairs.extend(
+            table_lcs(a_middle, b_middle, &eq)
+                .into_iter()
+                .map(|(i, j)| (prefix + i, prefix + j)),
+        );
+    }
+    pairs.extend((0..suffix).map(|k| (a.len() - suffix + k, b.len() - suffix + k)));
+    pairs
+}
+
+/// Returns the pairs of indices of a longest common subsequence of `a` and `b`, using a
+/// table of `(a.len() + 1) * (b.len() + 1)` cells.
+fn table_lcs<T, F: Fn(&T, &T) -> bool>(a: &[T], b: &[T], eq: F) -> Vec<(usize, usize)> {
+    let mut lengths = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lengths[i][j] = if eq(&a[i], &b[j]) {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                lengths[i + 1][j].max(lengths[i][j + 1])
+            };
+        }
+    }
+
+    let (mut i, mut j) = (0, 0);
+    let mut pairs = Vec::new();
+    while i < a.len() && j < b.len() {
+        if eq(&a[i], &b[j]) {
+            pairs.push((i, j));
+            i += 1;
+            j += 1;
+        } else if lengths[i + 1][j] >= lengths[i][j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    pairs
+}
+
+/// Diffs two texts line by line.
+pub fn diff_lines(old: &str, new: &str) -> Vec<LineChange> {
+    let a: Vec<_> = old.lines().collect();
+    let b: Vec<_> = new.lines().collect();
+
+    let mut changes = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    for (k, l) in lcs(&a, &b, |x, y| x == y)
+        .into_iter()
+        .chain([(a.len(), b.len())])
+    {
+        changes.extend(a[i..k].iter().map(|s| LineChange::Removed(s.to_string())));
+        changes.extend(b[j..l].iter().map(|s| LineChange::Added(s.to_string())));
+        if k < a.len() {
+            changes.push(LineChange::Same(a[k].to_string()));
+        }
+        (i, j) = (k + 1, l + 1);
+    }
+    changes
+}
+
+/// Returns the data of a chunk as text if it is meant to be read as text: tEXt and iTXt
+/// chunks, and any other chunk whose data is printable UTF-8. NUL separators are shown as
+/// line breaks.
+fn text_data(chunk: &ChunkRef) -> Option<String> {
+    let text = std::str::from_utf8(chunk.data()).ok()?;
+    let is_text_chunk = matches!(chunk.chunk_type().to_string().as_str(), "tEXt" | "iTXt");
+    let printable = text
+        .chars()
+        .all(|c| !c.is_control() || c.is_whitespace() || c == '\0');
+    (is_text_chunk || printable).then(|| text.replace('\0', "\n"))
+}
+
+fn same_data(a: &ChunkRef, b: &ChunkRef) -> bool {
+    a.chunk_type() == b.chunk_type() && a.data() == b.data()
+}
+
+fn is_idat(chunk: &ChunkRef) -> bool {
+    chunk.chunk_type().to_string() == "IDAT"
+}
+
+/// Compares the chunks of two PNGs. Chunks are aligned by finding the longest run of
+/// identical chunks in order; of the rest, identical chunks are reported as moved, chunks of
+/// the same type are paired up in order as changed, and anything left over is added or
+/// removed. With `compare_pixels`, IDAT chunks are left out of the alignment and their
+/// decoded pixels are compared instead, so re-compressed image data is not a change.
+pub fn diff(a: &Png, b: &Png, compare_pixels: bool) -> ChunkDiff {
+    let keep = |c: &(usize, ChunkRef)| !compare_pixels || !is_idat(&c.1);
+    let a_chunks: Vec<_> = a.chunks().enumerate().filter(keep).collect();
+    let b_chunks: Vec<_> = b.chunks().enumerate().filter(keep).collect();
+
+    let mut a_matched = vec![false; a_chunks.len()];
+    let mut b_matched = vec![false; b_chunks.len()];
+    for (i, j) in lcs(&a_chunks, &b_chunks, |x, y| same_data(&x.1, &y.1)) {
+        a_matched[i] = true;
+        b_matched[j] = true;
+    }
+
+    let mut changes = Vec::new();
+
+    // Identical chunks out of order, then same-typed chunks with different data
+    for pass in [same_data, |x: &ChunkRef, y: &ChunkRef| {
+        x.chunk_type() == y.chunk_type()
+    }] {
+        for (i, (from, x)) in a_chunks.iter().enumerate() {
+            if a_matched[i] {
+                continue;
+            }
+            let found = b_chunks
+                .iter()
+                .enumerate()
+                .position(|(j, (_, y))| !b_matched[j] && pass(x, y));
+            let Some(j) = found else { continue };
+            let (to, y) = &b_chunks[j];
+            a_matched[i] = true;
+            b_matched[j] = true;
+
+            let chunk_type = x.chunk_type().to_string();
+            changes.push(if same_data(x, y) {
+                ChunkChange::Moved {
+                    chunk_type,
+                    from: *from,
+                    to: *to,
+                }
+            } else {
+                let text_diff = text_data(x)
+                    .zip(text_data(y))
+                    .map(|(old, new)| diff_lines(&old, &new));
+                ChunkChange::Changed {
+                    chunk_type,
+                    from: *from,
+                    to: *to,
+                    old_length: x.length(),
+                    new_length: y.length(),
+                    text_diff,
+                }
+            });
+        }
+    }
+
+    changes.extend(
+        a_chunks
+            .iter()
+            .zip(a_matched)
+            .filter(|(_, matched)| !matched)
+            .map(|((index, c), _)| ChunkChange::Removed {
+                chunk_type: c.chunk_type().to_string(),
+                index: *index,
+            }),
+    );
+    changes.extend(
+        b_chunks
+            .iter()
+            .zip(b_matched)
+            .filter(|(_, matched)| !matched)
+            .map(|((index, c), _)| ChunkChange::Added {
+                chunk_type: c.chunk_type().to_string(),
+                index: *index,
+            }),
+    );
+
+    let pixels = compare_pixels.then(|| compare_decoded(a, b));
+    ChunkDiff { changes, pixels }
+}
+
+/// Decodes the pixels of two PNGs under the default `DecodeLimits` and compares them. An
+/// image that breaks the limits is undecodable.
+fn compare_decoded(a: &Png, b: &Png) -> PixelComparison {
+    let decode = |png: &Png| -> Result<_, ImageError> {
+        Ok((
+            image::ImageHeader::from_png(png)?,
+            image::decode_pixels_with_limits(png, &DecodeLimits::default())?,
+        ))
+    };
+    match (decode(a), decode(b)) {
+        (Ok(a), Ok(b)) if a == b => PixelComparison::Identical,
+        (Ok(_), Ok(_)) => PixelComparison::Different,
+        _ => PixelComparison::Undecodable,
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct DiffLine<'a, T: Serialize> {
+    schema_version: u32,
+    old_file: &'a str,
+    new_file: &'a str,
+    #[serde(flatten)]
+    item: T,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "change", rename = "pixels")]
+struct PixelLine {
+    result: PixelComparison,
+}
+
+#[derive(Debug, Serialize)]
+struct DiffDocument<'a> {
+    schema_version: u32,
+    old_file: &'a str,
+    new_file: &'a str,
+    #[serde(flatten)]
+    diff: &'a ChunkDiff,
+}
+
+/// Writes a chunk diff in the given format. The text and table formats list one change per
+/// line; NDJSON writes one change per line, followed by the pixel comparison if there was one.
+pub fn write_diff<W: Write>(
+    w: &mut W,
+    format: OutputFormat,
+    old_file: &str,
+    new_file: &str,
+    diff: &ChunkDiff,
+) -> io::Result<()> {
+    match format {
+        OutputFormat::Text | OutputFormat::Table => {
+            writeln!(w, "--- {old_file}")?;
+            writeln!(w, "+++ {new_file}")?;
+            for change in &diff.changes {
+                match change {
+                    ChunkChange::Added { chunk_type, index } => {
+                        writeln!(w, "+ {chunk_type} #{index} added")?
+                    }
+                    ChunkChange::Removed { chunk_type, index } => {
+                        writeln!(w, "- {chunk_type} #{index} removed")?
+                    }
+                    ChunkChange::Moved {
+                        chunk_type,
+                        from,
+                        to,
+                    } => writeln!(w, "> {chunk_type} #{from} -> #{to} moved")?,
+                    ChunkChange::Changed {
+                        chunk_type,
+                        from,
+                        to,
+                        old_length,
+                        new_length,
+                        text_diff,
+                    } => {
+                        writeln!(
+                            w,
+                            "~ {chunk_type} #{from} -> #{to} changed ({old_length} -> {new_length} bytes)"
+                        )?;
+                        for line in text_diff.iter().flatten() {
+                            match line {
+                                LineChange::Same(s) => writeln!(w, "      {s}")?,
+                                LineChange::Removed(s) => writeln!(w, "    - {s}")?,
+                                LineChange::Added(s) => writeln!(w, "    + {s}")?,
+                            }
+                        }
+                    }
+                }
+            }
+            match diff.pixels {
+                Some(PixelComparison::Identical) => writeln!(w, "= pixels identical")?,
+                Some(PixelComparison::Different) => writeln!(w, "~ pixels changed")?,
+                Some(PixelComparison::Undecodable) => writeln!(w, "? pixels could not be decoded")?,
+                None => {}
+            }
+            Ok(())
+        }
+        OutputFormat::Json => report::write_json(
+            w,
+            &DiffDocument {
+                schema_version: SCHEMA_VERSION,
+                old_file,
+                new_file,
+                diff,
+            },
+        ),
+        OutputFormat::Ndjson => {
+            let line = |item| DiffLine {
+                schema_version: SCHEMA_VERSION,
+                old_file,
+                new_file,
+                item,
+            };
+            for change in &diff.changes {
+                report::write_json(w, &line(change))?;
+            }
+            match diff.pixels {
+                Some(result) => report::write_json(
+                    w,
+                    &DiffLine {
+                        schema_version: SCHEMA_VERSION,
+                        old_file,
+                        new_file,
+                        item: PixelLine { result },
+                    },
+                ),
+                None => Ok(()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::Write, str::FromStr};
+
+    use flate2::{write::ZlibEncoder, Compression};
+
+    use crate::{chunk::Chunk, chunk_type::ChunkType};
+
+    fn chunk(chunk_type: &str, data: &[u8]) -> Chunk {
+        Chunk::new(ChunkType::from_str(chunk_type).unwrap(), data.to_vec())
+    }
+
+    fn png(chunks: &[(&str, &[u8])]) -> Png<'static> {
+        Png::from_chunks(chunks.iter().map(|(t, d)| chunk(t, d)).collect())
+    }
+
+    #[test]
+    fn test_identical() {
+        let a = png(&[("IHDR", b"h"), ("tEXt", b"a\0b"), ("IEND", b"")]);
+        assert!(diff(&a, &a, false).is_empty());
+    }
+
+    #[test]
+    fn test_added_removed_moved() {
+        let a = png(&[
+            ("IHDR", b"h"),
+            ("ruSt", b"x"),
+            ("pHYs", b"p"),
+            ("IEND", b""),
+        ]);
+        let b = png(&[
+            ("IHDR", b"h"),
+            ("pHYs", b"p"),
+            ("teSt", b"y"),
+            ("IEND", b""),
+            ("ruSt", b"x"),
+        ]);
+        let changes = diff(&a, &b, false).changes;
+
+        assert!(changes.contains(&ChunkChange::Moved {
+            chunk_type: String::from("ruSt"),
+            from: 1,
+            to: 4
+        }));
+        assert!(changes.contains(&ChunkChange::Added {
+            chunk_type: String::from("teSt"),
+            index: 2
+        }));
+        assert_eq!(changes.len(), 2);
+
+        let changes = diff(&b, &a, false).changes;
+        assert!(changes.contains(&ChunkChange::Removed {
+            chunk_type: String::from("teSt"),
+            index: 2
+        }));
+    }
+
+    #[test]
+    fn test_changed_text() {
+        let a = png(&[("IHDR", b"h"), ("tEXt", b"Title\0Old title")]);
+        let b = png(&[("IHDR", b"h"), ("tEXt", b"Title\0New title")]);
+        let changes = diff(&a, &b, false).changes;
+
+        assert_eq!(
+            changes,
+            vec![ChunkChange::Changed {
+                chunk_type: String::from("tEXt"),
+                from: 1,
+                to: 1,
+                old_length: 15,
+                new_length: 15,
+                text_diff: Some(vec![
+                    LineChange::Same(String::from("Title")),
+                    LineChange::Removed(String::from("Old title")),
+                    LineChange::Added(String::from("New title")),
+                ]),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_changed_binary() {
+        let a = png(&[("ruSt", &[0, 1, 2])]);
+        let b = png(&[("ruSt", &[0, 1, 3, 4])]);
+        let changes = diff(&a, &b, false).changes;
+
+        assert!(matches!(
+            &changes[..],
+            [ChunkChange::Changed {
+                old_length: 3,
+                new_length: 4,
+                text_diff: None,
+                ..
+            }]
+        ));
+    }
+
+    #[test]
+    fn test_recompressed_idat() {
+        let header = [0, 0, 0, 1, 0, 0, 0, 1, 8, 0, 0, 0, 0];
+        let deflate = |level| {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::new(level));
+            encoder.write_all(&[0, 42]).unwrap();
+            encoder.finish().unwrap()
+        };
+        let a = png(&[("IHDR", &header), ("IDAT", &deflate(0)), ("IEND", b"")]);
+        let b = png(&[("IHDR", &header), ("IDAT", &deflate(9)), ("IEND", b"")]);
+
+        assert!(!diff(&a, &b, false).is_empty());
+
+        let with_pixels = diff(&a, &b, true);
+        assert!(with_pixels.is_empty());
+        assert_eq!(with_pixels.pixels, Some(PixelComparison::Identical));
+    }
+
+    #[test]
+    fn test_huge_image_undecodable() {
+        // An RGBA16 IHDR of 2^31-1 by 2^31-1 pixels
+        let header = [
+            0x7f, 0xff, 0xff, 0xff, 0x7f, 0xff, 0xff, 0xff, 16, 6, 0, 0, 0,
+        ];
+        let huge = png(&[("IHDR", &header), ("IDAT", &[0; 10]), ("IEND", b"")]);
+        assert_eq!(
+            diff(&huge, &huge, true).pixels,
+            Some(PixelComparison::Undecodable)
+        );
+    }
+
+    #[test]
+    fn test_diff_lines() {
+        assert_eq!(
+            diff_lines("a\nb\nc", "a\nc\nd"),
+            vec![
+                LineChange::Same(String::from("a")),
+                LineChange::Removed(String::from("b")),
+                LineChange::Same(String::from("c")),
+                LineChange::Added(String::from("d")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lcs_bounded() {
+        assert_eq!(
+            lcs(&[1, 2, 3, 4], &[1, 3, 5, 4], |x, y| x == y),
+            [(0, 0), (2, 1), (3, 3)]
+        );
+
+        // Too many items between the common ends for a table
+        let a: Vec<u32> = (0..3000).collect();
+        let b: Vec<u32> = [0].into_iter().chain(5000..8000).chain([2999]).collect();
+        assert_eq!(lcs(&a, &b, |x, y| x == y), [(0, 0), (2999, 3001)]);
+    }
+
+    #[test]
+    fn test_json_output() {
+        let a = png(&[("IHDR", b"h")]);
+        let b = png(&[("IHDR", b"h"), ("ruSt", b"x")]);
+        let mut out = Vec::new();
+        write_diff(
+            &mut out,
+            OutputFormat::Json,
+            "a.png",
+            "b.png",
+            &diff(&a, &b, false),
+        )
+        .unwrap();
+
+        let json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(json["schema_version"], SCHEMA_VERSION);
+        assert_eq!(json["changes"][0]["change"], "added");
+        assert_eq!(json["changes"][0]["type"], "ruSt");
+    }
+}
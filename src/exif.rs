@@ -0,0 +1,224 @@
+use std::fmt::{self, Display};
+
+/// The tag of the IFD0 entry pointing to the GPS IFD.
+const GPS_IFD_TAG: u16 = 0x8825;
+const IFD_ENTRY_SIZE: usize = 12;
+
+#[derive(Debug)]
+pub enum ExifError {
+    InvalidByteOrder([u8; 2]),
+    InvalidMagic(u16),
+    Truncated { offset: usize },
+}
+
+impl Display for ExifError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidByteOrder(order) => writeln!(f, "Invalid Exif byte order: {order:?}"),
+            Self::InvalidMagic(magic) => writeln!(f, "Invalid Exif magic number: {magic}"),
+            Self::Truncated { offset } => {
+                writeln!(f, "Exif data is truncated at offset {offset:#x}")
+            }
+        }
+    }
+}
+
+/// A TIFF structure as stored in an eXIf chunk, with its byte order.
+struct Tiff<'a> {
+    data: &'a mut [u8],
+    big_endian: bool,
+}
+
+impl Tiff<'_> {
+    fn range(&self, offset: usize, len: usize) -> Result<std::ops::Range<usize>, ExifError> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.data.len() => Ok(offset..end),
+            _ => Err(ExifError::Truncated { offset }),
+        }
+    }
+
+    fn u16_at(&self, offset: usize) -> Result<u16, ExifError> {
+        let bytes = self.data[self.range(offset, 2)?].try_into().unwrap();
+        Ok(match self.big_endian {
+            true => u16::from_be_bytes(bytes),
+            false => u16::from_le_bytes(bytes),
+        })
+    }
+
+    fn u32_at(&self, offset: usize) -> Result<u32, ExifError> {
+        let bytes = self.data[self.range(offset, 4)?].try_into().unwrap();
+        Ok(match self.big_endian {
+            true => u32::from_be_bytes(bytes),
+            false => u32::from_le_bytes(bytes),
+        })
+    }
+
+    fn set_u16_at(&mut self, offset: usize, value: u16) -> Result<(), ExifError> {
+        let bytes = match self.big_endian {
+            true => value.to_be_bytes(),
+            false => value.to_le_bytes(),
+        };
+        let range = self.range(offset, 2)?;
+        self.data[range].copy_from_slice(&bytes);
+        Ok(())
+    }
+
+    fn zero(&mut self, offset: usize, len: usize) -> Result<(), ExifError> {
+        let range = self.range(offset, len)?;
+        self.data[range].fill(0);
+        Ok(())
+    }
+}
+
+/// The size in bytes of a single value of an IFD field type, or 0 for unknown types.
+fn type_size(field_type: u16) -> usize {
+    match field_type {
+        1 | 2 | 6 | 7 => 1,
+        3 | 8 => 2,
+        4 | 9 | 11 => 4,
+        5 | 10 | 12 => 8,
+        _ => 0,
+    }
+}
+
+/// Removes the GPS fields from the data of an eXIf chunk, leaving every other field in
+/// place. The GPS IFD and the values it points to are zeroed, and its entry is dropped from
+/// IFD0, so the data keeps its length and other offsets stay valid. Returns the number of GPS
+/// fields removed.
+pub fn strip_gps(data: &mut [u8]) -> Result<usize, ExifError> {
+    let big_endian = match data.get(..2) {
+        Some(b"MM") => true,
+        Some(b"II") => false,
+        Some(order) => return Err(ExifError::InvalidByteOrder([order[0], order[1]])),
+        None => return Err(ExifError::Truncated { offset: 0 }),
+    };
+    let mut tiff = Tiff { data, big_endian };
+    match tiff.u16_at(2)? {
+        42 => {}
+        magic => return Err(ExifError::InvalidMagic(magic)),
+    }
+
+    let ifd0 = tiff.u32_at(4)? as usize;
+    let count = tiff.u16_at(ifd0)? as usize;
+    let entries = ifd0 + 2;
+    let gps_entry = (0..count)
+        .map(|i| entries + i * IFD_ENTRY_SIZE)
+        .find(|&entry| tiff.u16_at(entry).is_ok_and(|tag| tag == GPS_IFD_TAG));
+    let Some(gps_entry) = gps_entry else {
+        return Ok(0);
+    };
+
+    let gps_ifd = tiff.u32_at(gps_entry + 8)? as usize;
+    let gps_count = tiff.u16_at(gps_ifd)? as usize;
+    for i in 0..gps_count {
+        let entry = gps_ifd + 2 + i * IFD_ENTRY_SIZE;
+        let field_type = tiff.u16_at(entry + 2)?;
+        let len = (tiff.u32_at(entry + 4)? as usize).saturating_mul(type_size(field_type));
+        if len > 4 {
+            let offset = tiff.u32_at(entry + 8)? as usize;
+            tiff.zero(offset, len)?;
+        }
+    }
+    tiff.zero(gps_ifd, 2 + gps_count * IFD_ENTRY_SIZE + 4)?;
+
+    // Shift the following entries and the next IFD offset over the GPS entry
+    let end = tiff.range(entries, count * IFD_ENTRY_SIZE + 4)?.end;
+    tiff.data
+        .copy_within(gps_entry + IFD_ENTRY_SIZE..end, gps_entry);
+    tiff.zero(end - IFD_ENTRY_SIZE, IFD_ENTRY_SIZE)?;
+    tiff.set_u16_at(ifd0, count as u16 - 1)?;
+
+    Ok(gps_count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Little endian Exif data with an Orientation field, a GPS IFD pointer and a Software
+    /// field in IFD0, and a GPS IFD holding a latitude reference and a latitude.
+    fn testing_exif() -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend(b"II");
+        data.extend(42u16.to_le_bytes());
+        data.extend(8u32.to_le_bytes());
+
+        // IFD0 at 8, with 3 entries, ending at 8 + 2 + 36 + 4 = 50
+        data.extend(3u16.to_le_bytes());
+        data.extend([0x12, 0x01, 3, 0, 1, 0, 0, 0, 1, 0, 0, 0]);
+        data.extend([0x25, 0x88, 4, 0, 1, 0, 0, 0, 56, 0, 0, 0]);
+        data.extend([0x31, 0x01, 2, 0, 4, 0, 0, 0, b'p', b'n', b'g', 0]);
+        data.extend(0u32.to_le_bytes());
+        data.extend([0; 6]);
+
+        // GPS IFD at 56, with 2 entries, ending at 56 + 2 + 24 + 4 = 86
+        data.extend(2u16.to_le_bytes());
+        data.extend([0x01, 0x00, 2, 0, 2, 0, 0, 0, b'N', 0, 0, 0]);
+        data.extend([0x02, 0x00, 5, 0, 3, 0, 0, 0, 86, 0, 0, 0]);
+        data.extend(0u32.to_le_bytes());
+
+        // Latitude as three rationals at 86
+        for value in [52u32, 1, 30, 1, 15, 1] {
+            data.extend(value.to_le_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn test_strip_gps() {
+        let mut data = testing_exif();
+        let len = data.len();
+
+        assert_eq!(strip_gps(&mut data).unwrap(), 2);
+        assert_eq!(data.len(), len);
+        assert_eq!(&data[8..10], [2, 0]);
+        assert_eq!(&data[10..12], [0x12, 0x01]);
+        assert_eq!(&data[22..24], [0x31, 0x01]);
+        assert_eq!(&data[30..34], b"png\0");
+        assert!(data[34..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_strip_gps_big_endian() {
+        let mut data = vec![b'M', b'M', 0, 42, 0, 0, 0, 8];
+        data.extend([0, 1]);
+        data.extend([0x88, 0x25, 0, 4, 0, 0, 0, 1, 0, 0, 0, 26]);
+        data.extend([0; 4]);
+        data.extend([0, 1]);
+        data.extend([0x00, 0x01, 0, 2, 0, 0, 0, 2, b'S', 0, 0, 0]);
+        data.extend([0; 4]);
+
+        assert_eq!(strip_gps(&mut data).unwrap(), 1);
+        assert_eq!(&data[8..10], [0, 0]);
+        assert!(data[10..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_no_gps() {
+        let mut data = testing_exif();
+        data[22] = 0x26;
+        let original = data.clone();
+
+        assert_eq!(strip_gps(&mut data).unwrap(), 0);
+        assert_eq!(data, original);
+    }
+
+    #[test]
+    fn test_invalid_exif() {
+        assert!(matches!(
+            strip_gps(&mut b"XX".to_vec()),
+            Err(ExifError::InvalidByteOrder(_))
+        ));
+        assert!(matches!(
+            strip_gps(&mut b"II\x2b\x00".to_vec()),
+            Err(ExifError::InvalidMagic(43))
+        ));
+
+        let mut data = testing_exif();
+        data.truncate(60);
+        assert!(matches!(
+            strip_gps(&mut data),
+            Err(ExifError::Truncated { .. })
+        ));
+    }
+}
@@ -0,0 +1,602 @@
+use alloc::{string::ToString, vec, vec::Vec};
+use core::fmt::{self, Display};
+#[cfg(feature = "std")]
+use std::io::{self, Read, Write};
+
+#[cfg(feature = "std")]
+use flate2::{
+    read::ZlibDecoder, write::ZlibEncoder, Compression, Decompress, DecompressError,
+    FlushDecompress, Status,
+};
+
+#[cfg(feature = "std")]
+use crate::limits::DecodeLimits;
+use crate::{limits::LimitError, png::Png};
+
+pub const IMAGE_HEADER_SIZE: usize = 13;
+
+/// The largest width or height the PNG spec allows, 2^31 - 1.
+pub const MAX_DIMENSION: u32 = i32::MAX as u32;
+
+/// The starting column, starting row, column step and row step of each Adam7 pass.
+const ADAM7: [(u32, u32, u32, u32); 7] = [
+    (0, 0, 8, 8),
+    (4, 0, 8, 8),
+    (0, 4, 4, 8),
+    (2, 0, 4, 4),
+    (0, 2, 2, 4),
+    (1, 0, 2, 2),
+    (0, 1, 1, 2),
+];
+
+/// The contents of an IHDR chunk. See the PNG spec for more details
+/// http://www.libpng.org/pub/png/spec/1.2/PNG-Chunks.html#C.IHDR
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageHeader {
+    pub width: u32,
+    pub height: u32,
+    pub bit_depth: u8,
+    pub color_type: u8,
+    pub compression_method: u8,
+    pub filter_method: u8,
+    pub interlace_method: u8,
+}
+
+#[derive(Debug)]
+pub enum ImageError {
+    MissingHeader,
+    InvalidHeaderLength(usize),
+    InvalidHeader(ImageHeader),
+    #[cfg(feature = "std")]
+    Inflate(io::Error),
+    InvalidFilter {
+        row: usize,
+        filter: u8,
+    },
+    DataLength {
+        expected: u64,
+        actual: u64,
+    },
+    LimitExceeded(LimitError),
+}
+
+impl Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingHeader => writeln!(f, "Missing IHDR chunk"),
+            Self::InvalidHeaderLength(length) => {
+                writeln!(f, "IHDR length not {IMAGE_HEADER_SIZE}: {length}")
+            }
+            Self::InvalidHeader(header) => writeln!(f, "Invalid IHDR: {header:?}"),
+            #[cfg(feature = "std")]
+            Self::Inflate(err) => writeln!(f, "Image data does not inflate: {err}"),
+            Self::InvalidFilter { row, filter } => {
+                writeln!(f, "Invalid filter type {filter} in scanline {row}")
+            }
+            Self::DataLength { expected, actual } => writeln!(
+                f,
+                "Image data length mismatch: {actual} (actual) vs {expected} (expected from IHDR)"
+            ),
+            Self::LimitExceeded(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl TryFrom<&[u8]> for ImageHeader {
+    type Error = ImageError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        if bytes.len() != IMAGE_HEADER_SIZE {
+            return Err(ImageError::InvalidHeaderLength(bytes.len()));
+        }
+
+        let header = Self {
+            width: u32::from_be_bytes(bytes[0..4].try_into().unwrap()),
+            height: u32::from_be_bytes(bytes[4..8].try_into().unwrap()),
+            bit_depth: bytes[8],
+            color_type: bytes[9],
+            compression_method: bytes[10],
+            filter_method: bytes[11],
+            interlace_method: bytes[12],
+        };
+        if !header.is_valid() {
+            return Err(ImageError::InvalidHeader(header));
+        }
+        Ok(header)
+    }
+}
+
+impl ImageHeader {
+    /// Reads the header from the IHDR chunk of a PNG.
+    pub fn from_png(png: &Png) -> Result<Self, ImageError> {
+        let ihdr = png.chunk_by_type("IHDR").ok_or(ImageError::MissingHeader)?;
+        Self::try_from(ihdr.data())
+    }
+
+    /// Returns this header as the data of an IHDR chunk.
+    pub fn as_bytes(&self) -> Vec<u8> {
+        self.width
+            .to_be_bytes()
+            .iter()
+            .chain(self.height.to_be_bytes().iter())
+            .chain(
+                [
+                    self.bit_depth,
+                    self.color_type,
+                    self.compression_method,
+                    self.filter_method,
+                    self.interlace_method,
+                ]
+                .iter(),
+            )
+            .copied()
+            .collect()
+    }
+
+    /// Returns true if the dimensions are non-zero and at most `MAX_DIMENSION`, and the bit
+    /// depth is allowed for the color type.
+    pub fn is_valid(&self) -> bool {
+        let depth_allowed = match self.color_type {
+            0 => matches!(self.bit_depth, 1 | 2 | 4 | 8 | 16),
+            3 => matches!(self.bit_depth, 1 | 2 | 4 | 8),
+            2 | 4 | 6 => matches!(self.bit_depth, 8 | 16),
+            _ => false,
+        };
+        depth_allowed
+            && (1..=MAX_DIMENSION).contains(&self.width)
+            && (1..=MAX_DIMENSION).contains(&self.height)
+            && self.compression_method == 0
+            && self.filter_method == 0
+            && self.interlace_method <= 1
+    }
+
+    /// Number of samples per pixel.
+    pub fn channels(&self) -> u64 {
+        match self.color_type {
+            2 => 3,
+            4 => 2,
+            6 => 4,
+            _ => 1,
+        }
+    }
+
+    /// Number of bits per pixel.
+    pub fn bits_per_pixel(&self) -> u64 {
+        self.channels() * self.bit_depth as u64
+    }
+
+    /// Number of bytes per complete pixel, rounded up to one, as used by the filters.
+    fn filter_stride(&self) -> usize {
+        (self.bits_per_pixel() as usize).div_ceil(8)
+    }
+
+    /// Length of one scanline of the given width, without its filter type byte.
+    pub fn row_len(&self, width: u32) -> u64 {
+        (width as u64 * self.bits_per_pixel()).div_ceil(8)
+    }
+
+    /// Width and height of each reduced image the data is split into: one for non-interlaced
+    /// images and seven for Adam7. Empty passes are included with a zero dimension.
+    pub fn passes(&self) -> Vec<(u32, u32)> {
+        if self.interlace_method == 0 {
+            return vec![(self.width, self.height)];
+        }
+
+        ADAM7
+            .iter()
+            .map(|&(x0, y0, dx, dy)| {
+                let width = (self.width as u64 + (dx - 1 - x0) as u64) / dx as u64;
+                let height = (self.height as u64 + (dy - 1 - y0) as u64) / dy as u64;
+                (width as u32, height as u32)
+            })
+            .collect()
+    }
+
+    /// Number of scanlines in the image data, counting those of every pass.
+    pub fn scanlines(&self) -> u64 {
+        self.passes()
+            .iter()
+            .filter(|&&(width, _)| width > 0)
+            .map(|&(_, height)| height as u64)
+            .sum()
+    }
+
+    /// Number of complete scanlines in the first `len` bytes of inflated image data, with
+    /// the number of bytes they take up.
+    pub fn complete_scanlines(&self, len: u64) -> (u64, u64) {
+        let (mut scanlines, mut used) = (0, 0);
+        for (width, height) in self.passes() {
+            if width == 0 || height == 0 {
+                continue;
+            }
+            let row_len = 1 + self.row_len(width);
+            let rows = ((len - used) / row_len).min(height as u64);
+            scanlines += rows;
+            used += rows * row_len;
+            if rows < height as u64 {
+                break;
+            }
+        }
+        (scanlines, used)
+    }
+
+    /// Length of the inflated image data, including the filter type byte of every scanline.
+    /// Saturates at `u64::MAX` for headers too large to ever decode.
+    pub fn expected_data_len(&self) -> u64 {
+        self.passes()
+            .iter()
+            .filter(|&&(width, height)| width > 0 && height > 0)
+            .map(|&(width, height)| (height as u64).saturating_mul(1 + self.row_len(width)))
+            .fold(0, u64::saturating_add)
+    }
+}
+
+/// Concatenates the data of every IDAT chunk.
+pub fn idat_data(png: &Png) -> Vec<u8> {
+    png.chunks()
+        .filter(|c| c.chunk_type().to_string() == "IDAT")
+        .flat_map(|c| c.data().iter().copied())
+        .collect()
+}
+
+/// Inflates a zlib stream, reading at most `limit` bytes of output.
+#[cfg(feature = "std")]
+pub fn inflate(data: &[u8], limit: u64) -> io::Result<Vec<u8>> {
+    let mut inflated = Vec::new();
+    ZlibDecoder::new(data)
+        .take(limit)
+        .read_to_end(&mut inflated)?;
+    Ok(inflated)
+}
+
+/// Inflates as much of a zlib stream as possible, up to `limit` bytes. Unlike `inflate`,
+/// a truncated or corrupt stream is not an error: everything inflated before the end of the
+/// data or the first corrupt byte is returned.
+#[cfg(feature = "std")]
+pub fn inflate_partial(data: &[u8], limit: u64) -> Vec<u8> {
+    let mut decompress = Decompress::new(true);
+    let mut inflated = Vec::new();
+    while (inflated.len() as u64) < limit {
+        let (total_in, total_out) = (decompress.total_in(), decompress.total_out());
+        inflated.reserve((limit - total_out).min(1 << 16) as usize);
+        let input = &data[total_in as usize..];
+        match decompress.decompress_vec(input, &mut inflated, FlushDecompress::None) {
+            Ok(Status::StreamEnd) | Err(_) => break,
+            Ok(_) if decompress.total_in() == total_in && decompress.total_out() == total_out => {
+                break
+            }
+            Ok(_) => {}
+        }
+    }
+    inflated.truncate(limit as usize);
+    inflated
+}
+
+/// Compresses data into a zlib stream at the given level, from 0 to 9.
+#[cfg(feature = "std")]
+pub fn deflate(data: &[u8], level: u32) -> Vec<u8> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::new(level));
+    encoder
+        .write_all(data)
+        .expect("writing to a Vec cannot fail");
+    encoder.finish().expect("writing to a Vec cannot fail")
+}
+
+/// How a zlib stream inflated, as checked by `inflate_len`.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InflateSummary {
+    /// The number of bytes inflated.
+    pub inflated: u64,
+    /// The number of input bytes used by the stream.
+    pub consumed: u64,
+    /// Whether the stream reached its end.
+    pub complete: bool,
+}
+
+/// Inflates a zlib stream without keeping the output, to check that it inflates cleanly.
+/// Stops early once more than `limit` bytes have been inflated.
+#[cfg(feature = "std")]
+pub fn inflate_len(data: &[u8], limit: u64) -> Result<InflateSummary, DecompressError> {
+    let mut decompress = Decompress::new(true);
+    let mut buf = vec![0; 1 << 16];
+    loop {
+        let (total_in, total_out) = (decompress.total_in(), decompress.total_out());
+        let input = &data[total_in as usize..];
+        let status = decompress.decompress(input, &mut buf, FlushDecompress::None)?;
+
+        let stalled = decompress.total_in() == total_in && decompress.total_out() == total_out;
+        if status == Status::StreamEnd || stalled || decompress.total_out() > limit {
+            return Ok(InflateSummary {
+                inflated: decompress.total_out(),
+                consumed: decompress.total_in(),
+                complete: status == Status::StreamEnd,
+            });
+        }
+    }
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let pa = (p - a as i16).abs();
+    let pb = (p - b as i16).abs();
+    let pc = (p - c as i16).abs();
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+/// Reverses scanline filtering, returning the raw scanlines of every pass without their
+/// filter type bytes.
+pub fn unfilter(header: &ImageHeader, data: &[u8]) -> Result<Vec<u8>, ImageError> {
+    let expected = header.expected_data_len();
+    if data.len() as u64 != expected {
+        return Err(ImageError::DataLength {
+            expected,
+            actual: data.len() as u64,
+        });
+    }
+
+    let stride = header.filter_stride();
+    let mut pixels = Vec::with_capacity(data.len());
+    let mut input = data;
+    let mut row = 0;
+
+    for (width, height) in header.passes() {
+        if width == 0 || height == 0 {
+            continue;
+        }
+        let row_len = header.row_len(width) as usize;
+        let mut prev = vec![0u8; row_len];
+
+        for _ in 0..height {
+            let filter = input[0];
+            let mut line = input[1..=row_len].to_vec();
+            input = &input[row_len + 1..];
+
+            for i in 0..row_len {
+                let a = if i >= stride { line[i - stride] } else { 0 };
+                let b = prev[i];
+                let c = if i >= stride { prev[i - stride] } else { 0 };
+                line[i] = line[i].wrapping_add(match filter {
+                    0 => 0,
+                    1 => a,
+                    2 => b,
+                    3 => ((a as u16 + b as u16) / 2) as u8,
+                    4 => paeth(a, b, c),
+                    _ => return Err(ImageError::InvalidFilter { row, filter }),
+                });
+            }
+
+            pixels.extend_from_slice(&line);
+            prev = line;
+            row += 1;
+        }
+    }
+    Ok(pixels)
+}
+
+/// Inflates and unfilters the image data of a PNG.
+#[cfg(feature = "std")]
+pub fn decode_pixels(png: &Png) -> Result<Vec<u8>, ImageError> {
+    decode_pixels_with_limits(png, &DecodeLimits::UNLIMITED)
+}
+
+/// Inflates and unfilters the image data of a PNG, failing before anything is inflated if
+/// the image is larger than the limits allow.
+#[cfg(feature = "std")]
+pub fn decode_pixels_with_limits(png: &Png, limits: &DecodeLimits) -> Result<Vec<u8>, ImageError> {
+    let header = ImageHeader::from_png(png)?;
+    limits
+        .check_header(&header)
+        .map_err(ImageError::LimitExceeded)?;
+    let limit = header.expected_data_len().saturating_add(1);
+    let data = inflate(&idat_data(png), limit).map_err(ImageError::Inflate)?;
+    unfilter(&header, &data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "std")]
+    use core::str::FromStr;
+
+    #[cfg(feature = "std")]
+    use crate::{chunk::Chunk, chunk_type::ChunkType};
+
+    #[cfg(feature = "std")]
+    fn testing_png(header: &ImageHeader, scanlines: &[u8], level: u32) -> Png<'static> {
+        Png::from_chunks(vec![
+            Chunk::new(ChunkType::from_str("IHDR").unwrap(), header.as_bytes()),
+            Chunk::new(
+                ChunkType::from_str("IDAT").unwrap(),
+                deflate(scanlines, level),
+            ),
+            Chunk::new(ChunkType::from_str("IEND").unwrap(), vec![]),
+        ])
+    }
+
+    fn rgb_header(width: u32, height: u32) -> ImageHeader {
+        ImageHeader {
+            width,
+            height,
+            bit_depth: 8,
+            color_type: 2,
+            compression_method: 0,
+            filter_method: 0,
+            interlace_method: 0,
+        }
+    }
+
+    #[test]
+    fn test_header_round_trip() {
+        let header = rgb_header(50, 50);
+        assert_eq!(
+            ImageHeader::try_from(header.as_bytes().as_ref()).unwrap(),
+            header
+        );
+
+        let png = Png::try_from(&include_bytes!("../tests/data/dice.png")[..]).unwrap();
+        let header = ImageHeader::from_png(&png).unwrap();
+        assert_eq!((header.width, header.height), (50, 50));
+        assert_eq!(header.color_type, 6);
+    }
+
+    #[test]
+    fn test_invalid_header() {
+        let mut header = rgb_header(1, 1);
+        header.bit_depth = 4;
+        assert!(ImageHeader::try_from(header.as_bytes().as_ref()).is_err());
+        assert!(ImageHeader::try_from(&[0u8; 12][..]).is_err());
+
+        for (width, height) in [(0, 1), (1, 0), (MAX_DIMENSION + 1, 1), (1, u32::MAX)] {
+            let header = rgb_header(width, height);
+            assert!(ImageHeader::try_from(header.as_bytes().as_ref()).is_err());
+        }
+        assert!(rgb_header(MAX_DIMENSION, MAX_DIMENSION).is_valid());
+    }
+
+    #[test]
+    fn test_huge_dimensions() {
+        let mut header = rgb_header(u32::MAX, u32::MAX);
+        header.interlace_method = 1;
+        assert_eq!(header.passes()[0], (u32::MAX / 8 + 1, u32::MAX / 8 + 1));
+        assert_eq!(header.passes()[6], (u32::MAX, u32::MAX / 2));
+
+        header.bit_depth = 16;
+        header.color_type = 6;
+        header.width = MAX_DIMENSION;
+        header.height = MAX_DIMENSION;
+        assert_eq!(header.expected_data_len(), u64::MAX);
+    }
+
+    #[test]
+    fn test_expected_data_len() {
+        assert_eq!(rgb_header(2, 3).expected_data_len(), 3 * 7);
+
+        let mut header = rgb_header(1, 1);
+        header.bit_depth = 16;
+        header.color_type = 0;
+        header.interlace_method = 1;
+        assert_eq!(
+            header
+                .passes()
+                .iter()
+                .filter(|p| p.0 > 0 && p.1 > 0)
+                .count(),
+            1
+        );
+        assert_eq!(header.expected_data_len(), 3);
+
+        let mut header = rgb_header(10, 1);
+        header.bit_depth = 1;
+        header.color_type = 0;
+        assert_eq!(header.expected_data_len(), 3);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_unfilter() {
+        #[rustfmt::skip]
+        let scanlines = [
+            1, 10, 20, 30, 1, 1, 1,
+            2, 1, 1, 1, 0, 0, 0,
+        ];
+        let png = testing_png(&rgb_header(2, 2), &scanlines, 6);
+
+        assert_eq!(
+            decode_pixels(&png).unwrap(),
+            [10, 20, 30, 11, 21, 31, 11, 21, 31, 11, 21, 31]
+        );
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_recompressed_pixels_match() {
+        let header = rgb_header(2, 2);
+        let scanlines = [0, 1, 2, 3, 4, 5, 6, 0, 7, 8, 9, 10, 11, 12];
+        let fast = testing_png(&header, &scanlines, 1);
+        let best = testing_png(&header, &scanlines, 9);
+
+        assert_ne!(idat_data(&fast), idat_data(&best));
+        assert_eq!(decode_pixels(&fast).unwrap(), decode_pixels(&best).unwrap());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_decode_errors() {
+        let header = rgb_header(2, 2);
+        let png = testing_png(&header, &[0; 10], 6);
+        assert!(matches!(
+            decode_pixels(&png),
+            Err(ImageError::DataLength {
+                expected: 14,
+                actual: 10
+            })
+        ));
+
+        let mut scanlines = [0; 14];
+        scanlines[7] = 9;
+        let png = testing_png(&header, &scanlines, 6);
+        assert!(matches!(
+            decode_pixels(&png),
+            Err(ImageError::InvalidFilter { row: 1, filter: 9 })
+        ));
+
+        // Data too large to ever inflate to does not overflow the inflate limit
+        let mut header = rgb_header(MAX_DIMENSION, MAX_DIMENSION);
+        header.bit_depth = 16;
+        header.color_type = 6;
+        let png = testing_png(&header, &[0; 10], 6);
+        assert!(matches!(
+            decode_pixels(&png),
+            Err(ImageError::DataLength {
+                expected: u64::MAX,
+                actual: 10
+            })
+        ));
+        assert!(matches!(
+            decode_pixels_with_limits(&png, &DecodeLimits::default()),
+            Err(ImageError::LimitExceeded(_))
+        ));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_inflate_len() {
+        let mut stream = deflate(&[7; 100], 6);
+        let summary = inflate_len(&stream, 1000).unwrap();
+        assert_eq!(summary.inflated, 100);
+        assert_eq!(summary.consumed, stream.len() as u64);
+        assert!(summary.complete);
+
+        stream.truncate(stream.len() - 4);
+        assert!(!inflate_len(&stream, 1000).unwrap().complete);
+        assert!(inflate_len(&[0x78, 0x9c, 0xff, 0xff], 1000).is_err());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_partial_scanlines() {
+        let header = rgb_header(2, 3);
+        assert_eq!(header.scanlines(), 3);
+        assert_eq!(header.complete_scanlines(15), (2, 14));
+        assert_eq!(header.complete_scanlines(21), (3, 21));
+
+        let stream = deflate(&[1; 21], 6);
+        assert_eq!(inflate_partial(&stream, 100), [1; 21]);
+        assert_eq!(inflate_partial(&stream, 10), [1; 10]);
+        assert!(inflate_partial(&stream[..stream.len() / 2], 100).len() < 21);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_decode_dice() {
+        let png = Png::try_from(&include_bytes!("../tests/data/dice.png")[..]).unwrap();
+        assert_eq!(decode_pixels(&png).unwrap().len(), 50 * 50 * 4);
+    }
+}
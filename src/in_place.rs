@@ -0,0 +1,315 @@
+use std::{
+    fmt::{self, Display},
+    fs::File,
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use crate::{
+    chunk::{Chunk, ChunkDecodeError, ChunkRef, LEN_SIZE, MIN_CHUNK_SIZE},
+    chunk_type::{ChunkType, CHUNK_TYPE_SIZE},
+    png::{ChunkPlacement, Png, PngDecodeError},
+};
+
+/// Outcome of trying to update a PNG file without rewriting all of it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InPlaceOutcome {
+    /// The file was updated in place.
+    Updated,
+    /// The change cannot be made in place and the whole file has to be rewritten instead.
+    /// The file has not been touched.
+    RewriteRequired,
+}
+
+#[derive(Debug)]
+pub enum InPlaceError {
+    Io(io::Error),
+    PngDecode(PngDecodeError),
+}
+
+impl Display for InPlaceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => writeln!(f, "File error: {err}"),
+            Self::PngDecode(err) => writeln!(f, "PNG decode error: {err}"),
+        }
+    }
+}
+
+impl From<io::Error> for InPlaceError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<PngDecodeError> for InPlaceError {
+    fn from(err: PngDecodeError) -> Self {
+        Self::PngDecode(err)
+    }
+}
+
+impl From<ChunkDecodeError> for InPlaceError {
+    fn from(err: ChunkDecodeError) -> Self {
+        Self::PngDecode(err.into())
+    }
+}
+
+/// The length, type and position of a chunk, read without loading its data.
+#[derive(Debug, Clone, Copy)]
+struct ChunkHeader {
+    offset: u64,
+    length: u32,
+    chunk_type: ChunkType,
+}
+
+impl ChunkHeader {
+    /// Offset of the first byte after this chunk.
+    fn end(&self) -> u64 {
+        self.offset + (MIN_CHUNK_SIZE as u64) + self.length as u64
+    }
+
+    fn is(&self, chunk_type: &str) -> bool {
+        self.chunk_type.to_string() == chunk_type
+    }
+}
+
+/// Walks the chunk headers of a PNG file by seeking over chunk data, stopping after IEND.
+fn scan_headers(file: &mut File) -> Result<Vec<ChunkHeader>, InPlaceError> {
+    let file_len = file.metadata()?.len();
+
+    let mut signature = [0u8; 8];
+    file.seek(SeekFrom::Start(0))?;
+    if file_len < signature.len() as u64 {
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+        return Err(PngDecodeError::TooShort(bytes).into());
+    }
+    file.read_exact(&mut signature)?;
+    if signature != Png::STANDARD_HEADER {
+        return Err(PngDecodeError::InvalidHeader(signature).into());
+    }
+
+    let mut headers = Vec::new();
+    let mut offset = signature.len() as u64;
+    while offset < file_len {
+        let invalid_size = ChunkDecodeError::InvalidChunkSize {
+            offset,
+            size: (file_len - offset) as usize,
+        };
+        if file_len - offset < MIN_CHUNK_SIZE as u64 {
+            return Err(invalid_size.into());
+        }
+
+        let mut bytes = [0u8; LEN_SIZE + CHUNK_TYPE_SIZE];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut bytes)?;
+
+        let header = ChunkHeader {
+            offset,
+            length: u32::from_be_bytes(bytes[..LEN_SIZE].try_into().unwrap()),
+            chunk_type: ChunkType::try_from(<[u8; 4]>::try_from(&bytes[LEN_SIZE..]).unwrap())
+                .map_err(|err| ChunkDecodeError::ChunkTypeDecode { offset, err })?,
+        };
+        if header.end() > file_len {
+            return Err(invalid_size.into());
+        }
+
+        headers.push(header);
+        if header.is("IEND") {
+            break;
+        }
+        offset = header.end();
+    }
+    Ok(headers)
+}
+
+/// Reads everything from `offset` to the end of the file.
+fn read_from(file: &mut File, offset: u64) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Replaces everything from `offset` to the end of the file with `tail`.
+fn rewrite_tail(file: &mut File, offset: u64, tail: &[u8]) -> io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(tail)?;
+    file.set_len(offset + tail.len() as u64)?;
+    file.sync_all()
+}
+
+/// Adds chunks to a PNG file by rewriting only the IEND chunk and whatever follows it.
+/// Only `ChunkPlacement::BeforeIend` can be done in place, and the file must be a regular
+/// file with an IEND chunk. Unlike `save_atomic`, an interruption can leave the file without
+/// an IEND chunk.
+pub fn append_in_place<P: AsRef<Path>>(
+    file_path: P,
+    chunks: &[Chunk],
+    placement: ChunkPlacement,
+) -> Result<InPlaceOutcome, InPlaceError> {
+    if placement != ChunkPlacement::BeforeIend {
+        return Ok(InPlaceOutcome::RewriteRequired);
+    }
+
+    let mut file = File::options().read(true).write(true).open(file_path)?;
+    if !file.metadata()?.is_file() {
+        return Ok(InPlaceOutcome::RewriteRequired);
+    }
+    let iend = match scan_headers(&mut file)?.pop() {
+        Some(header) if header.is("IEND") => header,
+        _ => return Ok(InPlaceOutcome::RewriteRequired),
+    };
+
+    let tail: Vec<u8> = chunks
+        .iter()
+        .flat_map(|c| c.as_bytes())
+        .chain(read_from(&mut file, iend.offset)?)
+        .collect();
+    rewrite_tail(&mut file, iend.offset, &tail)?;
+    Ok(InPlaceOutcome::Updated)
+}
+
+/// Removes the first chunk of the given type from a PNG file by rewriting only the chunks
+/// after it. This is only done in a regular file when the chunk comes after the image data;
+/// otherwise, or if there is no such chunk, `None` is returned and the file is left untouched.
+pub fn remove_in_place<P: AsRef<Path>>(
+    file_path: P,
+    chunk_type: &str,
+) -> Result<Option<Chunk>, InPlaceError> {
+    let mut file = File::options().read(true).write(true).open(file_path)?;
+    if !file.metadata()?.is_file() {
+        return Ok(None);
+    }
+    let headers = scan_headers(&mut file)?;
+
+    let index = match headers.iter().position(|h| h.is(chunk_type)) {
+        Some(index) => index,
+        None => return Ok(None),
+    };
+    let last_idat = headers.iter().rposition(|h| h.is("IDAT"));
+    let in_tail = !headers[index].chunk_type.is_critical() && last_idat < Some(index);
+    if !in_tail || !headers.last().is_some_and(|h| h.is("IEND")) {
+        return Ok(None);
+    }
+
+    let removed = &headers[index];
+    let mut bytes = read_from(&mut file, removed.offset)?;
+    let chunk_len = (removed.end() - removed.offset) as usize;
+    let chunk = ChunkRef::parse_at(&bytes[..chunk_len], removed.offset)?;
+    chunk.verify_crc()?;
+    let chunk = chunk.to_chunk();
+
+    bytes.drain(..chunk_len);
+    rewrite_tail(&mut file, removed.offset, &bytes)?;
+    Ok(Some(chunk))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, str::FromStr};
+
+    use crate::chunk::CRC_SIZE;
+
+    fn chunk(chunk_type: &str, data: &str) -> Chunk {
+        Chunk::new(
+            ChunkType::from_str(chunk_type).unwrap(),
+            data.as_bytes().to_vec(),
+        )
+    }
+
+    fn testing_png() -> Png<'static> {
+        Png::from_chunks(vec![
+            chunk("IHDR", "header"),
+            chunk("IDAT", "pixels"),
+            chunk("teXt", "first"),
+            chunk("IEND", ""),
+        ])
+    }
+
+    fn write_png(png: &Png) -> tempfile::NamedTempFile {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        fs::write(file.path(), png.as_bytes()).unwrap();
+        file
+    }
+
+    #[test]
+    fn test_append_in_place() {
+        let mut png = testing_png();
+        let file = write_png(&png);
+        let added = [chunk("ruSt", "hello"), chunk("ruSt", "world")];
+
+        let outcome = append_in_place(file.path(), &added, ChunkPlacement::BeforeIend).unwrap();
+        assert_eq!(outcome, InPlaceOutcome::Updated);
+
+        for c in added {
+            png.insert_chunk(c, ChunkPlacement::BeforeIend);
+        }
+        assert_eq!(fs::read(file.path()).unwrap(), png.as_bytes());
+    }
+
+    #[test]
+    fn test_append_before_idat_requires_rewrite() {
+        let png = testing_png();
+        let file = write_png(&png);
+
+        let outcome = append_in_place(
+            file.path(),
+            &[chunk("ruSt", "hello")],
+            ChunkPlacement::BeforeIdat,
+        )
+        .unwrap();
+        assert_eq!(outcome, InPlaceOutcome::RewriteRequired);
+        assert_eq!(fs::read(file.path()).unwrap(), png.as_bytes());
+    }
+
+    #[test]
+    fn test_append_without_iend_requires_rewrite() {
+        let mut png = testing_png();
+        png.remove_chunk("IEND").unwrap();
+        let file = write_png(&png);
+
+        let outcome = append_in_place(
+            file.path(),
+            &[chunk("ruSt", "a")],
+            ChunkPlacement::BeforeIend,
+        )
+        .unwrap();
+        assert_eq!(outcome, InPlaceOutcome::RewriteRequired);
+    }
+
+    #[test]
+    fn test_remove_in_place() {
+        let mut png = testing_png();
+        png.insert_chunk(chunk("ruSt", "hello"), ChunkPlacement::BeforeIend);
+        let file = write_png(&png);
+
+        let removed = remove_in_place(file.path(), "teXt").unwrap().unwrap();
+        assert_eq!(removed.data_as_string().unwrap(), "first");
+
+        png.remove_chunk("teXt").unwrap();
+        assert_eq!(fs::read(file.path()).unwrap(), png.as_bytes());
+    }
+
+    #[test]
+    fn test_remove_before_idat_requires_rewrite() {
+        let mut png = testing_png();
+        png.insert_chunk(chunk("ruSt", "hello"), ChunkPlacement::BeforeIdat);
+        let file = write_png(&png);
+
+        assert!(remove_in_place(file.path(), "ruSt").unwrap().is_none());
+        assert!(remove_in_place(file.path(), "miSs").unwrap().is_none());
+        assert_eq!(fs::read(file.path()).unwrap(), png.as_bytes());
+    }
+
+    #[test]
+    fn test_truncated_file() {
+        let png = testing_png();
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let bytes = png.as_bytes();
+        fs::write(file.path(), &bytes[..bytes.len() - CRC_SIZE]).unwrap();
+
+        assert!(append_in_place(file.path(), &[], ChunkPlacement::BeforeIend).is_err());
+    }
+}
@@ -0,0 +1,319 @@
+use std::{
+    fmt::{self, Display},
+    fs::{self, File},
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    ops::Range,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::UNIX_EPOCH,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    chunk::{Chunk, MIN_CHUNK_SIZE},
+    chunk_type::{ChunkType, ChunkTypeDecodeError},
+    limits::DecodeLimits,
+    png::{Png, PngDecodeError},
+    stream::{self, ChunkLocation, LazyChunk, ReadState, StreamError},
+};
+
+/// The version of the sidecar format, bumped when it changes.
+const SIDECAR_VERSION: u32 = 1;
+
+#[derive(Debug)]
+pub enum IndexError {
+    Io(io::Error),
+    Decode(PngDecodeError),
+    Json(serde_json::Error),
+    ChunkType(ChunkTypeDecodeError),
+    UnsupportedVersion(u32),
+    NoSuchChunk(usize),
+}
+
+impl Display for IndexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => writeln!(f, "IO error: {err}"),
+            Self::Decode(err) => write!(f, "{err}"),
+            Self::Json(err) => writeln!(f, "Invalid index sidecar: {err}"),
+            Self::ChunkType(err) => write!(f, "Invalid chunk type in index sidecar: {err}"),
+            Self::UnsupportedVersion(version) => {
+                writeln!(f, "Unsupported index sidecar version {version}")
+            }
+            Self::NoSuchChunk(index) => writeln!(f, "No chunk at index {index}"),
+        }
+    }
+}
+
+impl From<io::Error> for IndexError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<StreamError> for IndexError {
+    fn from(err: StreamError) -> Self {
+        match err {
+            StreamError::Io(err) => Self::Io(err),
+            StreamError::Decode(err) => Self::Decode(err),
+        }
+    }
+}
+
+impl From<serde_json::Error> for IndexError {
+    fn from(err: serde_json::Error) -> Self {
+        Self::Json(err)
+    }
+}
+
+impl From<ChunkTypeDecodeError> for IndexError {
+    fn from(err: ChunkTypeDecodeError) -> Self {
+        Self::ChunkType(err)
+    }
+}
+
+/// The location of every chunk in a PNG file, found by reading only chunk headers and CRCs
+/// and seeking past the data. A chunk can then be read on its own by seeking to it, so
+/// queries on large files do not read the rest. An index can be saved as a sidecar file
+/// next to its PNG and reused until the PNG changes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PngIndex {
+    chunks: Vec<ChunkLocation>,
+    file_size: u64,
+    /// When the file was last modified, in nanoseconds since the Unix epoch, if known.
+    modified: Option<u64>,
+}
+
+impl PngIndex {
+    /// Indexes a PNG stream from its start. CRCs are recorded but not checked, since that
+    /// would mean reading the data.
+    pub fn scan<R: Read + Seek>(reader: &mut R) -> Result<Self, IndexError> {
+        reader.seek(SeekFrom::Start(0))?;
+        let state = ReadState::new(DecodeLimits::UNLIMITED).deferring(|_| true);
+        let chunks = stream::read_deferred(reader, state)?
+            .into_iter()
+            .filter_map(|chunk| match chunk {
+                LazyChunk::Deferred(location) => Some(location),
+                LazyChunk::Loaded(_) => None,
+            })
+            .collect();
+        Ok(Self {
+            chunks,
+            file_size: reader.seek(SeekFrom::End(0))?,
+            modified: None,
+        })
+    }
+
+    /// Indexes a PNG file.
+    pub fn build<P: AsRef<Path>>(file_path: P) -> Result<Self, IndexError> {
+        let mut file = BufReader::new(File::open(&file_path)?);
+        Ok(Self {
+            modified: modified(&file_path)?,
+            ..Self::scan(&mut file)?
+        })
+    }
+
+    /// Returns the index of a PNG file, loading its sidecar if it is up to date and
+    /// indexing the file otherwise. With `save`, a new index is saved as the sidecar.
+    pub fn open<P: AsRef<Path>>(file_path: P, save: bool) -> Result<Self, IndexError> {
+        let sidecar = Self::sidecar_path(&file_path);
+        if let Ok(index) = Self::load(&sidecar) {
+            if index.is_current(&file_path)? {
+                return Ok(index);
+            }
+        }
+        let index = Self::build(&file_path)?;
+        if save {
+            index.save(&sidecar)?;
+        }
+        Ok(index)
+    }
+
+    /// The path of the sidecar of a PNG file: its path with `.idx` appended.
+    pub fn sidecar_path<P: AsRef<Path>>(file_path: P) -> PathBuf {
+        let mut path = file_path.as_ref().as_os_str().to_owned();
+        path.push(".idx");
+        PathBuf::from(path)
+    }
+
+    /// Returns true if a PNG file has the size and modification time it had when indexed.
+    pub fn is_current<P: AsRef<Path>>(&self, file_path: P) -> io::Result<bool> {
+        let size = fs::metadata(&file_path)?.len();
+        Ok(size == self.file_size
+            && self.modified.is_some()
+            && modified(&file_path)? == self.modified)
+    }
+
+    pub fn chunks(&self) -> &[ChunkLocation] {
+        &self.chunks
+    }
+
+    /// Returns the position of the first chunk of a type.
+    pub fn position(&self, chunk_type: &str) -> Option<usize> {
+        self.chunks
+            .iter()
+            .position(|chunk| chunk.chunk_type.to_string() == chunk_type)
+    }
+
+    /// The range of bytes after the last chunk, which is empty unless the file has data
+    /// after IEND.
+    pub fn trailing_range(&self) -> Range<u64> {
+        let end = self
+            .chunks
+            .last()
+            .map_or(Png::STANDARD_HEADER.len() as u64, |chunk| {
+                chunk.offset + (MIN_CHUNK_SIZE as u64) + chunk.length as u64
+            });
+        end..self.file_size
+    }
+
+    /// Reads the chunk at `index` from the stream that was indexed, checking its CRC.
+    pub fn read_chunk<R: Read + Seek>(
+        &self,
+        reader: &mut R,
+        index: usize,
+    ) -> Result<Chunk, IndexError> {
+        let chunk = self
+            .chunks
+            .get(index)
+            .ok_or(IndexError::NoSuchChunk(index))?;
+        Ok(chunk.read_from(reader, 0)?)
+    }
+
+    /// Saves the index as JSON.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), IndexError> {
+        let sidecar = Sidecar {
+            version: SIDECAR_VERSION,
+            file_size: self.file_size,
+            modified: self.modified,
+            chunks: self
+                .chunks
+                .iter()
+                .map(|chunk| SidecarChunk {
+                    chunk_type: chunk.chunk_type.to_string(),
+                    offset: chunk.offset,
+                    length: chunk.length,
+                    crc: chunk.crc,
+                })
+                .collect(),
+        };
+        fs::write(path, serde_json::to_vec(&sidecar)?)?;
+        Ok(())
+    }
+
+    /// Loads an index saved with `save`.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, IndexError> {
+        let sidecar: Sidecar = serde_json::from_slice(&fs::read(path)?)?;
+        if sidecar.version != SIDECAR_VERSION {
+            return Err(IndexError::UnsupportedVersion(sidecar.version));
+        }
+        let chunks = sidecar
+            .chunks
+            .into_iter()
+            .map(|chunk| {
+                Ok(ChunkLocation {
+                    chunk_type: ChunkType::from_str(&chunk.chunk_type)?,
+                    offset: chunk.offset,
+                    length: chunk.length,
+                    crc: chunk.crc,
+                })
+            })
+            .collect::<Result<_, IndexError>>()?;
+        Ok(Self {
+            chunks,
+            file_size: sidecar.file_size,
+            modified: sidecar.modified,
+        })
+    }
+}
+
+/// Returns when a file was last modified, in nanoseconds since the Unix epoch, or `None` if
+/// the platform does not record it.
+fn modified<P: AsRef<Path>>(file_path: P) -> io::Result<Option<u64>> {
+    let modified = fs::metadata(file_path)?.modified().ok();
+    Ok(modified
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|duration| duration.as_nanos() as u64))
+}
+
+/// The layout of an index sidecar file.
+#[derive(Debug, Serialize, Deserialize)]
+struct Sidecar {
+    version: u32,
+    file_size: u64,
+    modified: Option<u64>,
+    chunks: Vec<SidecarChunk>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SidecarChunk {
+    #[serde(rename = "type")]
+    chunk_type: String,
+    offset: u64,
+    length: u32,
+    crc: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Write};
+
+    const DICE: &[u8] = include_bytes!("../tests/data/dice.png");
+
+    fn temp_png(bytes: &[u8]) -> tempfile::NamedTempFile {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(bytes).unwrap();
+        file
+    }
+
+    #[test]
+    fn test_scan() {
+        let mut bytes = DICE.to_vec();
+        bytes.extend(b"junk");
+        let mut cursor = Cursor::new(bytes);
+        let index = PngIndex::scan(&mut cursor).unwrap();
+
+        let offsets: Vec<_> = index.chunks().iter().map(|chunk| chunk.offset).collect();
+        assert_eq!(offsets, [8, 33, 46, 62, 83, 4776, 4791]);
+        assert_eq!(index.chunks()[4].length, 4681);
+        assert_eq!(index.trailing_range(), 4803..4807);
+
+        let rust = index.position("RuSt").unwrap();
+        let chunk = index.read_chunk(&mut cursor, rust).unwrap();
+        let png = Png::try_from(DICE).unwrap();
+        assert_eq!(chunk.data(), png.chunk_by_type("RuSt").unwrap().data());
+
+        cursor.get_mut()[4785] ^= 1;
+        assert!(matches!(
+            index.read_chunk(&mut cursor, rust),
+            Err(IndexError::Decode(_))
+        ));
+        assert!(matches!(
+            index.read_chunk(&mut cursor, 7),
+            Err(IndexError::NoSuchChunk(7))
+        ));
+    }
+
+    #[test]
+    fn test_sidecar() {
+        let file = temp_png(DICE);
+        let sidecar = PngIndex::sidecar_path(file.path());
+        assert!(sidecar.to_string_lossy().ends_with(".idx"));
+
+        let index = PngIndex::open(file.path(), true).unwrap();
+        assert_eq!(PngIndex::load(&sidecar).unwrap(), index);
+        assert!(index.is_current(file.path()).unwrap());
+
+        // A changed file is indexed again
+        let mut bytes = DICE.to_vec();
+        bytes.extend(b"junk");
+        fs::write(file.path(), &bytes).unwrap();
+        assert!(!index.is_current(file.path()).unwrap());
+        let reindexed = PngIndex::open(file.path(), true).unwrap();
+        assert_eq!(reindexed.trailing_range(), 4803..4807);
+        assert_eq!(PngIndex::load(&sidecar).unwrap(), reindexed);
+        fs::remove_file(sidecar).unwrap();
+    }
+}
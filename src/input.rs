@@ -0,0 +1,215 @@
+use std::{
+    fs::File,
+    io::{self, Read},
+    ops::Deref,
+    path::Path,
+};
+
+use memmap2::Mmap;
+
+use crate::limits::DecodeLimits;
+
+/// Regular files at least this large are memory-mapped by default.
+pub const MMAP_THRESHOLD: u64 = 1 << 20;
+
+/// The file path standing for stdin as an input and stdout as an output.
+pub const STDIO_PATH: &str = "-";
+
+/// Returns true if the path stands for stdin or stdout.
+pub fn is_stdio<P: AsRef<Path>>(file_path: P) -> bool {
+    file_path.as_ref() == Path::new(STDIO_PATH)
+}
+
+/// Opens stdin, or a special file such as a pipe, as a stream. Returns `None` for regular
+/// files, which can be memory-mapped or read whole.
+pub fn open_stream<P: AsRef<Path>>(file_path: P) -> io::Result<Option<Box<dyn Read>>> {
+    if is_stdio(&file_path) {
+        return Ok(Some(Box::new(io::stdin().lock())));
+    }
+    let file = File::open(file_path)?;
+    match file.metadata()?.is_file() {
+        true => Ok(None),
+        false => Ok(Some(Box::new(file))),
+    }
+}
+
+/// How the bytes of an input file should be loaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum InputMode {
+    /// Memory-map large regular files and read everything else into memory.
+    #[default]
+    Auto,
+    /// Memory-map the file, falling back to buffered reads if it is not a regular file.
+    Mmap,
+    /// Always read the whole file into memory.
+    Buffered,
+}
+
+/// The raw bytes of a PNG file, either memory-mapped or read into memory. Parse them
+/// with `Png::try_from(source.bytes())` to get chunks that borrow from the source.
+#[derive(Debug)]
+pub enum PngSource {
+    Mapped(Mmap),
+    Buffered(Vec<u8>),
+}
+
+impl PngSource {
+    /// Opens a file, memory-mapping it if it is a large regular file.
+    pub fn open<P: AsRef<Path>>(file_path: P) -> io::Result<Self> {
+        Self::open_with(file_path, InputMode::Auto)
+    }
+
+    /// Opens a file using the given input mode, or reads stdin if the path is `-`. Stdin,
+    /// pipes and other special files are always read into memory since they cannot be mapped,
+    /// up to the total size allowed by the default `DecodeLimits`.
+    pub fn open_with<P: AsRef<Path>>(file_path: P, mode: InputMode) -> io::Result<Self> {
+        if is_stdio(&file_path) {
+            return Self::from_reader_with_limits(io::stdin().lock(), &DecodeLimits::default());
+        }
+
+        let file = File::open(file_path)?;
+        let metadata = file.metadata()?;
+
+        let map = match mode {
+            InputMode::Auto => metadata.is_file() && metadata.len() >= MMAP_THRESHOLD,
+            InputMode::Mmap => metadata.is_file() && metadata.len() > 0,
+            InputMode::Buffered => false,
+        };
+
+        if map {
+            // SAFETY: the map is only ever read. The caller must make sure the file is not
+            // truncated or rewritten while the map is alive, which is why commands that save
+            // back to their input file use `InputMode::Buffered`.
+            let mmap = unsafe { Mmap::map(&file)? };
+            Ok(Self::Mapped(mmap))
+        } else if metadata.is_file() {
+            Self::from_reader(file)
+        } else {
+            Self::from_reader_with_limits(file, &DecodeLimits::default())
+        }
+    }
+
+    /// Reads everything from a reader into memory.
+    pub fn from_reader<R: Read>(mut reader: R) -> io::Result<Self> {
+        let mut buf: Vec<u8> = Vec::new();
+        reader.read_to_end(&mut buf)?;
+        Ok(Self::Buffered(buf))
+    }
+
+    /// Reads everything from a reader into memory, failing with `InvalidData` as soon as it
+    /// exceeds the total size allowed by `limits`.
+    pub fn from_reader_with_limits<R: Read>(reader: R, limits: &DecodeLimits) -> io::Result<Self> {
+        // One byte past the limit is enough to tell that it was exceeded
+        let mut buf: Vec<u8> = Vec::new();
+        let limit = limits.max_total_size.saturating_add(1);
+        reader.take(limit).read_to_end(&mut buf)?;
+        limits
+            .check_total_size(buf.len() as u64)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+        Ok(Self::Buffered(buf))
+    }
+
+    /// Returns true if the source is memory-mapped.
+    pub fn is_mapped(&self) -> bool {
+        matches!(self, Self::Mapped(_))
+    }
+
+    /// Returns the bytes of the source.
+    pub fn bytes(&self) -> &[u8] {
+        match self {
+            Self::Mapped(mmap) => mmap,
+            Self::Buffered(buf) => buf,
+        }
+    }
+}
+
+impl Deref for PngSource {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        self.bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    use crate::png::Png;
+
+    fn temp_file(len: usize) -> tempfile::NamedTempFile {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        let bytes: Vec<u8> = Png::STANDARD_HEADER
+            .into_iter()
+            .chain((0..len).map(|i| i as u8))
+            .collect();
+        file.write_all(&bytes).unwrap();
+        file
+    }
+
+    #[test]
+    fn test_small_file_is_buffered() {
+        let file = temp_file(16);
+        let source = PngSource::open(file.path()).unwrap();
+        assert!(!source.is_mapped());
+        assert_eq!(source.len(), 24);
+    }
+
+    #[test]
+    fn test_large_file_is_mapped() {
+        let file = temp_file(MMAP_THRESHOLD as usize);
+        let source = PngSource::open(file.path()).unwrap();
+        assert!(source.is_mapped());
+        assert_eq!(&source[..8], &Png::STANDARD_HEADER);
+    }
+
+    #[test]
+    fn test_forced_modes() {
+        let file = temp_file(16);
+        assert!(PngSource::open_with(file.path(), InputMode::Mmap)
+            .unwrap()
+            .is_mapped());
+
+        let file = temp_file(MMAP_THRESHOLD as usize);
+        assert!(!PngSource::open_with(file.path(), InputMode::Buffered)
+            .unwrap()
+            .is_mapped());
+    }
+
+    #[test]
+    fn test_reader_with_limits() {
+        let limits = DecodeLimits {
+            max_total_size: 24,
+            ..DecodeLimits::default()
+        };
+        let bytes = temp_file(16).reopen().unwrap();
+        assert_eq!(
+            PngSource::from_reader_with_limits(bytes, &limits)
+                .unwrap()
+                .len(),
+            24
+        );
+
+        let bytes = temp_file(17).reopen().unwrap();
+        let err = PngSource::from_reader_with_limits(bytes, &limits).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_open_stream() {
+        let file = temp_file(16);
+        assert!(open_stream(file.path()).unwrap().is_none());
+        assert!(open_stream(STDIO_PATH).unwrap().is_some());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_special_file_is_buffered() {
+        assert!(open_stream("/dev/null").unwrap().is_some());
+
+        let source = PngSource::open_with("/dev/null", InputMode::Mmap).unwrap();
+        assert!(!source.is_mapped());
+        assert!(source.is_empty());
+    }
+}
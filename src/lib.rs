@@ -1,5 +1,49 @@
-pub mod commands;
+//! Reads and edits the chunks of PNG files. Chunk types, chunks, CRCs, decode limits and
+//! the parser form a `no_std` core that only needs `alloc`; everything that does IO, as
+//! well as the commands of the `pngme` tool, needs the default `std` feature.
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
 
-mod chunk;
-mod chunk_type;
-mod png;
+#[cfg(feature = "async")]
+pub mod async_io;
+#[cfg(feature = "std")]
+pub mod batch;
+#[cfg(feature = "std")]
+pub mod bitfix;
+pub mod chunk;
+pub mod chunk_type;
+#[cfg(feature = "std")]
+pub mod commands;
+#[cfg(feature = "std")]
+pub mod copy;
+pub mod decoder;
+#[cfg(feature = "std")]
+pub mod diff;
+#[cfg(feature = "std")]
+pub mod exif;
+pub mod image;
+#[cfg(feature = "std")]
+pub mod in_place;
+#[cfg(feature = "std")]
+pub mod index;
+#[cfg(feature = "std")]
+pub mod input;
+pub mod limits;
+#[cfg(feature = "std")]
+pub mod manifest;
+#[cfg(feature = "std")]
+pub mod output;
+pub mod png;
+#[cfg(feature = "std")]
+pub mod repair;
+#[cfg(feature = "std")]
+pub mod report;
+#[cfg(feature = "std")]
+pub mod selector;
+#[cfg(feature = "std")]
+pub mod stream;
+#[cfg(feature = "std")]
+pub mod strip;
+#[cfg(feature = "std")]
+pub mod verify;
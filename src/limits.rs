@@ -0,0 +1,412 @@
+use core::fmt::{self, Display};
+#[cfg(feature = "std")]
+use std::io;
+
+#[cfg(feature = "std")]
+use crate::{chunk::ChunkRef, image};
+use crate::{
+    chunk::MAX_LEN,
+    chunk_type::ChunkType,
+    image::{ImageHeader, MAX_DIMENSION},
+    png::Png,
+};
+
+/// Caps on the resources parsing a PNG may use, for files from untrusted sources. Every
+/// parse checks the length, count and total size of chunks; the dimension and decompressed
+/// size limits apply when the image header is checked and when data is inflated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeLimits {
+    /// The longest chunk data allowed, in bytes.
+    pub max_chunk_length: u32,
+    /// The most chunks allowed, including IHDR and IEND.
+    pub max_chunk_count: usize,
+    /// The largest file allowed, in bytes.
+    pub max_total_size: u64,
+    /// The most bytes the image data may inflate to, filter type bytes included.
+    pub max_image_data_size: u64,
+    /// The most bytes the text of a zTXt or compressed iTXt chunk may inflate to.
+    pub max_text_size: u64,
+    /// The most bytes the profile of an iCCP chunk may inflate to.
+    pub max_icc_profile_size: u64,
+    pub max_width: u32,
+    pub max_height: u32,
+}
+
+impl DecodeLimits {
+    /// No limits beyond those of the PNG spec.
+    pub const UNLIMITED: Self = Self {
+        max_chunk_length: MAX_LEN as u32,
+        max_chunk_count: usize::MAX,
+        max_total_size: u64::MAX,
+        max_image_data_size: u64::MAX,
+        max_text_size: u64::MAX,
+        max_icc_profile_size: u64::MAX,
+        max_width: MAX_DIMENSION,
+        max_height: MAX_DIMENSION,
+    };
+
+    /// Checks the length of the chunk data and the number of chunks read so far, including
+    /// this one.
+    pub fn check_chunk(&self, length: u32, count: usize) -> Result<(), LimitError> {
+        if length > self.max_chunk_length {
+            return Err(LimitError::ChunkLength {
+                length,
+                limit: self.max_chunk_length,
+            });
+        }
+        if count > self.max_chunk_count {
+            return Err(LimitError::ChunkCount {
+                limit: self.max_chunk_count,
+            });
+        }
+        Ok(())
+    }
+
+    /// Checks the size of a whole file.
+    pub fn check_total_size(&self, size: u64) -> Result<(), LimitError> {
+        match size > self.max_total_size {
+            true => Err(LimitError::TotalSize {
+                size,
+                limit: self.max_total_size,
+            }),
+            false => Ok(()),
+        }
+    }
+
+    /// Checks the dimensions of an image and the size its data inflates to. Dimensions above
+    /// the `MAX_DIMENSION` of the PNG spec are rejected whatever the limits, before any size
+    /// is computed from them.
+    pub fn check_header(&self, header: &ImageHeader) -> Result<(), LimitError> {
+        let max_width = self.max_width.min(MAX_DIMENSION);
+        let max_height = self.max_height.min(MAX_DIMENSION);
+        if header.width > max_width || header.height > max_height {
+            return Err(LimitError::ImageDimensions {
+                width: header.width,
+                height: header.height,
+                max_width,
+                max_height,
+            });
+        }
+        if header.expected_data_len() > self.max_image_data_size {
+            return Err(LimitError::DecompressedSize {
+                chunk_type: ChunkType::try_from(*b"IDAT").unwrap(),
+                limit: self.max_image_data_size,
+            });
+        }
+        Ok(())
+    }
+
+    /// Checks the image described by the IHDR chunk of a PNG. A PNG without a valid IHDR
+    /// chunk passes: it describes no image to limit, and decoding its pixels fails anyway
+    /// with an `ImageError`.
+    pub fn check_png(&self, png: &Png) -> Result<(), LimitError> {
+        match ImageHeader::from_png(png) {
+            Ok(header) => self.check_header(&header),
+            Err(_) => Ok(()),
+        }
+    }
+
+    /// The most bytes the compressed data of a chunk of this type may inflate to, or `None`
+    /// if chunks of this type hold no compressed data.
+    pub fn decompressed_limit(&self, chunk_type: &ChunkType) -> Option<u64> {
+        match &chunk_type.bytes() {
+            b"IDAT" => Some(self.max_image_data_size),
+            b"zTXt" | b"iTXt" => Some(self.max_text_size),
+            b"iCCP" => Some(self.max_icc_profile_size),
+            _ => None,
+        }
+    }
+
+    /// Inflates the text of a zTXt or compressed iTXt chunk, or the profile of an iCCP
+    /// chunk, failing as soon as it grows past the limit for its type. Returns `None` for
+    /// chunks without compressed data.
+    #[cfg(feature = "std")]
+    pub fn inflate_chunk(&self, chunk: &ChunkRef) -> Result<Option<Vec<u8>>, InflateError> {
+        let (Some(data), Some(limit)) = (
+            compressed_data(chunk),
+            self.decompressed_limit(chunk.chunk_type()),
+        ) else {
+            return Ok(None);
+        };
+        // One byte past the limit tells a stream at the limit from a longer one
+        let inflated = image::inflate(data, limit.saturating_add(1))?;
+        if inflated.len() as u64 > limit {
+            return Err(InflateError::LimitExceeded(LimitError::DecompressedSize {
+                chunk_type: *chunk.chunk_type(),
+                limit,
+            }));
+        }
+        Ok(Some(inflated))
+    }
+}
+
+impl Default for DecodeLimits {
+    /// Limits suited to images uploaded by users: 256 MiB files of up to 10000 chunks of
+    /// 64 MiB, images up to 32768 pixels wide and high inflating to 512 MiB, 1 MiB of text
+    /// per chunk and 16 MiB ICC profiles.
+    fn default() -> Self {
+        Self {
+            max_chunk_length: 64 << 20,
+            max_chunk_count: 10_000,
+            max_total_size: 256 << 20,
+            max_image_data_size: 512 << 20,
+            max_text_size: 1 << 20,
+            max_icc_profile_size: 16 << 20,
+            max_width: 32_768,
+            max_height: 32_768,
+        }
+    }
+}
+
+/// A `DecodeLimits` limit a PNG exceeded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LimitError {
+    ChunkLength {
+        length: u32,
+        limit: u32,
+    },
+    ChunkCount {
+        limit: usize,
+    },
+    TotalSize {
+        size: u64,
+        limit: u64,
+    },
+    ImageDimensions {
+        width: u32,
+        height: u32,
+        max_width: u32,
+        max_height: u32,
+    },
+    /// The compressed data of a chunk, or of every IDAT chunk together, inflates past the
+    /// limit for its type.
+    DecompressedSize {
+        chunk_type: ChunkType,
+        limit: u64,
+    },
+}
+
+impl Display for LimitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ChunkLength { length, limit } => {
+                writeln!(f, "Chunk length {length} exceeds the limit of {limit}")
+            }
+            Self::ChunkCount { limit } => writeln!(f, "More than {limit} chunks"),
+            Self::TotalSize { size, limit } => {
+                writeln!(f, "File size {size} exceeds the limit of {limit}")
+            }
+            Self::ImageDimensions {
+                width,
+                height,
+                max_width,
+                max_height,
+            } => writeln!(
+                f,
+                "Image size {width}x{height} exceeds the limit of {max_width}x{max_height}"
+            ),
+            Self::DecompressedSize { chunk_type, limit } => {
+                writeln!(
+                    f,
+                    "{chunk_type} data inflates past the limit of {limit} bytes"
+                )
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub enum InflateError {
+    Inflate(io::Error),
+    LimitExceeded(LimitError),
+}
+
+#[cfg(feature = "std")]
+impl Display for InflateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Inflate(err) => writeln!(f, "Chunk data does not inflate: {err}"),
+            Self::LimitExceeded(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<io::Error> for InflateError {
+    fn from(err: io::Error) -> Self {
+        Self::Inflate(err)
+    }
+}
+
+/// Returns the zlib stream held by a zTXt, compressed iTXt or iCCP chunk, or `None` if the
+/// chunk holds none.
+#[cfg(feature = "std")]
+fn compressed_data<'a>(chunk: &ChunkRef<'a>) -> Option<&'a [u8]> {
+    let data = chunk.data();
+    // Every layout starts with a keyword or profile name ended by a null byte
+    let rest = &data[data.iter().position(|&b| b == 0)? + 1..];
+    match &chunk.chunk_type().bytes() {
+        // A compression method byte follows
+        b"zTXt" | b"iCCP" => rest.get(1..),
+        // Then a compression flag, a method, a language tag and a translated keyword
+        b"iTXt" if rest.first() == Some(&1) => {
+            let rest = rest.get(2..)?;
+            let language_end = rest.iter().position(|&b| b == 0)?;
+            let rest = &rest[language_end + 1..];
+            let translated_end = rest.iter().position(|&b| b == 0)?;
+            Some(&rest[translated_end + 1..])
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "std")]
+    use core::str::FromStr;
+
+    #[cfg(feature = "std")]
+    use crate::chunk::Chunk;
+
+    #[cfg(feature = "std")]
+    fn chunk(chunk_type: &str, data: &[u8]) -> Chunk {
+        Chunk::new(ChunkType::from_str(chunk_type).unwrap(), data.to_vec())
+    }
+
+    #[test]
+    fn test_check_chunk() {
+        let limits = DecodeLimits {
+            max_chunk_length: 100,
+            max_chunk_count: 3,
+            ..DecodeLimits::default()
+        };
+        assert!(limits.check_chunk(100, 3).is_ok());
+        assert_eq!(
+            limits.check_chunk(101, 1),
+            Err(LimitError::ChunkLength {
+                length: 101,
+                limit: 100
+            })
+        );
+        assert_eq!(
+            limits.check_chunk(0, 4),
+            Err(LimitError::ChunkCount { limit: 3 })
+        );
+        assert!(DecodeLimits::UNLIMITED
+            .check_chunk(MAX_LEN as u32, usize::MAX)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_check_header() {
+        let limits = DecodeLimits {
+            max_width: 50,
+            max_height: 50,
+            max_image_data_size: 10_050,
+            ..DecodeLimits::default()
+        };
+        let mut header =
+            ImageHeader::try_from(&[0, 0, 0, 50, 0, 0, 0, 50, 8, 6, 0, 0, 0][..]).unwrap();
+        assert!(limits.check_header(&header).is_ok());
+
+        header.width = 51;
+        assert!(matches!(
+            limits.check_header(&header),
+            Err(LimitError::ImageDimensions { width: 51, .. })
+        ));
+
+        header.width = 50;
+        header.color_type = 6;
+        header.bit_depth = 16;
+        assert!(matches!(
+            limits.check_header(&header),
+            Err(LimitError::DecompressedSize { limit: 10_050, .. })
+        ));
+
+        // Beyond the spec maximum even without limits
+        let limits = DecodeLimits {
+            max_width: u32::MAX,
+            ..DecodeLimits::UNLIMITED
+        };
+        header.width = u32::MAX;
+        header.interlace_method = 1;
+        assert_eq!(
+            limits.check_header(&header),
+            Err(LimitError::ImageDimensions {
+                width: u32::MAX,
+                height: 50,
+                max_width: MAX_DIMENSION,
+                max_height: MAX_DIMENSION,
+            })
+        );
+        header.width = MAX_DIMENSION;
+        assert!(DecodeLimits::UNLIMITED.check_header(&header).is_ok());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_check_png() {
+        let limits = DecodeLimits {
+            max_width: 50,
+            ..DecodeLimits::default()
+        };
+        let iend = chunk("IEND", &[]);
+        let ihdr = chunk("IHDR", &[0, 0, 0, 50, 0, 0, 0, 50, 8, 6, 0, 0, 0]);
+        let png = Png::from_chunks(vec![ihdr, iend.clone()]);
+        assert!(limits.check_png(&png).is_ok());
+
+        let wide = chunk("IHDR", &[0, 0, 0, 51, 0, 0, 0, 50, 8, 6, 0, 0, 0]);
+        let png = Png::from_chunks(vec![wide, iend.clone()]);
+        assert!(matches!(
+            limits.check_png(&png),
+            Err(LimitError::ImageDimensions { width: 51, .. })
+        ));
+
+        // Missing or malformed headers are left for decoding to report
+        let png = Png::from_chunks(vec![iend.clone()]);
+        assert!(limits.check_png(&png).is_ok());
+        let short = chunk("IHDR", &[0, 0, 0, 51, 0, 0, 0, 50]);
+        let png = Png::from_chunks(vec![short, iend]);
+        assert!(limits.check_png(&png).is_ok());
+        assert!(image::decode_pixels_with_limits(&png, &limits).is_err());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_inflate_chunk() {
+        let limits = DecodeLimits {
+            max_text_size: 100,
+            ..DecodeLimits::default()
+        };
+
+        let mut ztxt = b"Comment\0\0".to_vec();
+        ztxt.extend(image::deflate(&[b'a'; 100], 9));
+        let ztxt = chunk("zTXt", &ztxt);
+        let text = limits.inflate_chunk(&ztxt.as_chunk_ref()).unwrap();
+        assert_eq!(text.unwrap(), [b'a'; 100]);
+
+        let mut itxt = b"Comment\0\x01\0en\0\0".to_vec();
+        itxt.extend(image::deflate(&[b'a'; 101], 9));
+        let itxt = chunk("iTXt", &itxt);
+        assert!(matches!(
+            limits.inflate_chunk(&itxt.as_chunk_ref()),
+            Err(InflateError::LimitExceeded(LimitError::DecompressedSize {
+                limit: 100,
+                ..
+            }))
+        ));
+
+        let uncompressed = chunk("iTXt", b"Comment\0\0\0en\0\0text");
+        assert!(limits
+            .inflate_chunk(&uncompressed.as_chunk_ref())
+            .unwrap()
+            .is_none());
+        let text = chunk("tEXt", b"Comment\0text");
+        assert!(limits
+            .inflate_chunk(&text.as_chunk_ref())
+            .unwrap()
+            .is_none());
+    }
+}
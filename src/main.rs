@@ -1,5 +1,16 @@
-use clap::{Args, Parser, Subcommand};
-use pngme::commands;
+use std::path::Path;
+
+use clap::{error::ErrorKind, ArgGroup, Args, CommandFactory, Parser, Subcommand};
+use pngme::{
+    batch::{self, BatchOptions},
+    bitfix::BitFixOptions,
+    commands,
+    manifest::ManifestFormat,
+    output::SaveOptions,
+    repair::{RepairOptions, TrailingData},
+    report::OutputFormat,
+    strip::StripPreset,
+};
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
@@ -9,34 +20,274 @@ struct Cli {
     command: Commands,
 }
 
+#[derive(Args, Debug)]
+struct SaveArgs {
+    /// Keep a copy of the replaced file with a `.bak` extension
+    #[arg(long)]
+    backup: bool,
+    /// Keep the modification time of the replaced file
+    #[arg(long)]
+    preserve_mtime: bool,
+}
+
+impl From<&SaveArgs> for SaveOptions {
+    fn from(args: &SaveArgs) -> Self {
+        Self {
+            backup: args.backup,
+            preserve_mtime: args.preserve_mtime,
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+struct BatchArgs {
+    /// Process at most this many files at once, defaulting to the number of CPUs
+    #[arg(short = 'j', long, value_name = "N")]
+    jobs: Option<usize>,
+}
+
+impl BatchArgs {
+    fn options(&self, output_dir: Option<&str>) -> BatchOptions {
+        BatchOptions {
+            jobs: self.jobs.unwrap_or_else(batch::default_jobs),
+            output_dir: output_dir.map(Into::into),
+        }
+    }
+}
+
 #[derive(Args, Debug)]
 struct EncodeArgs {
-    file_path: String,
+    /// PNG files, directories searched recursively for PNG files, or glob patterns
+    #[arg(required = true)]
+    file_paths: Vec<String>,
     #[arg(short = 'c', long)]
     chunk_type: String,
     #[arg(short = 'm', long)]
     message: String,
     #[arg(short = 'o', long)]
     output_file: Option<String>,
+    /// Save results under this directory, mirroring the input layout, instead of over the inputs
+    #[arg(long, conflicts_with = "output_file")]
+    output_dir: Option<String>,
+    #[command(flatten)]
+    save: SaveArgs,
+    #[command(flatten)]
+    batch: BatchArgs,
+    /// Write the new chunk over the end of the file instead of rewriting all of it
+    #[arg(long, conflicts_with_all = ["output_file", "output_dir", "backup", "preserve_mtime"])]
+    in_place: bool,
+    /// Hide the new chunk after the IEND chunk, where PNG decoders ignore it
+    #[arg(long, conflicts_with = "in_place")]
+    after_iend: bool,
 }
 
 #[derive(Args, Debug)]
 struct DecodeArgs {
-    file_path: String,
-    #[arg(short = 'c', long)]
+    /// PNG files, directories searched recursively for PNG files, or glob patterns
+    #[arg(required = true)]
+    file_paths: Vec<String>,
+    /// Chunk type or selector expression; the first matching chunk is decoded
+    #[arg(short = 'c', long, visible_alias = "select")]
     chunk_type: String,
+    #[arg(short = 'f', long, value_enum, default_value_t)]
+    format: OutputFormat,
+    /// Search the chunks hidden after the IEND chunk instead of the PNG itself
+    #[arg(long)]
+    after_iend: bool,
+    #[command(flatten)]
+    batch: BatchArgs,
 }
 
 #[derive(Args, Debug)]
 struct RemoveArgs {
-    file_path: String,
-    #[arg(short = 'c', long)]
+    /// PNG files, directories searched recursively for PNG files, or glob patterns
+    #[arg(required = true)]
+    file_paths: Vec<String>,
+    /// Chunk type or selector expression; the first matching chunk is removed
+    #[arg(short = 'c', long, visible_alias = "select")]
     chunk_type: String,
+    /// Remove every matching chunk instead of only the first
+    #[arg(short = 'a', long)]
+    all: bool,
+    /// Where to save the result, overwriting the input file if not given
+    #[arg(short = 'o', long)]
+    output_file: Option<String>,
+    /// Save results under this directory, mirroring the input layout, instead of over the inputs
+    #[arg(long, conflicts_with = "output_file")]
+    output_dir: Option<String>,
+    #[command(flatten)]
+    save: SaveArgs,
+    #[command(flatten)]
+    batch: BatchArgs,
+    /// Rewrite only the end of the file when the chunk comes after the image data
+    #[arg(long, conflicts_with_all = ["output_file", "output_dir", "backup", "preserve_mtime"])]
+    in_place: bool,
 }
 
 #[derive(Args, Debug)]
 struct PrintArgs {
-    file_path: String,
+    /// PNG files, directories searched recursively for PNG files, or glob patterns
+    #[arg(required = true)]
+    file_paths: Vec<String>,
+    /// Only print chunks matching this selector expression
+    #[arg(short = 's', long)]
+    select: Option<String>,
+    #[arg(short = 'f', long, value_enum, default_value_t)]
+    format: OutputFormat,
+    /// Include up to this many bytes of each chunk's data in JSON output
+    #[arg(long, value_name = "BYTES")]
+    preview: Option<usize>,
+    #[command(flatten)]
+    batch: BatchArgs,
+}
+
+#[derive(Args, Debug)]
+struct ExportArgs {
+    /// PNG files, directories searched recursively for PNG files, or glob patterns
+    #[arg(required = true)]
+    file_paths: Vec<String>,
+    /// Where to save the manifest, printed to stdout if not given
+    #[arg(short = 'o', long)]
+    output_file: Option<String>,
+    /// Save manifests under this directory, mirroring the input layout, instead of next to
+    /// the inputs
+    #[arg(long, conflicts_with = "output_file")]
+    output_dir: Option<String>,
+    /// Write chunk data to files in this directory, relative to the manifest, instead of inlining it
+    #[arg(long)]
+    data_dir: Option<String>,
+    /// Manifest format, guessed from the output file extension if not given
+    #[arg(short = 'f', long, value_enum)]
+    format: Option<ManifestFormat>,
+    #[command(flatten)]
+    batch: BatchArgs,
+}
+
+#[derive(Args, Debug)]
+struct ImportArgs {
+    manifest_path: String,
+    #[arg(short = 'o', long)]
+    output_file: String,
+    /// Manifest format, guessed from the manifest file extension if not given
+    #[arg(short = 'f', long, value_enum)]
+    format: Option<ManifestFormat>,
+    #[command(flatten)]
+    save: SaveArgs,
+}
+
+#[derive(Args, Debug)]
+struct DiffArgs {
+    old_file: String,
+    new_file: String,
+    /// Compare image data by its decoded pixels, ignoring how it is compressed
+    #[arg(long)]
+    pixels: bool,
+    #[arg(short = 'f', long, value_enum, default_value_t)]
+    format: OutputFormat,
+}
+
+#[derive(Args, Debug)]
+#[command(group(ArgGroup::new("selection").required(true).multiple(true).args(["preset", "select", "keep", "gps", "trailing"])))]
+struct StripArgs {
+    /// PNG files, directories searched recursively for PNG files, or glob patterns
+    #[arg(required = true)]
+    file_paths: Vec<String>,
+    /// Remove a predefined set of ancillary chunks
+    #[arg(short = 'p', long, value_enum, conflicts_with_all = ["keep", "select"])]
+    preset: Option<StripPreset>,
+    /// Remove the ancillary chunks matching this selector expression
+    #[arg(short = 's', long, conflicts_with = "keep")]
+    select: Option<String>,
+    /// Remove every ancillary chunk except these types
+    #[arg(short = 'k', long, value_name = "TYPE", value_delimiter = ',')]
+    keep: Vec<String>,
+    /// Remove GPS fields from eXIf chunks
+    #[arg(long)]
+    gps: bool,
+    /// Remove any bytes after the IEND chunk
+    #[arg(long)]
+    trailing: bool,
+    /// Where to save the stripped PNG, overwriting the input file if not given
+    #[arg(short = 'o', long)]
+    output_file: Option<String>,
+    /// Save results under this directory, mirroring the input layout, instead of over the inputs
+    #[arg(long, conflicts_with = "output_file")]
+    output_dir: Option<String>,
+    #[arg(short = 'f', long, value_enum, default_value_t)]
+    format: OutputFormat,
+    #[command(flatten)]
+    save: SaveArgs,
+    #[command(flatten)]
+    batch: BatchArgs,
+}
+
+#[derive(Args, Debug)]
+struct CopyChunksArgs {
+    src_file: String,
+    /// PNG files, directories searched recursively for PNG files, or glob patterns to copy into
+    #[arg(required = true)]
+    dst_files: Vec<String>,
+    /// Chunk types or selector expression of the ancillary chunks to copy
+    #[arg(short = 's', long)]
+    select: String,
+    /// Where to save the result, overwriting the destination file if not given
+    #[arg(short = 'o', long)]
+    output_file: Option<String>,
+    /// Save results under this directory, mirroring the input layout, instead of over the
+    /// destination files
+    #[arg(long, conflicts_with = "output_file")]
+    output_dir: Option<String>,
+    #[arg(short = 'f', long, value_enum, default_value_t)]
+    format: OutputFormat,
+    #[command(flatten)]
+    save: SaveArgs,
+    #[command(flatten)]
+    batch: BatchArgs,
+}
+
+#[derive(Args, Debug)]
+struct VerifyArgs {
+    /// PNG files, directories searched recursively for PNG files, or glob patterns
+    #[arg(required = true)]
+    file_paths: Vec<String>,
+    #[arg(short = 'f', long, value_enum, default_value_t)]
+    format: OutputFormat,
+    #[command(flatten)]
+    batch: BatchArgs,
+}
+
+#[derive(Args, Debug)]
+struct RepairArgs {
+    /// PNG files, directories searched recursively for PNG files, or glob patterns
+    #[arg(required = true)]
+    file_paths: Vec<String>,
+    /// What to do with bytes after the IEND chunk
+    #[arg(long, value_enum, default_value_t)]
+    trailing: TrailingData,
+    /// Search for flipped bits that explain a CRC mismatch instead of only recomputing the CRC
+    #[arg(long)]
+    fix_bits: bool,
+    /// Correct errors spanning up to this many consecutive bits
+    #[arg(long, value_name = "BITS", default_value_t = 1, requires = "fix_bits")]
+    max_burst: u32,
+    /// Give up the search for flipped bits in a chunk after this many error patterns
+    #[arg(long, value_name = "N", default_value_t = BitFixOptions::default().max_checks, requires = "fix_bits")]
+    max_checks: u64,
+    /// Recover image data cut off by the end of the file, padding the missing scanlines
+    #[arg(long)]
+    salvage: bool,
+    /// Where to save the repaired PNG, overwriting the input file if not given
+    #[arg(short = 'o', long)]
+    output_file: Option<String>,
+    /// Save results under this directory, mirroring the input layout, instead of over the inputs
+    #[arg(long, conflicts_with = "output_file")]
+    output_dir: Option<String>,
+    #[arg(short = 'f', long, value_enum, default_value_t)]
+    format: OutputFormat,
+    #[command(flatten)]
+    save: SaveArgs,
+    #[command(flatten)]
+    batch: BatchArgs,
 }
 
 #[derive(Subcommand, Debug)]
@@ -49,20 +300,193 @@ enum Commands {
     Remove(RemoveArgs),
     /// Print a list of PNG chunks that can be searched for messages
     Print(PrintArgs),
+    /// Export the chunks of a PNG file into an editable JSON or YAML manifest
+    Export(ExportArgs),
+    /// Rebuild a PNG file from a manifest
+    Import(ImportArgs),
+    /// Compare the chunks of two PNG files
+    Diff(DiffArgs),
+    /// Remove metadata chunks and GPS location data from a PNG file
+    Strip(StripArgs),
+    /// Copy ancillary chunks from one PNG file into another
+    CopyChunks(CopyChunksArgs),
+    /// Check a PNG file for damaged chunks and image data, listing every problem found
+    Verify(VerifyArgs),
+    /// Fix wrong CRCs and lengths, missing IEND chunks, trailing data and truncated image data in
+    /// a damaged PNG file
+    Repair(RepairArgs),
+}
+
+/// Exits with a usage error if a single output file is given for several inputs.
+fn check_output_file(inputs: &[String], output_file: Option<&str>) {
+    if output_file.is_some() && batch::is_batch(inputs) {
+        Cli::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--output-file takes a single input file; use --output-dir for several",
+            )
+            .exit();
+    }
 }
 
 fn main() -> Result<(), commands::CommandError> {
     let cli = Cli::parse();
 
     match &cli.command {
-        Commands::Encode(args) => commands::encode(
-            &args.file_path,
-            &args.chunk_type,
-            &args.message,
-            args.output_file.as_deref(),
+        Commands::Encode(args) => {
+            check_output_file(&args.file_paths, args.output_file.as_deref());
+            let options = args.batch.options(args.output_dir.as_deref());
+            commands::for_each_input(&args.file_paths, &options, |out, file_path, output| {
+                commands::encode(
+                    out,
+                    file_path,
+                    &args.chunk_type,
+                    &args.message,
+                    output.or(args.output_file.as_deref()),
+                    &(&args.save).into(),
+                    args.in_place,
+                    args.after_iend,
+                )
+            })
+        }
+        Commands::Decode(args) => commands::for_each_input(
+            &args.file_paths,
+            &args.batch.options(None),
+            |out, file_path, _| {
+                commands::decode(
+                    out,
+                    file_path,
+                    &args.chunk_type,
+                    args.format,
+                    args.after_iend,
+                )
+            },
+        ),
+        Commands::Remove(args) => {
+            check_output_file(&args.file_paths, args.output_file.as_deref());
+            let options = args.batch.options(args.output_dir.as_deref());
+            commands::for_each_input(&args.file_paths, &options, |out, file_path, output| {
+                commands::remove(
+                    out,
+                    file_path,
+                    &args.chunk_type,
+                    args.all,
+                    output.or(args.output_file.as_deref()),
+                    &(&args.save).into(),
+                    args.in_place,
+                )
+            })
+        }
+        Commands::Print(args) => commands::for_each_input(
+            &args.file_paths,
+            &args.batch.options(None),
+            |out, file_path, _| {
+                commands::print_chunks(
+                    out,
+                    file_path,
+                    args.select.as_deref(),
+                    args.format,
+                    args.preview,
+                )
+            },
+        ),
+        Commands::Export(args) => {
+            check_output_file(&args.file_paths, args.output_file.as_deref());
+            let options = args.batch.options(args.output_dir.as_deref());
+            commands::for_each_input(&args.file_paths, &options, |out, file_path, output| {
+                // In a batch, each manifest is saved next to its PNG, or under the output
+                // directory, with its data files in a directory of its own
+                let extension = args.format.unwrap_or_default().extension();
+                let output = output.map(|o| Path::new(o).with_extension(extension));
+                let data_dir = match (&output, &args.data_dir) {
+                    (Some(output), Some(data_dir)) => {
+                        let stem = output.file_stem().unwrap_or_default();
+                        Some(Path::new(data_dir).join(stem).display().to_string())
+                    }
+                    (_, data_dir) => data_dir.clone(),
+                };
+                let output = output.map(|o| o.display().to_string());
+                commands::export(
+                    out,
+                    file_path,
+                    output.as_deref().or(args.output_file.as_deref()),
+                    data_dir.as_deref(),
+                    args.format,
+                )
+            })
+        }
+        Commands::Import(args) => commands::import(
+            &args.manifest_path,
+            &args.output_file,
+            args.format,
+            &(&args.save).into(),
+        ),
+        Commands::Diff(args) => commands::diff(
+            &mut std::io::stdout().lock(),
+            &args.old_file,
+            &args.new_file,
+            args.pixels,
+            args.format,
         ),
-        Commands::Decode(args) => commands::decode(&args.file_path, &args.chunk_type),
-        Commands::Remove(args) => commands::remove(&args.file_path, &args.chunk_type),
-        Commands::Print(args) => commands::print_chunks(&args.file_path),
+        Commands::Strip(args) => {
+            check_output_file(&args.file_paths, args.output_file.as_deref());
+            let options = args.batch.options(args.output_dir.as_deref());
+            commands::for_each_input(&args.file_paths, &options, |out, file_path, output| {
+                commands::strip(
+                    out,
+                    file_path,
+                    output.or(args.output_file.as_deref()),
+                    args.preset,
+                    args.select.as_deref(),
+                    &args.keep,
+                    args.gps,
+                    args.trailing,
+                    args.format,
+                    &(&args.save).into(),
+                )
+            })
+        }
+        Commands::CopyChunks(args) => {
+            check_output_file(&args.dst_files, args.output_file.as_deref());
+            let options = args.batch.options(args.output_dir.as_deref());
+            commands::for_each_input(&args.dst_files, &options, |out, dst_file, output| {
+                commands::copy_chunks(
+                    out,
+                    &args.src_file,
+                    dst_file,
+                    &args.select,
+                    output.or(args.output_file.as_deref()),
+                    args.format,
+                    &(&args.save).into(),
+                )
+            })
+        }
+        Commands::Verify(args) => {
+            let options = args.batch.options(None);
+            commands::for_each_input(&args.file_paths, &options, |out, file_path, _| {
+                commands::verify(out, file_path, args.format)
+            })
+        }
+        Commands::Repair(args) => {
+            check_output_file(&args.file_paths, args.output_file.as_deref());
+            let options = args.batch.options(args.output_dir.as_deref());
+            commands::for_each_input(&args.file_paths, &options, |out, file_path, output| {
+                commands::repair(
+                    out,
+                    file_path,
+                    output.or(args.output_file.as_deref()),
+                    &RepairOptions {
+                        trailing: args.trailing,
+                        fix_bits: args.fix_bits.then_some(BitFixOptions {
+                            max_burst: args.max_burst,
+                            max_checks: args.max_checks,
+                        }),
+                        salvage: args.salvage,
+                    },
+                    args.format,
+                    &(&args.save).into(),
+                )
+            })
+        }
     }
 }
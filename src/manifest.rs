@@ -0,0 +1,412 @@
+use std::{
+    fmt::{self, Display},
+    fs, io,
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    chunk::{Chunk, ChunkRef},
+    chunk_type::{ChunkType, ChunkTypeDecodeError},
+    png::Png,
+};
+
+/// The version of the manifest format written by `Manifest::from_png`.
+pub const MANIFEST_VERSION: u32 = 1;
+
+/// The serialization used for a manifest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum ManifestFormat {
+    #[default]
+    Json,
+    Yaml,
+}
+
+impl ManifestFormat {
+    /// Picks the format from a file extension, defaulting to JSON.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Self {
+        match path.as_ref().extension().and_then(|ext| ext.to_str()) {
+            Some("yaml" | "yml") => Self::Yaml,
+            _ => Self::Json,
+        }
+    }
+
+    /// The file extension for manifests in this format.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            Self::Yaml => "yaml",
+        }
+    }
+}
+
+/// Where the data of a chunk in a manifest comes from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChunkData {
+    /// The data encoded as standard base64.
+    Base64(String),
+    /// The data as UTF-8 text, convenient for editing text chunks by hand.
+    Text(String),
+    /// A file holding the raw data, relative to the directory of the manifest. Absolute
+    /// paths and paths containing `..` are rejected, so that an imported manifest cannot
+    /// read files outside its directory.
+    File(PathBuf),
+}
+
+/// A single chunk in a manifest.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestChunk {
+    #[serde(rename = "type")]
+    pub chunk_type: String,
+    // Keeps YAML as `data: { base64: ... }` rather than using tags, matching JSON
+    #[serde(with = "serde_yaml::with::singleton_map")]
+    pub data: ChunkData,
+}
+
+/// An editable description of the chunks of a PNG, in order. Rebuilding a PNG from an
+/// unmodified manifest reproduces the original bytes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub schema_version: u32,
+    pub chunks: Vec<ManifestChunk>,
+    /// The bytes after the IEND chunk, if there are any.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "serde_yaml::with::singleton_map"
+    )]
+    pub trailing_data: Option<ChunkData>,
+}
+
+#[derive(Debug)]
+pub enum ManifestError {
+    Io(io::Error),
+    Json(serde_json::Error),
+    Yaml(serde_yaml::Error),
+    UnsupportedVersion(u32),
+    ChunkTypeDecode(ChunkTypeDecodeError),
+    Base64 {
+        index: usize,
+        err: base64::DecodeError,
+    },
+    TrailingBase64(base64::DecodeError),
+    /// A data file path that is absolute or leaves the directory of the manifest.
+    UnsafePath(PathBuf),
+}
+
+impl Display for ManifestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => writeln!(f, "File error: {err}"),
+            Self::Json(err) => writeln!(f, "JSON error: {err}"),
+            Self::Yaml(err) => writeln!(f, "YAML error: {err}"),
+            Self::UnsupportedVersion(version) => {
+                writeln!(f, "Unsupported manifest version: {version}")
+            }
+            Self::ChunkTypeDecode(err) => writeln!(f, "Chunk type decode error: {err}"),
+            Self::Base64 { index, err } => {
+                writeln!(f, "Invalid base64 data in chunk {index}: {err}")
+            }
+            Self::TrailingBase64(err) => {
+                writeln!(f, "Invalid base64 data after IEND: {err}")
+            }
+            Self::UnsafePath(path) => writeln!(
+                f,
+                "Data file {} must be relative to the manifest and may not contain '..'",
+                path.display()
+            ),
+        }
+    }
+}
+
+impl From<io::Error> for ManifestError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for ManifestError {
+    fn from(err: serde_json::Error) -> Self {
+        Self::Json(err)
+    }
+}
+
+impl From<serde_yaml::Error> for ManifestError {
+    fn from(err: serde_yaml::Error) -> Self {
+        Self::Yaml(err)
+    }
+}
+
+impl From<ChunkTypeDecodeError> for ManifestError {
+    fn from(err: ChunkTypeDecodeError) -> Self {
+        Self::ChunkTypeDecode(err)
+    }
+}
+
+/// Name of the file the data of the chunk at `index` is exported to.
+fn data_file_name(index: usize, chunk: &ChunkRef) -> String {
+    format!("{index:04}-{}.bin", chunk.chunk_type())
+}
+
+/// Name of the file the bytes after IEND are exported to.
+const TRAILING_DATA_FILE: &str = "trailing.bin";
+
+/// Reads data from a manifest, with file paths relative to `base_dir` and kept below it.
+/// `index` is the index
+/// of the chunk the data belongs to, or `None` for the bytes after IEND.
+fn read_data(
+    data: &ChunkData,
+    base_dir: &Path,
+    index: Option<usize>,
+) -> Result<Vec<u8>, ManifestError> {
+    Ok(match data {
+        ChunkData::Base64(data) => BASE64.decode(data).map_err(|err| match index {
+            Some(index) => ManifestError::Base64 { index, err },
+            None => ManifestError::TrailingBase64(err),
+        })?,
+        ChunkData::Text(text) => text.as_bytes().to_vec(),
+        ChunkData::File(path) => {
+            let escapes = path
+                .components()
+                .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir));
+            if escapes {
+                return Err(ManifestError::UnsafePath(path.clone()));
+            }
+            fs::read(base_dir.join(path))?
+        }
+    })
+}
+
+impl Manifest {
+    /// Describes the chunks of a PNG with their data inlined as base64.
+    pub fn from_png(png: &Png) -> Self {
+        Self {
+            schema_version: MANIFEST_VERSION,
+            chunks: png
+                .chunks()
+                .map(|chunk| ManifestChunk {
+                    chunk_type: chunk.chunk_type().to_string(),
+                    data: ChunkData::Base64(BASE64.encode(chunk.data())),
+                })
+                .collect(),
+            trailing_data: Some(png.trailing_data())
+                .filter(|data| !data.is_empty())
+                .map(|data| ChunkData::Base64(BASE64.encode(data))),
+        }
+    }
+
+    /// Describes the chunks of a PNG, writing the data of each chunk to its own file in
+    /// `base_dir.join(data_dir)`. The manifest refers to the files relative to `base_dir`,
+    /// which should be the directory the manifest is saved in.
+    pub fn from_png_with_files<P: AsRef<Path>, Q: AsRef<Path>>(
+        png: &Png,
+        base_dir: P,
+        data_dir: Q,
+    ) -> io::Result<Self> {
+        let data_dir = data_dir.as_ref();
+        fs::create_dir_all(base_dir.as_ref().join(data_dir))?;
+
+        let chunks = png
+            .chunks()
+            .enumerate()
+            .map(|(index, chunk)| {
+                let path = data_dir.join(data_file_name(index, &chunk));
+                fs::write(base_dir.as_ref().join(&path), chunk.data())?;
+                Ok(ManifestChunk {
+                    chunk_type: chunk.chunk_type().to_string(),
+                    data: ChunkData::File(path),
+                })
+            })
+            .collect::<io::Result<_>>()?;
+
+        let trailing_data = match png.trailing_data() {
+            [] => None,
+            data => {
+                let path = data_dir.join(TRAILING_DATA_FILE);
+                fs::write(base_dir.as_ref().join(&path), data)?;
+                Some(ChunkData::File(path))
+            }
+        };
+
+        Ok(Self {
+            schema_version: MANIFEST_VERSION,
+            chunks,
+            trailing_data,
+        })
+    }
+
+    /// Rebuilds the PNG described by this manifest, reading data files relative to
+    /// `base_dir`.
+    pub fn to_png<P: AsRef<Path>>(&self, base_dir: P) -> Result<Png<'static>, ManifestError> {
+        if self.schema_version != MANIFEST_VERSION {
+            return Err(ManifestError::UnsupportedVersion(self.schema_version));
+        }
+
+        let chunks = self
+            .chunks
+            .iter()
+            .enumerate()
+            .map(|(index, chunk)| {
+                let chunk_type = ChunkType::from_str(&chunk.chunk_type)?;
+                let data = read_data(&chunk.data, base_dir.as_ref(), Some(index))?;
+                Ok(Chunk::new(chunk_type, data))
+            })
+            .collect::<Result<_, ManifestError>>()?;
+
+        let mut png = Png::from_chunks(chunks);
+        if let Some(data) = &self.trailing_data {
+            png.set_trailing_data(read_data(data, base_dir.as_ref(), None)?);
+        }
+        Ok(png)
+    }
+
+    /// Parses a manifest from text.
+    pub fn parse(text: &str, format: ManifestFormat) -> Result<Self, ManifestError> {
+        Ok(match format {
+            ManifestFormat::Json => serde_json::from_str(text)?,
+            ManifestFormat::Yaml => serde_yaml::from_str(text)?,
+        })
+    }
+
+    /// Serializes the manifest to text.
+    pub fn to_text(&self, format: ManifestFormat) -> Result<String, ManifestError> {
+        Ok(match format {
+            ManifestFormat::Json => serde_json::to_string_pretty(self)? + "\n",
+            ManifestFormat::Yaml => serde_yaml::to_string(self)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn testing_png() -> Png<'static> {
+        let chunks = vec![
+            Chunk::new(ChunkType::from_str("IHDR").unwrap(), vec![0, 1, 2, 255]),
+            Chunk::new(
+                ChunkType::from_str("tEXt").unwrap(),
+                b"Title\0Dice".to_vec(),
+            ),
+            Chunk::new(ChunkType::from_str("IEND").unwrap(), vec![]),
+        ];
+        let mut png = Png::from_chunks(chunks);
+        png.set_trailing_data(b"after IEND".to_vec());
+        png
+    }
+
+    #[test]
+    fn test_inline_round_trip() {
+        let png = testing_png();
+        let manifest = Manifest::from_png(&png);
+
+        for format in [ManifestFormat::Json, ManifestFormat::Yaml] {
+            let text = manifest.to_text(format).unwrap();
+            let parsed = Manifest::parse(&text, format).unwrap();
+            assert_eq!(parsed, manifest);
+            assert_eq!(parsed.to_png(".").unwrap().as_bytes(), png.as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_file_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let png = testing_png();
+        let manifest = Manifest::from_png_with_files(&png, dir.path(), "data").unwrap();
+
+        assert_eq!(
+            manifest.chunks[1].data,
+            ChunkData::File(PathBuf::from("data/0001-tEXt.bin"))
+        );
+        assert_eq!(
+            fs::read(dir.path().join("data/0001-tEXt.bin")).unwrap(),
+            b"Title\0Dice"
+        );
+        assert_eq!(
+            manifest.trailing_data,
+            Some(ChunkData::File(PathBuf::from("data/trailing.bin")))
+        );
+        assert_eq!(
+            manifest.to_png(dir.path()).unwrap().as_bytes(),
+            png.as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_edited_manifest() {
+        let text = r#"{
+            "schema_version": 1,
+            "chunks": [
+                { "type": "IHDR", "data": { "base64": "AAEC/w==" } },
+                { "type": "ruSt", "data": { "text": "hello" } },
+                { "type": "IEND", "data": { "base64": "" } }
+            ]
+        }"#;
+        let png = Manifest::parse(text, ManifestFormat::Json)
+            .unwrap()
+            .to_png(".")
+            .unwrap();
+
+        assert_eq!(png.chunks().len(), 3);
+        assert_eq!(png.chunk_by_type("IHDR").unwrap().data(), [0, 1, 2, 255]);
+        assert_eq!(png.chunk_by_type("ruSt").unwrap().data(), b"hello");
+        assert!(png.trailing_data().is_empty());
+    }
+
+    #[test]
+    fn test_invalid_manifests() {
+        let mut manifest = Manifest::from_png(&testing_png());
+        manifest.schema_version = 2;
+        assert!(matches!(
+            manifest.to_png("."),
+            Err(ManifestError::UnsupportedVersion(2))
+        ));
+
+        let mut manifest = Manifest::from_png(&testing_png());
+        manifest.chunks[0].data = ChunkData::Base64(String::from("not base64!"));
+        assert!(matches!(
+            manifest.to_png("."),
+            Err(ManifestError::Base64 { index: 0, .. })
+        ));
+
+        let mut manifest = Manifest::from_png(&testing_png());
+        manifest.trailing_data = Some(ChunkData::Base64(String::from("not base64!")));
+        assert!(matches!(
+            manifest.to_png("."),
+            Err(ManifestError::TrailingBase64(_))
+        ));
+
+        for path in ["/etc/passwd", "../secret.bin", "data/../../secret.bin"] {
+            let mut manifest = Manifest::from_png(&testing_png());
+            manifest.chunks[1].data = ChunkData::File(PathBuf::from(path));
+            assert!(matches!(
+                manifest.to_png("."),
+                Err(ManifestError::UnsafePath(p)) if p == Path::new(path)
+            ));
+
+            let mut manifest = Manifest::from_png(&testing_png());
+            manifest.trailing_data = Some(ChunkData::File(PathBuf::from(path)));
+            assert!(matches!(
+                manifest.to_png("."),
+                Err(ManifestError::UnsafePath(_))
+            ));
+        }
+
+        let mut manifest = Manifest::from_png(&testing_png());
+        manifest.chunks[0].chunk_type = String::from("I1DR");
+        assert!(manifest.to_png(".").is_err());
+    }
+
+    #[test]
+    fn test_format_from_path() {
+        assert_eq!(ManifestFormat::from_path("a.yml"), ManifestFormat::Yaml);
+        assert_eq!(ManifestFormat::from_path("a.yaml"), ManifestFormat::Yaml);
+        assert_eq!(ManifestFormat::from_path("a.json"), ManifestFormat::Json);
+        assert_eq!(ManifestFormat::from_path("a"), ManifestFormat::Json);
+    }
+}
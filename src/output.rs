@@ -0,0 +1,203 @@
+use std::{
+    ffi::OsString,
+    fs::{self, File, FileTimes},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use tempfile::{Builder, NamedTempFile};
+
+use crate::input::is_stdio;
+
+/// Options controlling how `save_atomic` replaces an existing file.
+#[derive(Debug, Clone, Default)]
+pub struct SaveOptions {
+    /// Keep a copy of the file being replaced next to it, with `.bak` appended to its name.
+    pub backup: bool,
+    /// Give the new file the modification time of the file being replaced.
+    pub preserve_mtime: bool,
+}
+
+/// Returns the path used for the backup of `file_path`.
+pub fn backup_path<P: AsRef<Path>>(file_path: P) -> PathBuf {
+    let mut name = OsString::from(file_path.as_ref().as_os_str());
+    name.push(".bak");
+    PathBuf::from(name)
+}
+
+fn temp_file_in(dir: &Path) -> io::Result<NamedTempFile> {
+    let mut builder = Builder::new();
+    builder.prefix(".pngme-").suffix(".tmp");
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        // Matches `File::create`, leaving the umask to narrow the mode down
+        builder.permissions(fs::Permissions::from_mode(0o666));
+    }
+
+    builder.tempfile_in(dir)
+}
+
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Writes `bytes` to `file_path` without ever leaving a partially written file behind.
+/// The bytes go to a temporary file in the same directory which is synced to disk and then
+/// renamed over the target. The target is created if it does not exist, and if it does,
+/// its permissions are carried over to the new file.
+pub fn save_atomic<P: AsRef<Path>>(
+    file_path: P,
+    bytes: &[u8],
+    options: &SaveOptions,
+) -> io::Result<()> {
+    let file_path = file_path.as_ref();
+    let dir = match file_path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+
+    let original = match fs::metadata(file_path) {
+        Ok(metadata) => Some(metadata),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+        Err(err) => return Err(err),
+    };
+
+    let mut temp = temp_file_in(dir)?;
+    temp.write_all(bytes)?;
+
+    if let Some(metadata) = &original {
+        temp.as_file().set_permissions(metadata.permissions())?;
+        if options.preserve_mtime {
+            let times = FileTimes::new().set_modified(metadata.modified()?);
+            temp.as_file().set_times(times)?;
+        }
+    }
+    temp.as_file().sync_all()?;
+
+    if options.backup && original.is_some() {
+        let backup = backup_path(file_path);
+        fs::copy(file_path, &backup)?;
+        File::open(&backup)?.sync_all()?;
+    }
+
+    temp.persist(file_path).map_err(|err| err.error)?;
+    sync_dir(dir)
+}
+
+/// Opens stdout, or a special file such as a pipe, as a stream. These cannot be replaced, so
+/// they are written to directly. Returns `None` for regular files, directories and paths
+/// that do not exist yet.
+pub fn open_stream<P: AsRef<Path>>(file_path: P) -> io::Result<Option<Box<dyn Write>>> {
+    let file_path = file_path.as_ref();
+    if is_stdio(file_path) {
+        return Ok(Some(Box::new(io::stdout().lock())));
+    }
+    match fs::metadata(file_path) {
+        Ok(metadata) if !metadata.is_file() && !metadata.is_dir() => {
+            Ok(Some(Box::new(File::options().write(true).open(file_path)?)))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Writes `bytes` to `file_path`, or to stdout if the path is `-`. Regular files are saved
+/// with `save_atomic`; pipes and other special files are written to directly with
+/// `open_stream` and `options` are ignored.
+pub fn save<P: AsRef<Path>>(file_path: P, bytes: &[u8], options: &SaveOptions) -> io::Result<()> {
+    match open_stream(&file_path)? {
+        Some(mut stream) => {
+            stream.write_all(bytes)?;
+            stream.flush()
+        }
+        None => save_atomic(file_path, bytes, options),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_save_creates_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("new.png");
+
+        save_atomic(&path, b"new", &SaveOptions::default()).unwrap();
+
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+        assert!(!backup_path(&path).exists());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_save_replaces_file_with_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("old.png");
+        fs::write(&path, b"old").unwrap();
+
+        let options = SaveOptions {
+            backup: true,
+            ..Default::default()
+        };
+        save_atomic(&path, b"new", &options).unwrap();
+
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+        assert_eq!(fs::read(backup_path(&path)).unwrap(), b"old");
+    }
+
+    #[test]
+    fn test_save_preserves_mtime() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("old.png");
+        fs::write(&path, b"old").unwrap();
+
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+
+        let options = SaveOptions {
+            preserve_mtime: true,
+            ..Default::default()
+        };
+        save_atomic(&path, b"new", &options).unwrap();
+
+        assert_eq!(fs::metadata(&path).unwrap().modified().unwrap(), mtime);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_save_preserves_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("old.png");
+        fs::write(&path, b"old").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+
+        save_atomic(&path, b"new", &SaveOptions::default()).unwrap();
+
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o640);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_save_writes_special_files_directly() {
+        // Replacing /dev/null would need write access to /dev
+        save("/dev/null", b"new", &SaveOptions::default()).unwrap();
+        assert!(!fs::metadata("/dev/null").unwrap().is_file());
+    }
+}
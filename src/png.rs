@@ -1,12 +1,77 @@
-use std::fmt::{self, Display};
+use alloc::{
+    borrow::Cow,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::fmt::{self, Display};
 
-use super::chunk::{Chunk, ChunkDecodeError, ChunkIter};
+use super::{
+    chunk::{Chunk, ChunkDecodeError, ChunkIter, ChunkRef},
+    chunk_type::ChunkType,
+    limits::{DecodeLimits, LimitError},
+};
+
+/// A chunk held by a `Png`. Chunks parsed from a buffer stay borrowed from it, while chunks
+/// added afterwards are owned.
+#[derive(Debug, Clone)]
+enum PngChunk<'a> {
+    Borrowed(ChunkRef<'a>),
+    Owned(Chunk),
+}
+
+impl PngChunk<'_> {
+    fn as_chunk_ref(&self) -> ChunkRef<'_> {
+        match self {
+            Self::Borrowed(chunk) => *chunk,
+            Self::Owned(chunk) => chunk.as_chunk_ref(),
+        }
+    }
+
+    fn into_chunk(self) -> Chunk {
+        match self {
+            Self::Borrowed(chunk) => chunk.to_chunk(),
+            Self::Owned(chunk) => chunk,
+        }
+    }
+}
+
+/// Ancillary chunk types defined by the PNG spec and its registered extensions.
+pub const KNOWN_ANCILLARY_CHUNKS: [&str; 21] = [
+    "acTL", "bKGD", "cHRM", "cICP", "cLLI", "eXIf", "fcTL", "fdAT", "gAMA", "hIST", "iCCP", "iTXt",
+    "mDCV", "pHYs", "sBIT", "sPLT", "sRGB", "tEXt", "tIME", "tRNS", "zTXt",
+];
+
+/// Critical chunk types whose modification makes unknown unsafe-to-copy chunks invalid.
+const IMAGE_CHUNKS: [&str; 3] = ["IHDR", "PLTE", "IDAT"];
 
 /// A PNG container as described by the PNG spec
 /// http://www.libpng.org/pub/png/spec/1.2/PNG-Contents.html
-#[derive(Debug)]
-pub struct Png {
-    chunks: Vec<Chunk>,
+///
+/// As the spec requires of editors, any change to IHDR, PLTE or IDAT chunks drops unknown
+/// ancillary chunks that are not safe to copy. Adding the first chunk of one of these types
+/// builds the image rather than changing it and drops nothing. The dropped chunks are kept
+/// in `dropped_chunks` so callers can report them.
+///
+/// Bytes after the IEND chunk are not parsed as chunks but kept as they are in
+/// `trailing_data`, and written back after IEND.
+#[derive(Debug, Clone)]
+pub struct Png<'a> {
+    chunks: Vec<PngChunk<'a>>,
+    dropped: Vec<Chunk>,
+    trailing: Cow<'a, [u8]>,
+}
+
+/// Where `Png::insert_chunk` places a new chunk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkPlacement {
+    /// Right before the PLTE chunk, for chunks that must precede the palette. Falls back to
+    /// `BeforeIdat` if there is no PLTE chunk.
+    BeforePlte,
+    /// Right before the first IDAT chunk, for chunks that must precede the image data.
+    /// Falls back to `BeforeIend` if there is no IDAT chunk.
+    BeforeIdat,
+    /// Right before the IEND chunk, or at the end if there is no IEND chunk.
+    BeforeIend,
 }
 
 #[derive(Debug)]
@@ -14,36 +79,235 @@ pub struct ChunkNotFoundError {
     chunk_type: String,
 }
 
+impl ChunkNotFoundError {
+    /// An error for a search by a chunk type or selector that found nothing.
+    pub fn new(chunk_type: &str) -> Self {
+        Self {
+            chunk_type: String::from(chunk_type),
+        }
+    }
+}
+
 impl Display for ChunkNotFoundError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         writeln!(f, "Chunk not found for {}", self.chunk_type)
     }
 }
 
-impl Png {
+impl<'a> Png<'a> {
     pub const STANDARD_HEADER: [u8; 8] = [137, 80, 78, 71, 13, 10, 26, 10];
 
     /// Construct PNG from chunks.
     pub fn from_chunks(chunks: Vec<Chunk>) -> Self {
-        Self { chunks }
+        Self {
+            chunks: chunks.into_iter().map(PngChunk::Owned).collect(),
+            dropped: Vec::new(),
+            trailing: Cow::Borrowed(&[]),
+        }
+    }
+
+    /// Parses a PNG without checking chunk CRCs. Chunks borrow their data from `bytes` and
+    /// can be checked individually with `ChunkRef::verify_crc`.
+    pub fn from_bytes_unverified(bytes: &'a [u8]) -> Result<Self, PngDecodeError> {
+        Self::parse(bytes, ChunkIter::unverified, &DecodeLimits::UNLIMITED)
+    }
+
+    /// Parses a PNG without checking the CRCs of its IDAT chunks, which leaves the image
+    /// data unread. The IDAT chunks still borrow their data, so with a memory-mapped source
+    /// it is only read from disk when it is used. This makes reading metadata from huge
+    /// files near-instant; check the image data with `ChunkRef::verify_crc` if needed.
+    pub fn from_bytes_lazy(bytes: &'a [u8]) -> Result<Self, PngDecodeError> {
+        Self::parse(
+            bytes,
+            |bytes| ChunkIter::new(bytes).lazy_image_data(),
+            &DecodeLimits::UNLIMITED,
+        )
+    }
+
+    /// Parses a PNG from an untrusted source, failing with `LimitExceeded` if the file, its
+    /// chunks or the image described by its IHDR chunk exceed the limits. Compressed chunk
+    /// data is not inflated here; see `DecodeLimits::inflate_chunk`.
+    pub fn from_bytes_with_limits(
+        bytes: &'a [u8],
+        limits: &DecodeLimits,
+    ) -> Result<Self, PngDecodeError> {
+        limits.check_total_size(bytes.len() as u64)?;
+        let png = Self::parse(bytes, ChunkIter::new, limits)?;
+        limits.check_png(&png)?;
+        Ok(png)
+    }
+
+    fn parse(
+        bytes: &'a [u8],
+        chunk_iter: fn(&'a [u8]) -> ChunkIter<'a>,
+        limits: &DecodeLimits,
+    ) -> Result<Self, PngDecodeError> {
+        if bytes.len() < 8 {
+            return Err(PngDecodeError::TooShort(bytes.to_vec()));
+        }
+        if bytes[0..8] != Self::STANDARD_HEADER {
+            return Err(PngDecodeError::InvalidHeader(
+                bytes[0..8].try_into().unwrap(),
+            ));
+        }
+
+        let mut iter = chunk_iter(&bytes[8..]).starting_at(8).with_limits(*limits);
+        let mut chunks = Vec::new();
+        for chunk in iter.by_ref() {
+            let chunk = chunk?;
+            chunks.push(PngChunk::Borrowed(chunk));
+            if chunk.chunk_type().to_string() == "IEND" {
+                break;
+            }
+        }
+
+        Ok(Self {
+            chunks,
+            dropped: Vec::new(),
+            trailing: Cow::Borrowed(iter.remaining()),
+        })
+    }
+
+    /// Returns true if a chunk of this type is an ancillary chunk that this library does not
+    /// know and that may not be kept once the image data changes.
+    pub fn is_unknown_unsafe_to_copy(chunk_type: &ChunkType) -> bool {
+        !chunk_type.is_critical()
+            && !chunk_type.is_safe_to_copy()
+            && !KNOWN_ANCILLARY_CHUNKS.contains(&chunk_type.to_string().as_str())
+    }
+
+    /// Returns true if chunks of this type describe the image, so changing them invalidates
+    /// unknown unsafe-to-copy chunks.
+    pub fn is_image_chunk(chunk_type: &ChunkType) -> bool {
+        IMAGE_CHUNKS.contains(&chunk_type.to_string().as_str())
+    }
+
+    /// Drops unknown unsafe-to-copy chunks if a chunk of `chunk_type` was changed and it
+    /// describes the image.
+    fn image_changed(&mut self, chunk_type: &ChunkType) {
+        if Self::is_image_chunk(chunk_type) {
+            self.drop_unsafe_to_copy();
+        }
+    }
+
+    /// Drops unknown unsafe-to-copy chunks if a chunk of `chunk_type` was added and it
+    /// describes the image. Adding the first chunk of a type builds the image rather than
+    /// changing it, so nothing is dropped then.
+    fn image_added(&mut self, chunk_type: &ChunkType) {
+        let count = self
+            .chunks()
+            .filter(|c| c.chunk_type() == chunk_type)
+            .take(2)
+            .count();
+        if count > 1 {
+            self.image_changed(chunk_type);
+        }
+    }
+
+    /// Removes every unknown ancillary chunk that is not safe to copy, adding them to
+    /// `dropped_chunks`. Returns the number of chunks dropped.
+    pub fn drop_unsafe_to_copy(&mut self) -> usize {
+        let before = self.dropped.len();
+        let chunks = core::mem::take(&mut self.chunks);
+        for chunk in chunks {
+            if Self::is_unknown_unsafe_to_copy(chunk.as_chunk_ref().chunk_type()) {
+                self.dropped.push(chunk.into_chunk());
+            } else {
+                self.chunks.push(chunk);
+            }
+        }
+        self.dropped.len() - before
+    }
+
+    /// Returns the chunks dropped because the image data changed, in the order they were
+    /// dropped.
+    pub fn dropped_chunks(&self) -> &[Chunk] {
+        &self.dropped
     }
 
     /// Append chunk to end of PNG chunks.
     pub fn append_chunk(&mut self, chunk: Chunk) {
-        self.chunks.push(chunk)
+        let chunk_type = *chunk.chunk_type();
+        self.chunks.push(PngChunk::Owned(chunk));
+        self.image_added(&chunk_type);
+    }
+
+    /// Insert chunk at the given placement.
+    pub fn insert_chunk(&mut self, chunk: Chunk, placement: ChunkPlacement) {
+        let index = match placement {
+            ChunkPlacement::BeforePlte => self
+                .position("PLTE")
+                .or_else(|| self.position("IDAT"))
+                .or_else(|| self.position("IEND")),
+            ChunkPlacement::BeforeIdat => self.position("IDAT").or_else(|| self.position("IEND")),
+            ChunkPlacement::BeforeIend => self.position("IEND"),
+        };
+        let index = index.unwrap_or(self.chunks.len());
+        let chunk_type = *chunk.chunk_type();
+        self.chunks.insert(index, PngChunk::Owned(chunk));
+        self.image_added(&chunk_type);
+    }
+
+    /// Returns the index of the first chunk that matches the chunk_type given.
+    fn position(&self, chunk_type: &str) -> Option<usize> {
+        self.chunks()
+            .position(|x| x.chunk_type().to_string() == chunk_type)
     }
 
     /// Remove chunk that matches the chunk_type given from PNG.
     pub fn remove_chunk(&mut self, chunk_type: &str) -> Result<Chunk, ChunkNotFoundError> {
-        let chunk_type = String::from(chunk_type);
-        match self
-            .chunks()
+        match self.position(chunk_type) {
+            Some(index) => {
+                let chunk = self.chunks.remove(index).into_chunk();
+                self.image_changed(chunk.chunk_type());
+                Ok(chunk)
+            }
+            None => Err(ChunkNotFoundError {
+                chunk_type: String::from(chunk_type),
+            }),
+        }
+    }
+
+    /// Removes every chunk for which `keep` returns false, returning the removed chunks with
+    /// their former indices.
+    pub fn retain_chunks<F: FnMut(&ChunkRef) -> bool>(
+        &mut self,
+        mut keep: F,
+    ) -> Vec<(usize, Chunk)> {
+        let mut removed = Vec::new();
+        let mut index = 0;
+        self.chunks = core::mem::take(&mut self.chunks)
+            .into_iter()
+            .filter_map(|chunk| {
+                let kept = keep(&chunk.as_chunk_ref());
+                if !kept {
+                    removed.push((index, chunk.clone().into_chunk()));
+                }
+                index += 1;
+                kept.then_some(chunk)
+            })
+            .collect();
+        if removed
             .iter()
-            .position(|x| x.chunk_type().to_string() == chunk_type)
+            .any(|(_, c)| Self::is_image_chunk(c.chunk_type()))
         {
-            Some(index) => Ok(self.chunks.remove(index)),
-            None => Err(ChunkNotFoundError { chunk_type }),
+            self.drop_unsafe_to_copy();
+        }
+        removed
+    }
+
+    /// Replaces the chunk at `index`, returning the chunk it replaced.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is out of bounds.
+    pub fn replace_chunk(&mut self, index: usize, chunk: Chunk) -> Chunk {
+        let changes_image = Self::is_image_chunk(chunk.chunk_type());
+        let old = core::mem::replace(&mut self.chunks[index], PngChunk::Owned(chunk)).into_chunk();
+        if changes_image || Self::is_image_chunk(old.chunk_type()) {
+            self.drop_unsafe_to_copy();
         }
+        old
     }
 
     /// Returns header of PNG.
@@ -52,22 +316,77 @@ impl Png {
     }
 
     /// Returns chunks from PNG.
-    pub fn chunks(&self) -> &[Chunk] {
-        &self.chunks
+    pub fn chunks(&self) -> impl ExactSizeIterator<Item = ChunkRef<'_>> + '_ {
+        self.chunks.iter().map(PngChunk::as_chunk_ref)
     }
 
     /// Get chunk by chunk type from PNG.
-    pub fn chunk_by_type(&self, chunk_type: &str) -> Option<&Chunk> {
+    pub fn chunk_by_type(&self, chunk_type: &str) -> Option<ChunkRef<'_>> {
         self.chunks()
+            .find(|x| x.chunk_type().to_string() == chunk_type)
+    }
+
+    /// Returns the bytes after the IEND chunk.
+    pub fn trailing_data(&self) -> &[u8] {
+        &self.trailing
+    }
+
+    /// Replaces the bytes after the IEND chunk.
+    pub fn set_trailing_data(&mut self, data: Vec<u8>) {
+        self.trailing = Cow::Owned(data);
+    }
+
+    /// Removes the bytes after the IEND chunk, returning them.
+    pub fn strip_trailing_data(&mut self) -> Vec<u8> {
+        core::mem::take(&mut self.trailing).into_owned()
+    }
+
+    /// Iterates over the bytes after the IEND chunk as chunks, for data hidden there in
+    /// chunk form.
+    pub fn trailing_chunks(&self) -> ChunkIter<'_> {
+        ChunkIter::new(&self.trailing)
+    }
+
+    /// Hides a chunk after the IEND chunk, after any chunks already hidden there but ahead
+    /// of other trailing bytes, so that `trailing_chunks` finds it.
+    pub fn append_trailing_chunk(&mut self, chunk: &Chunk) {
+        let mut hidden = self.trailing_chunks();
+        while let Some(Ok(_)) = hidden.next() {}
+        let end = self.trailing.len() - hidden.remaining().len();
+
+        let mut trailing = self.trailing[..end].to_vec();
+        trailing.extend(chunk.as_bytes());
+        trailing.extend_from_slice(&self.trailing[end..]);
+        self.trailing = Cow::Owned(trailing);
+    }
+
+    /// Returns the number of chunks that are still borrowed from the parsed buffer.
+    pub fn borrowed_chunk_count(&self) -> usize {
+        self.chunks
             .iter()
-            .find(|&x| x.chunk_type().to_string() == chunk_type)
+            .filter(|c| matches!(c, PngChunk::Borrowed(_)))
+            .count()
+    }
+
+    /// Copies any borrowed chunks so the PNG no longer depends on the parsed buffer.
+    pub fn into_owned(self) -> Png<'static> {
+        Png {
+            chunks: self
+                .chunks
+                .into_iter()
+                .map(|c| PngChunk::Owned(c.into_chunk()))
+                .collect(),
+            dropped: self.dropped,
+            trailing: Cow::Owned(self.trailing.into_owned()),
+        }
     }
 
     /// Returns PNG as bytes.
     pub fn as_bytes(&self) -> Vec<u8> {
         Self::STANDARD_HEADER
             .into_iter()
-            .chain(self.chunks().into_iter().flat_map(|c| c.as_bytes()))
+            .chain(self.chunks().flat_map(|c| c.as_bytes()))
+            .chain(self.trailing.iter().copied())
             .collect()
     }
 }
@@ -77,6 +396,19 @@ pub enum PngDecodeError {
     TooShort(Vec<u8>),
     InvalidHeader([u8; 8]),
     ChunkDecode(ChunkDecodeError),
+    /// The file as a whole or its image exceeds a limit. Limits exceeded by a single chunk
+    /// are reported as `ChunkDecode` errors with the chunk's offset.
+    LimitExceeded(LimitError),
+}
+
+impl PngDecodeError {
+    /// The byte offset in the parsed buffer where decoding failed.
+    pub fn offset(&self) -> u64 {
+        match self {
+            Self::TooShort(_) | Self::InvalidHeader(_) | Self::LimitExceeded(_) => 0,
+            Self::ChunkDecode(err) => err.offset(),
+        }
+    }
 }
 
 impl Display for PngDecodeError {
@@ -85,6 +417,7 @@ impl Display for PngDecodeError {
             Self::TooShort(bytes) => writeln!(f, "Too short for PNG: {:?}", bytes),
             Self::InvalidHeader(bytes) => writeln!(f, "Invalid header for PNG: {:?}", bytes),
             Self::ChunkDecode(err) => writeln!(f, "Chunk error: {err}"),
+            Self::LimitExceeded(err) => write!(f, "{err}"),
         }
     }
 }
@@ -95,26 +428,23 @@ impl From<ChunkDecodeError> for PngDecodeError {
     }
 }
 
-impl TryFrom<&[u8]> for Png {
-    type Error = PngDecodeError;
+impl From<LimitError> for PngDecodeError {
+    fn from(err: LimitError) -> Self {
+        Self::LimitExceeded(err)
+    }
+}
 
-    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
-        if bytes.len() < 8 {
-            return Err(Self::Error::TooShort(bytes.to_vec()));
-        }
-        if &bytes[0..8] != &Self::STANDARD_HEADER {
-            return Err(Self::Error::InvalidHeader(bytes[0..8].try_into().unwrap()));
-        }
+impl<'a> TryFrom<&'a [u8]> for Png<'a> {
+    type Error = PngDecodeError;
 
-        Ok(Self {
-            chunks: ChunkIter::new(&bytes[8..]).collect::<Result<_, _>>()?,
-        })
+    fn try_from(bytes: &'a [u8]) -> Result<Self, Self::Error> {
+        Self::parse(bytes, ChunkIter::new, &DecodeLimits::UNLIMITED)
     }
 }
 
-impl Display for Png {
+impl Display for Png<'_> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        for chunk in &self.chunks {
+        for chunk in self.chunks() {
             write!(f, "{chunk}")?;
         }
         Ok(())
@@ -124,8 +454,9 @@ impl Display for Png {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::convert::TryFrom;
-    use std::str::FromStr;
+    use alloc::{format, vec};
+    use core::convert::TryFrom;
+    use core::str::FromStr;
 
     use crate::chunk_type::{ChunkType, ChunkTypeDecodeError};
 
@@ -137,7 +468,7 @@ mod tests {
         ]
     }
 
-    fn testing_png() -> Png {
+    fn testing_png() -> Png<'static> {
         let chunks = testing_chunks();
         Png::from_chunks(chunks)
     }
@@ -239,6 +570,38 @@ mod tests {
         assert_eq!(&chunk.data_as_string().unwrap(), "Message");
     }
 
+    #[test]
+    fn test_insert_chunk() {
+        let mut png = Png::try_from(&PNG_FILE[..]).unwrap();
+        png.insert_chunk(
+            chunk_from_strings("teSt", "Before IEND").unwrap(),
+            ChunkPlacement::BeforeIend,
+        );
+        png.insert_chunk(
+            chunk_from_strings("moRe", "Before IDAT").unwrap(),
+            ChunkPlacement::BeforeIdat,
+        );
+
+        let types: Vec<String> = png.chunks().map(|c| c.chunk_type().to_string()).collect();
+        let n = types.len();
+        assert_eq!(&types[n - 2..], ["teSt", "IEND"]);
+        let idat = types.iter().position(|t| t == "IDAT").unwrap();
+        assert_eq!(types[idat - 1], "moRe");
+    }
+
+    #[test]
+    fn test_insert_chunk_without_iend() {
+        let mut png = testing_png();
+        png.insert_chunk(
+            chunk_from_strings("TeSt", "Message").unwrap(),
+            ChunkPlacement::BeforeIdat,
+        );
+        assert_eq!(
+            png.chunks().last().unwrap().chunk_type().to_string(),
+            "TeSt"
+        );
+    }
+
     #[test]
     fn test_remove_chunk() {
         let mut png = testing_png();
@@ -248,6 +611,70 @@ mod tests {
         assert!(chunk.is_none());
     }
 
+    #[test]
+    fn test_retain_and_replace_chunks() {
+        let mut png = testing_png();
+        let removed = png.retain_chunks(|c| c.chunk_type().to_string() != "miDl");
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].0, 1);
+        assert_eq!(png.chunks().len(), 2);
+
+        let old = png.replace_chunk(0, chunk_from_strings("TeSt", "New").unwrap());
+        assert_eq!(&old.chunk_type().to_string(), "FrSt");
+        assert_eq!(png.chunks().next().unwrap().data(), b"New");
+    }
+
+    #[test]
+    fn test_image_change_drops_unsafe_to_copy() {
+        let mut png = testing_png();
+        png.append_chunk(chunk_from_strings("IDAT", "old image data").unwrap());
+        png.append_chunk(chunk_from_strings("unKN", "unknown, unsafe").unwrap());
+        png.append_chunk(chunk_from_strings("saFe", "unknown, safe").unwrap());
+        png.append_chunk(chunk_from_strings("sRGB", "known, unsafe").unwrap());
+
+        png.append_chunk(chunk_from_strings("teST", "not image data").unwrap());
+        assert!(png.dropped_chunks().is_empty());
+
+        png.insert_chunk(
+            chunk_from_strings("IDAT", "new image data").unwrap(),
+            ChunkPlacement::BeforeIend,
+        );
+        let dropped: Vec<_> = png
+            .dropped_chunks()
+            .iter()
+            .map(|c| c.chunk_type().to_string())
+            .collect();
+        assert_eq!(dropped, ["unKN", "teST"]);
+        assert!(png.chunk_by_type("saFe").is_some());
+        assert!(png.chunk_by_type("sRGB").is_some());
+    }
+
+    #[test]
+    fn test_build_keeps_unsafe_to_copy() {
+        let mut png = Png::from_chunks(vec![]);
+        png.append_chunk(chunk_from_strings("IHDR", "header").unwrap());
+        png.append_chunk(chunk_from_strings("prIV", "unknown, unsafe").unwrap());
+        png.append_chunk(chunk_from_strings("IDAT", "image data").unwrap());
+        png.insert_chunk(
+            chunk_from_strings("PLTE", "palette").unwrap(),
+            ChunkPlacement::BeforeIdat,
+        );
+        assert!(png.dropped_chunks().is_empty());
+        assert!(png.chunk_by_type("prIV").is_some());
+    }
+
+    #[test]
+    fn test_remove_image_chunk_drops_unsafe_to_copy() {
+        let mut png = Png::try_from(&PNG_FILE[..]).unwrap();
+        png.insert_chunk(
+            chunk_from_strings("unKN", "unsafe").unwrap(),
+            ChunkPlacement::BeforeIend,
+        );
+        png.remove_chunk("IDAT").unwrap();
+        assert_eq!(png.dropped_chunks().len(), 1);
+        assert!(png.chunk_by_type("unKN").is_none());
+    }
+
     #[test]
     fn test_png_from_image_file() {
         let png = Png::try_from(&PNG_FILE[..]);
@@ -262,6 +689,166 @@ mod tests {
         assert_eq!(actual, expected);
     }
 
+    #[test]
+    fn test_parsed_chunks_stay_borrowed() {
+        let mut png = Png::try_from(&PNG_FILE[..]).unwrap();
+        let parsed = png.chunks().len();
+        assert_eq!(png.borrowed_chunk_count(), parsed);
+
+        png.append_chunk(chunk_from_strings("TeSt", "Message").unwrap());
+        assert_eq!(png.borrowed_chunk_count(), parsed);
+        assert_eq!(png.chunks().len(), parsed + 1);
+
+        let png = png.into_owned();
+        assert_eq!(png.borrowed_chunk_count(), 0);
+        assert_eq!(&png.as_bytes()[..PNG_FILE.len()], &PNG_FILE[..]);
+    }
+
+    #[test]
+    fn test_chunk_offsets() {
+        let png = Png::try_from(&PNG_FILE[..]).unwrap();
+        let ihdr = png.chunks().next().unwrap();
+        assert_eq!(ihdr.span(), Some(8..33));
+
+        let iend = png.chunk_by_type("IEND").unwrap();
+        assert_eq!(iend.span().unwrap().end, PNG_FILE.len() as u64);
+        assert!(format!("{png}").contains("Offset: 8 (0x8)"));
+
+        let mut png = testing_png();
+        png.append_chunk(chunk_from_strings("TeSt", "Message").unwrap());
+        assert!(png.chunks().all(|c| c.offset().is_none()));
+    }
+
+    #[test]
+    fn test_error_offset() {
+        let mut bytes = PNG_FILE.to_vec();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+
+        let err = Png::try_from(bytes.as_ref()).unwrap_err();
+        assert_eq!(err.offset(), (PNG_FILE.len() - 12) as u64);
+    }
+
+    #[test]
+    fn test_from_bytes_unverified() {
+        let mut bytes = PNG_FILE.to_vec();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+
+        assert!(Png::try_from(bytes.as_ref()).is_err());
+
+        let png = Png::from_bytes_unverified(bytes.as_ref()).unwrap();
+        let iend = png.chunk_by_type("IEND").unwrap();
+        assert!(iend.verify_crc().is_err());
+    }
+
+    #[test]
+    fn test_trailing_data() {
+        let mut bytes = PNG_FILE.to_vec();
+        let hidden = chunk_from_strings("hiDe", "after IEND").unwrap();
+        bytes.extend(hidden.as_bytes());
+        bytes.extend(b"junk");
+
+        let mut png = Png::try_from(bytes.as_ref()).unwrap();
+        assert_eq!(
+            png.chunks().last().unwrap().chunk_type().to_string(),
+            "IEND"
+        );
+        assert_eq!(png.trailing_data(), &bytes[PNG_FILE.len()..]);
+        assert_eq!(png.as_bytes(), bytes);
+
+        let mut trailing = png.trailing_chunks();
+        assert_eq!(trailing.next().unwrap().unwrap().data(), b"after IEND");
+        assert!(trailing.next().unwrap().is_err());
+
+        assert_eq!(png.strip_trailing_data(), &bytes[PNG_FILE.len()..]);
+        assert_eq!(png.as_bytes(), PNG_FILE);
+        png.set_trailing_data(b"more".to_vec());
+        assert_eq!(png.into_owned().trailing_data(), b"more");
+    }
+
+    #[test]
+    fn test_append_trailing_chunk() {
+        let mut bytes = PNG_FILE.to_vec();
+        bytes.extend(b"junk");
+        let mut png = Png::try_from(bytes.as_ref()).unwrap();
+
+        png.append_trailing_chunk(&chunk_from_strings("ruSt", "first").unwrap());
+        png.append_trailing_chunk(&chunk_from_strings("ruSt", "second").unwrap());
+        let hidden: Vec<_> = png
+            .trailing_chunks()
+            .map_while(Result::ok)
+            .map(|c| c.data().to_vec())
+            .collect();
+        assert_eq!(hidden, [b"first".to_vec(), b"second".to_vec()]);
+        assert!(png.trailing_data().ends_with(b"junk"));
+
+        // The hidden chunks survive a round trip through bytes
+        let bytes = png.as_bytes();
+        let png = Png::try_from(bytes.as_ref()).unwrap();
+        assert_eq!(png.trailing_chunks().map_while(Result::ok).count(), 2);
+    }
+
+    #[test]
+    fn test_from_bytes_lazy() {
+        let mut bytes = PNG_FILE.to_vec();
+        let png = Png::try_from(bytes.as_ref()).unwrap();
+        let idat = png.chunk_by_type("IDAT").unwrap().offset().unwrap() as usize;
+        bytes[idat + 20] ^= 1;
+
+        assert!(Png::try_from(bytes.as_ref()).is_err());
+        let png = Png::from_bytes_lazy(bytes.as_ref()).unwrap();
+        let idat = png.chunk_by_type("IDAT").unwrap();
+        assert!(idat.verify_crc().is_err());
+        assert_eq!(png.as_bytes(), bytes);
+
+        // Other chunks are still checked, here the IHDR data
+        bytes[16] ^= 1;
+        assert!(Png::from_bytes_lazy(bytes.as_ref()).is_err());
+    }
+
+    #[test]
+    fn test_from_bytes_with_limits() {
+        let limits = DecodeLimits::default();
+        let png = Png::from_bytes_with_limits(&PNG_FILE[..], &limits).unwrap();
+        assert_eq!(png.as_bytes(), PNG_FILE);
+
+        let small = DecodeLimits {
+            max_total_size: PNG_FILE.len() as u64 - 1,
+            ..limits
+        };
+        assert!(matches!(
+            Png::from_bytes_with_limits(&PNG_FILE[..], &small),
+            Err(PngDecodeError::LimitExceeded(LimitError::TotalSize { .. }))
+        ));
+
+        let small = DecodeLimits {
+            max_chunk_length: 1000,
+            ..limits
+        };
+        let err = Png::from_bytes_with_limits(&PNG_FILE[..], &small).unwrap_err();
+        assert!(matches!(
+            err,
+            PngDecodeError::ChunkDecode(ChunkDecodeError::LimitExceeded {
+                err: LimitError::ChunkLength { .. },
+                ..
+            })
+        ));
+        assert!(err.offset() > 8);
+
+        let header = crate::image::ImageHeader::from_png(&png).unwrap();
+        let small = DecodeLimits {
+            max_width: header.width - 1,
+            ..limits
+        };
+        assert!(matches!(
+            Png::from_bytes_with_limits(&PNG_FILE[..], &small),
+            Err(PngDecodeError::LimitExceeded(
+                LimitError::ImageDimensions { .. }
+            ))
+        ));
+    }
+
     #[test]
     fn test_png_trait_impls() {
         let chunk_bytes: Vec<u8> = testing_chunks()
@@ -0,0 +1,768 @@
+use std::{
+    fmt::{self, Display},
+    io::{self, Write},
+};
+
+use clap::ValueEnum;
+use serde::Serialize;
+
+use crate::{
+    bitfix::{self, BitFixOptions},
+    chunk::{self, Chunk, CRC_SIZE, LEN_SIZE, MAX_LEN, MIN_CHUNK_SIZE},
+    chunk_type::{ChunkType, CHUNK_TYPE_SIZE},
+    image::{self, ImageHeader},
+    limits::DecodeLimits,
+    png::Png,
+    report::{self, OutputFormat, SCHEMA_VERSION},
+};
+
+/// How far a length field may be off for `repair` to correct it.
+pub const MAX_LENGTH_DELTA: u32 = 16;
+
+#[derive(Debug)]
+pub enum RepairError {
+    /// The file does not start with a PNG signature followed by an IHDR chunk.
+    NotPng,
+}
+
+impl Display for RepairError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotPng => writeln!(f, "Not a PNG file: no signature or IHDR chunk found"),
+        }
+    }
+}
+
+/// What `repair` does with bytes after IEND.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum TrailingData {
+    /// Remove them
+    #[default]
+    Drop,
+    /// Keep them after IEND
+    Keep,
+}
+
+/// How `repair` fixes a PNG file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RepairOptions {
+    pub trailing: TrailingData,
+    /// Search for flipped bits that explain a wrong CRC instead of only recomputing it.
+    pub fix_bits: Option<BitFixOptions>,
+    /// Keep the data of an IDAT chunk cut off by the end of the file, and pad image data
+    /// that ends early with blank scanlines.
+    pub salvage: bool,
+}
+
+/// A bit flipped back by `repair`, at a byte offset in the damaged file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CorrectedBit {
+    pub offset: u64,
+    /// The bit within the byte, counting from the least significant.
+    pub bit: u8,
+}
+
+/// A change made by `repair`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "fix", rename_all = "snake_case")]
+pub enum FixKind {
+    SignatureRestored,
+    /// CRCs are 8 lowercase hex digits.
+    CrcCorrected {
+        chunk_type: String,
+        old_crc: String,
+        new_crc: String,
+    },
+    LengthCorrected {
+        chunk_type: String,
+        old_length: u32,
+        new_length: u32,
+    },
+    /// Flipped bits in the chunk type or data were found with the CRC and flipped back.
+    BitsCorrected {
+        chunk_type: String,
+        bits: Vec<CorrectedBit>,
+    },
+    /// Bytes that are not part of any chunk were skipped to reach the next chunk.
+    BytesSkipped {
+        length: u64,
+    },
+    /// The rest of the file could not be read as chunks and was removed.
+    UnreadableDataDropped {
+        length: u64,
+    },
+    /// An IDAT chunk cut off by the end of the file was kept with the data that was there.
+    TruncatedDataKept {
+        chunk_type: String,
+        length: u64,
+    },
+    /// The image data ended early. The complete scanlines were kept, the rest were padded
+    /// with blank ones and the image data was compressed again into a single IDAT chunk.
+    ImageDataSalvaged {
+        scanlines: u64,
+        total_scanlines: u64,
+    },
+    IendAdded,
+    TrailingDataDropped {
+        length: u64,
+    },
+    TrailingDataKept {
+        length: u64,
+    },
+}
+
+/// A change made by `repair`, at a byte offset in the damaged file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Fix {
+    pub offset: u64,
+    #[serde(flatten)]
+    pub kind: FixKind,
+}
+
+impl Display for Fix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#x}: ", self.offset)?;
+        match &self.kind {
+            FixKind::SignatureRestored => writeln!(f, "Restored PNG signature"),
+            FixKind::CrcCorrected {
+                chunk_type,
+                old_crc,
+                new_crc,
+            } => writeln!(f, "Corrected CRC of {chunk_type}: {old_crc} -> {new_crc}"),
+            FixKind::LengthCorrected {
+                chunk_type,
+                old_length,
+                new_length,
+            } => writeln!(
+                f,
+                "Corrected length of {chunk_type}: {old_length} -> {new_length}"
+            ),
+            FixKind::BitsCorrected { chunk_type, bits } => {
+                let bits: Vec<_> = bits
+                    .iter()
+                    .map(|b| format!("{:#x} bit {}", b.offset, b.bit))
+                    .collect();
+                writeln!(
+                    f,
+                    "Corrected flipped bits in {chunk_type}: {}",
+                    bits.join(", ")
+                )
+            }
+            FixKind::BytesSkipped { length } => {
+                writeln!(f, "Skipped {length} bytes between chunks")
+            }
+            FixKind::UnreadableDataDropped { length } => {
+                writeln!(f, "Dropped {length} unreadable bytes")
+            }
+            FixKind::TruncatedDataKept { chunk_type, length } => {
+                writeln!(f, "Kept {length} bytes of truncated {chunk_type}")
+            }
+            FixKind::ImageDataSalvaged {
+                scanlines,
+                total_scanlines,
+            } => writeln!(
+                f,
+                "Recovered {scanlines} of {total_scanlines} scanlines, padding the rest"
+            ),
+            FixKind::IendAdded => writeln!(f, "Added missing IEND chunk"),
+            FixKind::TrailingDataDropped { length } => {
+                writeln!(f, "Dropped {length} bytes after IEND")
+            }
+            FixKind::TrailingDataKept { length } => writeln!(f, "Kept {length} bytes after IEND"),
+        }
+    }
+}
+
+/// Every change made by `repair`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct RepairReport {
+    pub fixes: Vec<Fix>,
+}
+
+impl RepairReport {
+    /// Returns true if the file was left as it was. Kept trailing data is not a change.
+    pub fn is_unchanged(&self) -> bool {
+        self.fixes
+            .iter()
+            .all(|fix| matches!(fix.kind, FixKind::TrailingDataKept { .. }))
+    }
+
+    fn push(&mut self, offset: u64, kind: FixKind) {
+        self.fixes.push(Fix { offset, kind });
+    }
+}
+
+/// A chunk found at some offset, with the length its data turned out to have.
+struct Candidate {
+    chunk_type: [u8; CHUNK_TYPE_SIZE],
+    length: u32,
+    crc_matches: bool,
+}
+
+fn is_valid_type(bytes: &[u8]) -> bool {
+    <[u8; CHUNK_TYPE_SIZE]>::try_from(bytes)
+        .ok()
+        .and_then(|bytes| ChunkType::try_from(bytes).ok())
+        .is_some_and(|t| t.is_reserved_bit_valid())
+}
+
+fn read_u32(bytes: &[u8], pos: usize) -> Option<u32> {
+    let bytes = bytes.get(pos..pos + 4)?;
+    Some(u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+/// Reads the chunk at `pos` as if its data were `length` bytes long. Returns `None` if it
+/// does not fit in the file.
+fn candidate(bytes: &[u8], pos: usize, length: u32) -> Option<Candidate> {
+    let data_start = pos + LEN_SIZE + CHUNK_TYPE_SIZE;
+    let data_end = data_start.checked_add(length as usize)?;
+    let given_crc = read_u32(bytes, data_end)?;
+    let chunk_type = bytes[pos + LEN_SIZE..data_start].try_into().unwrap();
+    Some(Candidate {
+        chunk_type,
+        length,
+        crc_matches: chunk::checksum(&chunk_type, &bytes[data_start..data_end]) == given_crc,
+    })
+}
+
+/// Returns true if another chunk, or the end of the file, follows a chunk of this length.
+fn next_is_chunk(bytes: &[u8], pos: usize, length: u32) -> bool {
+    let end = pos + MIN_CHUNK_SIZE + length as usize;
+    end == bytes.len()
+        || bytes
+            .get(end + LEN_SIZE..end + 8)
+            .is_some_and(is_valid_type)
+}
+
+/// Finds the length of the chunk at `pos`. The stated length is kept if the CRC matches or
+/// another chunk follows it; otherwise nearby lengths are tried, first for a matching CRC
+/// and then for a following chunk. With `fix_bits`, lengths with a single flipped bit are
+/// tried for a matching CRC too, and a chunk with an invalid type is kept at its stated
+/// length in case a flipped bit in the type can be corrected.
+fn find_chunk(bytes: &[u8], pos: usize, fix_bits: bool) -> Option<Candidate> {
+    let stated = read_u32(bytes, pos)?;
+    let stated_chunk = candidate(bytes, pos, stated);
+    if !bytes
+        .get(pos + LEN_SIZE..pos + 8)
+        .is_some_and(is_valid_type)
+    {
+        return stated_chunk.filter(|_| fix_bits && next_is_chunk(bytes, pos, stated));
+    }
+    if let Some(chunk) = stated_chunk.filter(|c| c.crc_matches || next_is_chunk(bytes, pos, stated))
+    {
+        return Some(chunk);
+    }
+
+    let lengths: Vec<u32> = (0..=MAX_LENGTH_DELTA)
+        .flat_map(|delta| [stated.checked_add(delta), stated.checked_sub(delta)])
+        .flatten()
+        .filter(|&length| length as usize <= MAX_LEN)
+        .collect();
+    let flipped_lengths = (0..u32::BITS)
+        .map(|bit| stated ^ (1 << bit))
+        .filter(|&length| fix_bits && length as usize <= MAX_LEN);
+    let mut candidates = lengths
+        .iter()
+        .copied()
+        .chain(flipped_lengths)
+        .filter_map(|length| candidate(bytes, pos, length));
+    if let Some(chunk) = candidates.find(|c| c.crc_matches) {
+        return Some(chunk);
+    }
+    lengths
+        .iter()
+        .filter(|&&length| next_is_chunk(bytes, pos, length))
+        .find_map(|&length| candidate(bytes, pos, length))
+}
+
+/// Reads the chunk at `pos`, correcting its length and CRC, and with `fix_bits`, flipped
+/// bits in its type and data. Returns the chunk, the fixes made and where the next chunk
+/// starts, or `None` if no chunk could be read.
+fn read_chunk(
+    bytes: &[u8],
+    pos: usize,
+    fix_bits: Option<&BitFixOptions>,
+) -> Option<(Chunk, Vec<FixKind>, usize)> {
+    let found = find_chunk(bytes, pos, fix_bits.is_some())?;
+    let mut fixes = Vec::new();
+    let stated = read_u32(bytes, pos).unwrap();
+    if found.length != stated {
+        fixes.push(FixKind::LengthCorrected {
+            chunk_type: found.chunk_type.escape_ascii().to_string(),
+            old_length: stated,
+            new_length: found.length,
+        });
+    }
+
+    let data_end = pos + LEN_SIZE + CHUNK_TYPE_SIZE + found.length as usize;
+    let stored_crc = read_u32(bytes, data_end).unwrap();
+    let mut message = bytes[pos + LEN_SIZE..data_end].to_vec();
+    // A CRC that is off by a single bit is more likely damaged than the chunk
+    let flipped = match fix_bits {
+        Some(options) if !found.crc_matches && !bitfix::is_crc_bit_flip(&message, stored_crc) => {
+            let mut corrected = message.clone();
+            match bitfix::correct_bits(&mut corrected, stored_crc, options) {
+                Some(flipped) if is_valid_type(&corrected[..CHUNK_TYPE_SIZE]) => {
+                    message = corrected;
+                    Some(flipped)
+                }
+                _ => None,
+            }
+        }
+        _ => None,
+    };
+
+    let (chunk_type, data) = message.split_at(CHUNK_TYPE_SIZE);
+    let chunk_type = ChunkType::try_from(<[u8; CHUNK_TYPE_SIZE]>::try_from(chunk_type).unwrap());
+    let chunk = Chunk::new(chunk_type.ok()?, data.to_vec());
+    let chunk_type = chunk.chunk_type().to_string();
+    match flipped {
+        Some(flipped) => fixes.push(FixKind::BitsCorrected {
+            chunk_type,
+            bits: flipped
+                .iter()
+                .map(|flip| CorrectedBit {
+                    offset: (pos + LEN_SIZE + flip.byte) as u64,
+                    bit: flip.bit,
+                })
+                .collect(),
+        }),
+        None if !found.crc_matches => fixes.push(FixKind::CrcCorrected {
+            chunk_type,
+            old_crc: format!("{stored_crc:08x}"),
+            new_crc: format!("{:08x}", chunk.crc()),
+        }),
+        None => {}
+    }
+
+    Some((chunk, fixes, data_end + CRC_SIZE))
+}
+
+/// Finds the next offset after `pos` where a chunk with a matching CRC starts.
+fn resync(bytes: &[u8], pos: usize) -> Option<usize> {
+    (pos + 1..bytes.len().saturating_sub(MIN_CHUNK_SIZE - 1)).find(|&start| {
+        is_valid_type(&bytes[start + LEN_SIZE..start + 8])
+            && read_u32(bytes, start)
+                .and_then(|length| candidate(bytes, start, length))
+                .is_some_and(|c| c.crc_matches)
+    })
+}
+
+/// Returns the data of an IDAT chunk at `pos` that runs past the end of the file.
+fn truncated_idat(bytes: &[u8], pos: usize) -> Option<&[u8]> {
+    let data_start = pos + LEN_SIZE + CHUNK_TYPE_SIZE;
+    let length = read_u32(bytes, pos)? as usize;
+    match bytes.get(pos + LEN_SIZE..data_start) {
+        Some(b"IDAT") if data_start + length + CRC_SIZE > bytes.len() => {
+            Some(&bytes[data_start..(data_start + length).min(bytes.len())])
+        }
+        _ => None,
+    }
+}
+
+fn is_idat(chunk: &Chunk) -> bool {
+    chunk.chunk_type().to_string() == "IDAT"
+}
+
+/// Inflates as much of the image data as possible, keeps the complete scanlines and pads
+/// the rest with zeros, which are blank scanlines with no filter. The IDAT chunks are
+/// replaced by a single one holding the result. Returns the number of scanlines recovered
+/// and in total, or `None` if the image data is already complete, there is no valid IHDR
+/// or IDAT chunk, or the image is larger than the default `DecodeLimits` allow, since a
+/// damaged IHDR could otherwise make the padding take any amount of memory.
+fn salvage_image_data(chunks: &mut Vec<Chunk>) -> Option<(u64, u64)> {
+    let ihdr = chunks
+        .iter()
+        .find(|c| c.chunk_type().to_string() == "IHDR")?;
+    let header = ImageHeader::try_from(ihdr.data()).ok()?;
+    DecodeLimits::default().check_header(&header).ok()?;
+    let first_idat = chunks.iter().position(is_idat)?;
+    let idat: Vec<u8> = chunks
+        .iter()
+        .filter(|c| is_idat(c))
+        .flat_map(|c| c.data().iter().copied())
+        .collect();
+
+    let expected = header.expected_data_len();
+    let summary = image::inflate_len(&idat, expected).ok();
+    if summary.is_some_and(|s| s.complete && s.inflated == expected) {
+        return None;
+    }
+
+    let mut data = image::inflate_partial(&idat, expected);
+    let (scanlines, used) = header.complete_scanlines(data.len() as u64);
+    data.truncate(used as usize);
+    data.resize(expected as usize, 0);
+
+    chunks.retain(|c| !is_idat(c));
+    let data = image::deflate(&data, 9);
+    chunks.insert(
+        first_idat,
+        Chunk::new(ChunkType::try_from(*b"IDAT").unwrap(), data),
+    );
+    Some((scanlines, header.scanlines()))
+}
+
+/// Repairs a damaged PNG file and returns the repaired file with a report of every fix.
+/// Wrong CRCs are recomputed, or with `fix_bits`, the flipped bits that caused them are
+/// searched for first. Length fields off by up to `MAX_LENGTH_DELTA` bytes are corrected
+/// using the CRC or the next valid chunk type. Bytes between chunks are skipped, a missing
+/// IEND chunk is added, and bytes after IEND are dropped or kept. With `salvage`, image data
+/// cut off by the end of the file is recovered as far as it goes and padded to a full image.
+pub fn repair(
+    bytes: &[u8],
+    options: &RepairOptions,
+) -> Result<(Vec<u8>, RepairReport), RepairError> {
+    let mut report = RepairReport::default();
+    let signature_len = Png::STANDARD_HEADER.len();
+    if bytes.get(signature_len + LEN_SIZE..signature_len + 8) != Some(b"IHDR") {
+        return Err(RepairError::NotPng);
+    }
+    if bytes[..signature_len] != Png::STANDARD_HEADER {
+        report.push(0, FixKind::SignatureRestored);
+    }
+
+    let mut chunks = Vec::new();
+    let mut rest: &[u8] = &[];
+    let mut pos = signature_len;
+    let mut idat_offset = None;
+    while pos < bytes.len() {
+        let offset = pos as u64;
+        if chunks
+            .last()
+            .is_some_and(|c: &Chunk| c.chunk_type().to_string() == "IEND")
+        {
+            rest = &bytes[pos..];
+            break;
+        }
+
+        let Some((chunk, fixes, next)) = read_chunk(bytes, pos, options.fix_bits.as_ref()) else {
+            if let Some(data) = truncated_idat(bytes, pos).filter(|_| options.salvage) {
+                let length = data.len() as u64;
+                let chunk_type = String::from("IDAT");
+                report.push(offset, FixKind::TruncatedDataKept { chunk_type, length });
+                chunks.push(Chunk::new(
+                    ChunkType::try_from(*b"IDAT").unwrap(),
+                    data.to_vec(),
+                ));
+                idat_offset.get_or_insert(offset);
+                break;
+            }
+            match resync(bytes, pos) {
+                Some(next) => {
+                    let length = (next - pos) as u64;
+                    report.push(offset, FixKind::BytesSkipped { length });
+                    pos = next;
+                    continue;
+                }
+                None => {
+                    let length = (bytes.len() - pos) as u64;
+                    report.push(offset, FixKind::UnreadableDataDropped { length });
+                    break;
+                }
+            }
+        };
+
+        for fix in fixes {
+            report.push(offset, fix);
+        }
+        if is_idat(&chunk) {
+            idat_offset.get_or_insert(offset);
+        }
+        chunks.push(chunk);
+        pos = next;
+    }
+
+    if options.salvage {
+        if let Some((scanlines, total_scanlines)) = salvage_image_data(&mut chunks) {
+            let kind = FixKind::ImageDataSalvaged {
+                scanlines,
+                total_scanlines,
+            };
+            report.push(idat_offset.unwrap_or_default(), kind);
+        }
+    }
+
+    if !chunks.iter().any(|c| c.chunk_type().to_string() == "IEND") {
+        report.push(bytes.len() as u64, FixKind::IendAdded);
+        chunks.push(Chunk::new(ChunkType::try_from(*b"IEND").unwrap(), vec![]));
+    }
+
+    let mut repaired = Png::from_chunks(chunks).as_bytes();
+    if !rest.is_empty() {
+        let offset = pos as u64;
+        let length = rest.len() as u64;
+        match options.trailing {
+            TrailingData::Drop => report.push(offset, FixKind::TrailingDataDropped { length }),
+            TrailingData::Keep => {
+                report.push(offset, FixKind::TrailingDataKept { length });
+                repaired.extend_from_slice(rest);
+            }
+        }
+    }
+
+    Ok((repaired, report))
+}
+
+#[derive(Debug, Serialize)]
+struct RepairDocument<'a> {
+    schema_version: u32,
+    file: &'a str,
+    #[serde(flatten)]
+    report: &'a RepairReport,
+}
+
+/// Writes the fixes made to a PNG file in the given format.
+pub fn write_report<W: Write>(
+    w: &mut W,
+    format: OutputFormat,
+    file: &str,
+    repair_report: &RepairReport,
+) -> io::Result<()> {
+    match format {
+        OutputFormat::Text | OutputFormat::Table => {
+            for fix in &repair_report.fixes {
+                write!(w, "{fix}")?;
+            }
+            if repair_report.fixes.is_empty() {
+                writeln!(w, "Nothing to repair")?;
+            }
+            Ok(())
+        }
+        OutputFormat::Json | OutputFormat::Ndjson => report::write_json(
+            w,
+            &RepairDocument {
+                schema_version: SCHEMA_VERSION,
+                file,
+                report: repair_report,
+            },
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    use crate::verify;
+
+    const DICE: &[u8] = include_bytes!("../tests/data/dice.png");
+
+    fn kinds(report: &RepairReport) -> Vec<&FixKind> {
+        report.fixes.iter().map(|f| &f.kind).collect()
+    }
+
+    #[test]
+    fn test_intact_png() {
+        let (repaired, report) = repair(DICE, &RepairOptions::default()).unwrap();
+        assert_eq!(repaired, DICE);
+        assert!(report.fixes.is_empty());
+    }
+
+    #[test]
+    fn test_crc_corrected() {
+        let mut bytes = DICE.to_vec();
+        // The CRC of sRGB, which starts at 33 with one byte of data
+        bytes[33 + 9] ^= 0x80;
+        bytes[..4].copy_from_slice(b"\0PNG");
+
+        let (repaired, report) = repair(&bytes, &RepairOptions::default()).unwrap();
+        assert_eq!(repaired, DICE);
+        assert_eq!(report.fixes[0].kind, FixKind::SignatureRestored);
+        assert_eq!(report.fixes[1].offset, 33);
+        assert!(matches!(
+            &report.fixes[1].kind,
+            FixKind::CrcCorrected { chunk_type, .. } if chunk_type == "sRGB"
+        ));
+    }
+
+    #[test]
+    fn test_bits_corrected() {
+        let fix_bits = RepairOptions {
+            fix_bits: Some(BitFixOptions::default()),
+            ..Default::default()
+        };
+        let mut bytes = DICE.to_vec();
+        // A bit in the IDAT data, which starts at 83, and in the type of IEND
+        bytes[83 + 8 + 1000] ^= 0x10;
+        bytes[4791 + 4] ^= 0x04;
+        // A flipped bit in the CRC of sRGB is left to be recomputed
+        bytes[33 + 9] ^= 0x01;
+
+        let (repaired, report) = repair(&bytes, &fix_bits).unwrap();
+        assert_eq!(repaired, DICE);
+        assert!(matches!(report.fixes[0].kind, FixKind::CrcCorrected { .. }));
+        assert_eq!(
+            report.fixes[1].kind,
+            FixKind::BitsCorrected {
+                chunk_type: String::from("IDAT"),
+                bits: vec![CorrectedBit {
+                    offset: 1091,
+                    bit: 4
+                }]
+            }
+        );
+        assert!(matches!(
+            &report.fixes[2].kind,
+            FixKind::BitsCorrected { bits, .. } if bits[0] == CorrectedBit { offset: 4795, bit: 2 }
+        ));
+
+        // A flipped bit in a length field
+        let mut bytes = DICE.to_vec();
+        bytes[62 + 2] ^= 0x01;
+        let (repaired, report) = repair(&bytes, &fix_bits).unwrap();
+        assert_eq!(repaired, DICE);
+        assert!(matches!(
+            report.fixes[0].kind,
+            FixKind::LengthCorrected { new_length: 9, .. }
+        ));
+    }
+
+    #[test]
+    fn test_length_corrected() {
+        let png = Png::try_from(DICE).unwrap();
+        let mut chunks: Vec<_> = png.chunks().map(|c| c.to_chunk()).collect();
+        let text = Chunk::new(
+            ChunkType::from_str("tEXt").unwrap(),
+            b"Title\0dice".to_vec(),
+        );
+        chunks.insert(4, text);
+        let original = Png::from_chunks(chunks).as_bytes();
+
+        // The tEXt chunk starts at 83, after pHYs
+        for length in [7u32, 13] {
+            let mut bytes = original.clone();
+            bytes[83..87].copy_from_slice(&length.to_be_bytes());
+            let (repaired, report) = repair(&bytes, &RepairOptions::default()).unwrap();
+            assert_eq!(repaired, original);
+            assert_eq!(
+                kinds(&report),
+                [&FixKind::LengthCorrected {
+                    chunk_type: String::from("tEXt"),
+                    old_length: length,
+                    new_length: 10
+                }]
+            );
+        }
+    }
+
+    #[test]
+    fn test_iend_and_trailing_data() {
+        let without_iend = &DICE[..DICE.len() - MIN_CHUNK_SIZE];
+        let (repaired, report) = repair(without_iend, &RepairOptions::default()).unwrap();
+        assert_eq!(repaired, DICE);
+        assert_eq!(kinds(&report), [&FixKind::IendAdded]);
+
+        let mut bytes = DICE.to_vec();
+        bytes.extend(b"junk");
+        let (repaired, report) = repair(&bytes, &RepairOptions::default()).unwrap();
+        assert_eq!(repaired, DICE);
+        assert_eq!(
+            kinds(&report),
+            [&FixKind::TrailingDataDropped { length: 4 }]
+        );
+
+        let keep = RepairOptions {
+            trailing: TrailingData::Keep,
+            ..Default::default()
+        };
+        let (repaired, report) = repair(&bytes, &keep).unwrap();
+        assert_eq!(repaired, bytes);
+        assert!(report.is_unchanged());
+    }
+
+    #[test]
+    fn test_salvage_truncated() {
+        // Cut off halfway through the IDAT data, which starts at 83
+        let truncated = &DICE[..83 + 8 + 2000];
+        let (repaired, report) = repair(truncated, &RepairOptions::default()).unwrap();
+        assert!(matches!(
+            kinds(&report)[..],
+            [FixKind::UnreadableDataDropped { .. }, FixKind::IendAdded]
+        ));
+        assert!(!verify::verify(&repaired).is_ok());
+
+        let salvage = RepairOptions {
+            salvage: true,
+            ..Default::default()
+        };
+        let (repaired, report) = repair(truncated, &salvage).unwrap();
+        assert!(verify::verify(&repaired).is_ok());
+        assert_eq!(report.fixes[0].offset, 83);
+        assert_eq!(
+            report.fixes[0].kind,
+            FixKind::TruncatedDataKept {
+                chunk_type: String::from("IDAT"),
+                length: 2000
+            }
+        );
+        let FixKind::ImageDataSalvaged {
+            scanlines,
+            total_scanlines,
+        } = report.fixes[1].kind
+        else {
+            panic!("image data not salvaged: {report:?}");
+        };
+        assert!(scanlines > 0 && scanlines < 50);
+        assert_eq!(total_scanlines, 50);
+        assert_eq!(report.fixes[2].kind, FixKind::IendAdded);
+
+        // The recovered scanlines decode to the same pixels as the original
+        let original = image::decode_pixels(&Png::try_from(DICE).unwrap()).unwrap();
+        let salvaged = image::decode_pixels(&Png::try_from(repaired.as_slice()).unwrap()).unwrap();
+        let row_len = 50 * 4;
+        let recovered = scanlines as usize * row_len;
+        assert_eq!(salvaged[..recovered], original[..recovered]);
+
+        let (repaired, report) = repair(DICE, &salvage).unwrap();
+        assert_eq!(repaired, DICE);
+        assert!(report.fixes.is_empty());
+    }
+
+    #[test]
+    fn test_salvage_bogus_header() {
+        // An RGBA16 IHDR of 40000 by 2000000000 pixels, with its CRC updated
+        let mut bytes = DICE[..83 + 8 + 2000].to_vec();
+        bytes[16..20].copy_from_slice(&40_000u32.to_be_bytes());
+        bytes[20..24].copy_from_slice(&2_000_000_000u32.to_be_bytes());
+        bytes[24] = 16;
+        let crc = chunk::checksum(b"IHDR", &bytes[16..29]);
+        bytes[29..33].copy_from_slice(&crc.to_be_bytes());
+
+        let salvage = RepairOptions {
+            salvage: true,
+            ..Default::default()
+        };
+        let (_, report) = repair(&bytes, &salvage).unwrap();
+        assert!(matches!(
+            kinds(&report)[..],
+            [FixKind::TruncatedDataKept { .. }, FixKind::IendAdded]
+        ));
+    }
+
+    #[test]
+    fn test_garbage_skipped() {
+        let mut bytes = DICE[..62].to_vec();
+        bytes.extend([0xff; 5]);
+        bytes.extend(&DICE[62..]);
+
+        let (repaired, report) = repair(&bytes, &RepairOptions::default()).unwrap();
+        assert!(verify::verify(&repaired).is_ok());
+        assert_eq!(
+            report.fixes,
+            [Fix {
+                offset: 62,
+                kind: FixKind::BytesSkipped { length: 5 }
+            }]
+        );
+    }
+
+    #[test]
+    fn test_not_png() {
+        assert!(matches!(
+            repair(b"GIF89a", &RepairOptions::default()),
+            Err(RepairError::NotPng)
+        ));
+    }
+}
@@ -0,0 +1,446 @@
+use std::{
+    fmt::Write as _,
+    io::{self, Write},
+};
+
+use clap::ValueEnum;
+use serde::Serialize;
+
+use crate::{chunk::ChunkRef, png::Png, selector::ChunkSelector};
+
+/// The version of the JSON schema emitted by the `json` and `ndjson` formats, documented in
+/// the README. It is bumped whenever a field is removed or changes meaning.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// How a command prints its results.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum OutputFormat {
+    /// Human readable text
+    #[default]
+    Text,
+    /// Aligned columns, one row per chunk
+    Table,
+    /// A single JSON document
+    Json,
+    /// One JSON object per line
+    Ndjson,
+}
+
+/// The first bytes of a chunk's data.
+#[derive(Debug, Clone, Serialize)]
+pub struct DataPreview {
+    /// The previewed bytes as lowercase hex.
+    pub hex: String,
+    /// The previewed bytes as text, if they are valid UTF-8.
+    pub text: Option<String>,
+    /// Whether the data is longer than the preview.
+    pub truncated: bool,
+}
+
+impl DataPreview {
+    pub fn new(data: &[u8], max_len: usize) -> Self {
+        let bytes = &data[..data.len().min(max_len)];
+        Self {
+            hex: to_hex(bytes),
+            text: std::str::from_utf8(bytes).ok().map(String::from),
+            truncated: data.len() > max_len,
+        }
+    }
+}
+
+/// Formats bytes as lowercase hex without separators.
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, b| {
+        let _ = write!(hex, "{b:02x}");
+        hex
+    })
+}
+
+/// Description of a single chunk in the JSON schema.
+#[derive(Debug, Clone, Serialize)]
+pub struct ChunkRecord {
+    /// Byte offset of the chunk's length field in the file, if the chunk was parsed from one.
+    pub offset: Option<u64>,
+    pub length: u32,
+    #[serde(rename = "type")]
+    pub chunk_type: String,
+    pub critical: bool,
+    pub public: bool,
+    pub reserved_bit_valid: bool,
+    pub safe_to_copy: bool,
+    /// The stored CRC as 8 lowercase hex digits.
+    pub crc: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data_preview: Option<DataPreview>,
+}
+
+impl ChunkRecord {
+    /// Describes a chunk, including a preview of up to `preview` bytes of its data.
+    pub fn new(chunk: &ChunkRef, preview: Option<usize>) -> Self {
+        let chunk_type = chunk.chunk_type();
+        Self {
+            offset: chunk.offset(),
+            length: chunk.length(),
+            chunk_type: chunk_type.to_string(),
+            critical: chunk_type.is_critical(),
+            public: chunk_type.is_public(),
+            reserved_bit_valid: chunk_type.is_reserved_bit_valid(),
+            safe_to_copy: chunk_type.is_safe_to_copy(),
+            crc: format!("{:08x}", chunk.crc()),
+            data_preview: preview.map(|len| DataPreview::new(chunk.data(), len)),
+        }
+    }
+
+    /// The property bits of the chunk type as four characters, `C`, `P`, `R` and `S` for
+    /// critical, public, reserved bit valid and safe-to-copy, with `-` for unset bits.
+    pub fn flags(&self) -> String {
+        [
+            (self.critical, 'C'),
+            (self.public, 'P'),
+            (self.reserved_bit_valid, 'R'),
+            (self.safe_to_copy, 'S'),
+        ]
+        .iter()
+        .map(|&(set, c)| if set { c } else { '-' })
+        .collect()
+    }
+}
+
+/// Description of the bytes after the IEND chunk in the JSON schema.
+#[derive(Debug, Clone, Serialize)]
+pub struct TrailingRecord {
+    pub length: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data_preview: Option<DataPreview>,
+}
+
+#[derive(Debug, Serialize)]
+struct ChunkList<'a> {
+    schema_version: u32,
+    file: &'a str,
+    chunks: &'a [ChunkRecord],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    trailing_data: Option<&'a TrailingRecord>,
+}
+
+#[derive(Debug, Serialize)]
+struct TrailingLine<'a> {
+    schema_version: u32,
+    file: &'a str,
+    trailing_data: &'a TrailingRecord,
+}
+
+#[derive(Debug, Serialize)]
+struct ChunkLine<'a> {
+    schema_version: u32,
+    file: &'a str,
+    #[serde(flatten)]
+    chunk: &'a ChunkRecord,
+}
+
+/// Writes JSON to `w` followed by a newline.
+pub fn write_json<W: Write, T: Serialize>(w: &mut W, value: &T) -> io::Result<()> {
+    serde_json::to_writer(&mut *w, value)?;
+    writeln!(w)
+}
+
+/// Writes a chunk table with one row per record.
+pub fn write_table<W: Write>(w: &mut W, records: &[ChunkRecord]) -> io::Result<()> {
+    writeln!(
+        w,
+        "{:>12}  {:>10}  {:<4}  {:<5}  {:<8}",
+        "OFFSET", "LENGTH", "TYPE", "FLAGS", "CRC"
+    )?;
+    for record in records {
+        let offset = record.offset.map_or(String::from("-"), |o| o.to_string());
+        writeln!(
+            w,
+            "{:>12}  {:>10}  {:<4}  {:<5}  {:<8}",
+            offset,
+            record.length,
+            record.chunk_type,
+            record.flags(),
+            record.crc
+        )?;
+    }
+    Ok(())
+}
+
+/// Writes the chunks of a PNG in the given format, or only the selected ones if a selector is
+/// given. Bytes after IEND are described after the chunks unless a selector is given.
+pub fn write_chunks<W: Write>(
+    w: &mut W,
+    format: OutputFormat,
+    file: &str,
+    png: &Png,
+    select: Option<&ChunkSelector>,
+    preview: Option<usize>,
+) -> io::Result<()> {
+    let chunks: Vec<_> = png
+        .chunks()
+        .enumerate()
+        .filter(|(index, chunk)| select.is_none_or(|s| s.matches(*index, chunk)))
+        .map(|(_, chunk)| chunk)
+        .collect();
+    let records: Vec<_> = chunks
+        .iter()
+        .map(|chunk| ChunkRecord::new(chunk, preview))
+        .collect();
+    let trailing = match png.trailing_data() {
+        [] => None,
+        _ if select.is_some() => None,
+        data => Some(TrailingRecord {
+            length: data.len(),
+            data_preview: preview.map(|len| DataPreview::new(data, len)),
+        }),
+    };
+
+    match format {
+        OutputFormat::Text => {
+            for chunk in &chunks {
+                write!(w, "{chunk}")?;
+            }
+            if let Some(trailing) = &trailing {
+                writeln!(w, "Trailing data: {} bytes after IEND", trailing.length)?;
+            }
+            writeln!(w)
+        }
+        OutputFormat::Table => {
+            write_table(w, &records)?;
+            if let Some(trailing) = &trailing {
+                writeln!(w, "\n{} bytes after IEND", trailing.length)?;
+            }
+            Ok(())
+        }
+        OutputFormat::Json => write_json(
+            w,
+            &ChunkList {
+                schema_version: SCHEMA_VERSION,
+                file,
+                chunks: &records,
+                trailing_data: trailing.as_ref(),
+            },
+        ),
+        OutputFormat::Ndjson => {
+            records.iter().try_for_each(|chunk| {
+                write_json(
+                    w,
+                    &ChunkLine {
+                        schema_version: SCHEMA_VERSION,
+                        file,
+                        chunk,
+                    },
+                )
+            })?;
+            match &trailing {
+                Some(trailing_data) => write_json(
+                    w,
+                    &TrailingLine {
+                        schema_version: SCHEMA_VERSION,
+                        file,
+                        trailing_data,
+                    },
+                ),
+                None => Ok(()),
+            }
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct DecodeResult<'a> {
+    schema_version: u32,
+    file: &'a str,
+    chunk_type: &'a str,
+    found: bool,
+    message: Option<String>,
+    chunk: Option<ChunkRecord>,
+}
+
+/// Writes the result of searching a PNG for a message in the given format.
+pub fn write_decoded<W: Write>(
+    w: &mut W,
+    format: OutputFormat,
+    file: &str,
+    chunk_type: &str,
+    chunk: Option<ChunkRef>,
+) -> io::Result<()> {
+    let message = chunk.map(|c| String::from_utf8_lossy(c.data()).into_owned());
+    match format {
+        OutputFormat::Text => match message {
+            Some(message) => writeln!(w, "Message found: {message}"),
+            None => writeln!(w, "No message found"),
+        },
+        OutputFormat::Table => {
+            let records: Vec<_> = chunk.iter().map(|c| ChunkRecord::new(c, None)).collect();
+            write_table(w, &records)?;
+            if let Some(message) = message {
+                writeln!(w, "\n{message}")?;
+            }
+            Ok(())
+        }
+        OutputFormat::Json | OutputFormat::Ndjson => write_json(
+            w,
+            &DecodeResult {
+                schema_version: SCHEMA_VERSION,
+                file,
+                chunk_type,
+                found: chunk.is_some(),
+                message,
+                chunk: chunk.map(|c| ChunkRecord::new(&c, None)),
+            },
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    use crate::{chunk::Chunk, chunk_type::ChunkType};
+
+    fn testing_png() -> Png<'static> {
+        let chunks = vec![
+            Chunk::new(ChunkType::from_str("IHDR").unwrap(), vec![0; 13]),
+            Chunk::new(
+                ChunkType::from_str("ruSt").unwrap(),
+                b"hello world".to_vec(),
+            ),
+        ];
+        Png::from_chunks(chunks)
+    }
+
+    fn render(format: OutputFormat, preview: Option<usize>) -> String {
+        let bytes = testing_png().as_bytes();
+        let png = Png::try_from(bytes.as_ref()).unwrap();
+        let mut out = Vec::new();
+        write_chunks(&mut out, format, "test.png", &png, None, preview).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_chunk_record() {
+        let png = testing_png();
+        let chunk = png.chunk_by_type("ruSt").unwrap();
+        let record = ChunkRecord::new(&chunk, Some(5));
+
+        assert_eq!(record.flags(), "--RS");
+        assert_eq!(record.crc, format!("{:08x}", chunk.crc()));
+
+        let preview = record.data_preview.unwrap();
+        assert_eq!(preview.hex, "68656c6c6f");
+        assert_eq!(preview.text.as_deref(), Some("hello"));
+        assert!(preview.truncated);
+    }
+
+    #[test]
+    fn test_json_output() {
+        let json: serde_json::Value =
+            serde_json::from_str(&render(OutputFormat::Json, None)).unwrap();
+
+        assert_eq!(json["schema_version"], SCHEMA_VERSION);
+        assert_eq!(json["file"], "test.png");
+        let chunks = json["chunks"].as_array().unwrap();
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0]["offset"], 8);
+        assert_eq!(chunks[0]["type"], "IHDR");
+        assert_eq!(chunks[0]["critical"], true);
+        assert_eq!(chunks[1]["offset"], 33);
+        assert_eq!(chunks[1]["safe_to_copy"], true);
+        assert!(chunks[1].get("data_preview").is_none());
+    }
+
+    #[test]
+    fn test_ndjson_output() {
+        let output = render(OutputFormat::Ndjson, Some(4));
+        let lines: Vec<serde_json::Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1]["schema_version"], SCHEMA_VERSION);
+        assert_eq!(lines[1]["type"], "ruSt");
+        assert_eq!(lines[1]["data_preview"]["text"], "hell");
+    }
+
+    #[test]
+    fn test_table_output() {
+        let output = render(OutputFormat::Table, None);
+        let lines: Vec<_> = output.lines().collect();
+
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].contains("OFFSET"));
+        assert!(lines[2].contains("ruSt"));
+        assert!(lines[2].contains("--RS"));
+    }
+
+    #[test]
+    fn test_selected_chunks() {
+        let png = testing_png();
+        let selector = ChunkSelector::from_str("ancillary").unwrap();
+        let mut out = Vec::new();
+        write_chunks(
+            &mut out,
+            OutputFormat::Ndjson,
+            "test.png",
+            &png,
+            Some(&selector),
+            None,
+        )
+        .unwrap();
+
+        let output = String::from_utf8(out).unwrap();
+        assert_eq!(output.lines().count(), 1);
+        assert!(output.contains("ruSt"));
+    }
+
+    #[test]
+    fn test_trailing_data() {
+        let mut png = testing_png();
+        png.append_chunk(Chunk::new(ChunkType::from_str("IEND").unwrap(), vec![]));
+        let mut bytes = png.as_bytes();
+        bytes.extend(b"after");
+        let png = Png::try_from(bytes.as_ref()).unwrap();
+        let mut out = Vec::new();
+        write_chunks(
+            &mut out,
+            OutputFormat::Json,
+            "test.png",
+            &png,
+            None,
+            Some(3),
+        )
+        .unwrap();
+
+        let json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(json["trailing_data"]["length"], 5);
+        assert_eq!(json["trailing_data"]["data_preview"]["text"], "aft");
+        assert!(
+            serde_json::from_str::<serde_json::Value>(&render(OutputFormat::Json, None))
+                .unwrap()
+                .get("trailing_data")
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_decoded_json() {
+        let png = testing_png();
+        let mut out = Vec::new();
+        write_decoded(
+            &mut out,
+            OutputFormat::Json,
+            "test.png",
+            "ruSt",
+            png.chunk_by_type("ruSt"),
+        )
+        .unwrap();
+
+        let json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(json["found"], true);
+        assert_eq!(json["message"], "hello world");
+        assert_eq!(json["chunk"]["type"], "ruSt");
+    }
+}
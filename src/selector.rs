@@ -0,0 +1,518 @@
+use std::{
+    fmt::{self, Display},
+    iter::Peekable,
+    str::{CharIndices, FromStr},
+};
+
+use crate::{chunk::ChunkRef, chunk_type::ChunkType, png::Png};
+
+/// Chunk types whose data starts with a keyword followed by a NUL byte.
+const TEXT_CHUNKS: [&str; 3] = ["tEXt", "zTXt", "iTXt"];
+
+/// A property bit of a chunk type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkFlag {
+    Critical,
+    Ancillary,
+    Public,
+    Private,
+    SafeToCopy,
+    UnsafeToCopy,
+}
+
+impl ChunkFlag {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "critical" => Self::Critical,
+            "ancillary" => Self::Ancillary,
+            "public" => Self::Public,
+            "private" => Self::Private,
+            "safe-to-copy" => Self::SafeToCopy,
+            "unsafe-to-copy" => Self::UnsafeToCopy,
+            _ => return None,
+        })
+    }
+
+    fn matches(self, chunk_type: &ChunkType) -> bool {
+        match self {
+            Self::Critical => chunk_type.is_critical(),
+            Self::Ancillary => !chunk_type.is_critical(),
+            Self::Public => chunk_type.is_public(),
+            Self::Private => !chunk_type.is_public(),
+            Self::SafeToCopy => chunk_type.is_safe_to_copy(),
+            Self::UnsafeToCopy => !chunk_type.is_safe_to_copy(),
+        }
+    }
+}
+
+/// How `size` compares a chunk's data length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+}
+
+impl Comparison {
+    fn matches(self, left: u32, right: u32) -> bool {
+        match self {
+            Self::Lt => left < right,
+            Self::Le => left <= right,
+            Self::Gt => left > right,
+            Self::Ge => left >= right,
+            Self::Eq => left == right,
+            Self::Ne => left != right,
+        }
+    }
+}
+
+/// Selects chunks of a PNG. Parsed from expressions such as `tEXt`, `t*`, `ancillary &&
+/// private`, `index:2..5`, `size>1024` or `keyword:Title`; see the README for the full
+/// syntax.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChunkSelector {
+    /// Chunks of exactly this type.
+    Type(ChunkType),
+    /// Chunks whose type matches a glob with `*`, `?` and `[...]`.
+    Glob(String),
+    Flag(ChunkFlag),
+    /// Chunks whose index is in `start..end`, with open ends when `None`.
+    Index {
+        start: Option<usize>,
+        end: Option<usize>,
+    },
+    /// Chunks whose data length compares to the given size.
+    Size(Comparison, u32),
+    /// Text chunks whose keyword matches a glob.
+    Keyword(String),
+    Not(Box<ChunkSelector>),
+    And(Box<ChunkSelector>, Box<ChunkSelector>),
+    Or(Box<ChunkSelector>, Box<ChunkSelector>),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SelectorParseError {
+    Empty,
+    UnexpectedEnd,
+    UnexpectedToken { position: usize, token: String },
+    InvalidTerm { position: usize, term: String },
+}
+
+impl Display for SelectorParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Empty => writeln!(f, "Empty chunk selector"),
+            Self::UnexpectedEnd => writeln!(f, "Chunk selector ends unexpectedly"),
+            Self::UnexpectedToken { position, token } => {
+                writeln!(
+                    f,
+                    "Unexpected {token:?} at position {position} in chunk selector"
+                )
+            }
+            Self::InvalidTerm { position, term } => {
+                writeln!(
+                    f,
+                    "Invalid term {term:?} at position {position} in chunk selector"
+                )
+            }
+        }
+    }
+}
+
+/// Matches `text` against a glob supporting `*`, `?` and character classes like `[a-z]`.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    fn class_matches(class: &[char], c: char) -> bool {
+        let (negated, class) = match class.first() {
+            Some('!' | '^') => (true, &class[1..]),
+            _ => (false, class),
+        };
+        let mut found = false;
+        let mut i = 0;
+        while i < class.len() {
+            if i + 2 < class.len() && class[i + 1] == '-' {
+                found |= (class[i]..=class[i + 2]).contains(&c);
+                i += 3;
+            } else {
+                found |= class[i] == c;
+                i += 1;
+            }
+        }
+        found != negated
+    }
+
+    fn matches(pattern: &[char], text: &[char]) -> bool {
+        match pattern.first() {
+            None => text.is_empty(),
+            Some('*') => (0..=text.len()).any(|i| matches(&pattern[1..], &text[i..])),
+            Some('?') => !text.is_empty() && matches(&pattern[1..], &text[1..]),
+            Some('[') => match pattern.iter().position(|&c| c == ']') {
+                Some(end) if end > 1 => {
+                    !text.is_empty()
+                        && class_matches(&pattern[1..end], text[0])
+                        && matches(&pattern[end + 1..], &text[1..])
+                }
+                _ => text.first() == Some(&'[') && matches(&pattern[1..], &text[1..]),
+            },
+            Some(&c) => text.first() == Some(&c) && matches(&pattern[1..], &text[1..]),
+        }
+    }
+
+    let pattern: Vec<_> = pattern.chars().collect();
+    let text: Vec<_> = text.chars().collect();
+    matches(&pattern, &text)
+}
+
+/// The keyword of a text chunk, the Latin-1 text before the first NUL byte.
+fn keyword(chunk: &ChunkRef) -> Option<String> {
+    if !TEXT_CHUNKS.contains(&chunk.chunk_type().to_string().as_str()) {
+        return None;
+    }
+    let data = chunk.data();
+    let end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+    Some(data[..end].iter().map(|&b| b as char).collect())
+}
+
+impl ChunkSelector {
+    /// Returns true if the chunk at `index` of its PNG is selected.
+    pub fn matches(&self, index: usize, chunk: &ChunkRef) -> bool {
+        match self {
+            Self::Type(chunk_type) => chunk.chunk_type() == chunk_type,
+            Self::Glob(pattern) => glob_match(pattern, &chunk.chunk_type().to_string()),
+            Self::Flag(flag) => flag.matches(chunk.chunk_type()),
+            Self::Index { start, end } => {
+                start.is_none_or(|start| index >= start) && end.is_none_or(|end| index < end)
+            }
+            Self::Size(comparison, size) => comparison.matches(chunk.length(), *size),
+            Self::Keyword(pattern) => keyword(chunk).is_some_and(|k| glob_match(pattern, &k)),
+            Self::Not(selector) => !selector.matches(index, chunk),
+            Self::And(a, b) => a.matches(index, chunk) && b.matches(index, chunk),
+            Self::Or(a, b) => a.matches(index, chunk) || b.matches(index, chunk),
+        }
+    }
+
+    /// Returns the selected chunks of a PNG with their indices.
+    pub fn select<'p>(&'p self, png: &'p Png) -> impl Iterator<Item = (usize, ChunkRef<'p>)> + 'p {
+        png.chunks()
+            .enumerate()
+            .filter(|(index, chunk)| self.matches(*index, chunk))
+    }
+
+    /// Returns the chunk type if this selector matches exactly one chunk type and nothing
+    /// else.
+    pub fn exact_type(&self) -> Option<ChunkType> {
+        match self {
+            Self::Type(chunk_type) => Some(*chunk_type),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token<'s> {
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+    Term(&'s str),
+}
+
+struct Parser<'s> {
+    text: &'s str,
+    chars: Peekable<CharIndices<'s>>,
+}
+
+impl<'s> Parser<'s> {
+    /// Returns the next token and where it starts.
+    fn next_token(&mut self) -> Result<Option<(usize, Token<'s>)>, SelectorParseError> {
+        while self.chars.next_if(|(_, c)| c.is_whitespace()).is_some() {}
+        let Some((start, c)) = self.chars.next() else {
+            return Ok(None);
+        };
+        let token = match c {
+            '(' => Token::Open,
+            ')' => Token::Close,
+            '!' => Token::Not,
+            ',' => Token::Or,
+            '&' | '|' => match self.chars.next_if(|&(_, next)| next == c) {
+                Some(_) if c == '&' => Token::And,
+                Some(_) => Token::Or,
+                None => {
+                    return Err(SelectorParseError::UnexpectedToken {
+                        position: start,
+                        token: c.to_string(),
+                    })
+                }
+            },
+            _ => {
+                let mut end = start + c.len_utf8();
+                while let Some((i, c)) = self
+                    .chars
+                    .next_if(|(_, c)| !c.is_whitespace() && !"()&|,".contains(*c))
+                {
+                    end = i + c.len_utf8();
+                }
+                Token::Term(&self.text[start..end])
+            }
+        };
+        Ok(Some((start, token)))
+    }
+
+    fn peek_token(&mut self) -> Result<Option<(usize, Token<'s>)>, SelectorParseError> {
+        let chars = self.chars.clone();
+        let token = self.next_token();
+        self.chars = chars;
+        token
+    }
+
+    fn parse_or(&mut self) -> Result<ChunkSelector, SelectorParseError> {
+        let mut selector = self.parse_and()?;
+        while let Some((_, Token::Or)) = self.peek_token()? {
+            self.next_token()?;
+            selector = ChunkSelector::Or(Box::new(selector), Box::new(self.parse_and()?));
+        }
+        Ok(selector)
+    }
+
+    fn parse_and(&mut self) -> Result<ChunkSelector, SelectorParseError> {
+        let mut selector = self.parse_unary()?;
+        while let Some((_, Token::And)) = self.peek_token()? {
+            self.next_token()?;
+            selector = ChunkSelector::And(Box::new(selector), Box::new(self.parse_unary()?));
+        }
+        Ok(selector)
+    }
+
+    fn parse_unary(&mut self) -> Result<ChunkSelector, SelectorParseError> {
+        match self.next_token()? {
+            Some((_, Token::Not)) => Ok(ChunkSelector::Not(Box::new(self.parse_unary()?))),
+            Some((_, Token::Open)) => {
+                let selector = self.parse_or()?;
+                match self.next_token()? {
+                    Some((_, Token::Close)) => Ok(selector),
+                    Some((position, token)) => Err(unexpected(position, &token)),
+                    None => Err(SelectorParseError::UnexpectedEnd),
+                }
+            }
+            Some((position, Token::Term(term))) => {
+                parse_term(term).ok_or_else(|| SelectorParseError::InvalidTerm {
+                    position,
+                    term: term.to_string(),
+                })
+            }
+            Some((position, token)) => Err(unexpected(position, &token)),
+            None => Err(SelectorParseError::UnexpectedEnd),
+        }
+    }
+}
+
+fn unexpected(position: usize, token: &Token) -> SelectorParseError {
+    let token = match token {
+        Token::And => "&&",
+        Token::Or => "||",
+        Token::Not => "!",
+        Token::Open => "(",
+        Token::Close => ")",
+        Token::Term(term) => *term,
+    };
+    SelectorParseError::UnexpectedToken {
+        position,
+        token: token.to_string(),
+    }
+}
+
+/// Parses `start..end`, `start..=end`, `start..`, `..end` or a single index.
+fn parse_range(range: &str) -> Option<(Option<usize>, Option<usize>)> {
+    let parse_bound = |s: &str| match s {
+        "" => Some(None),
+        s => s.parse().ok().map(Some),
+    };
+    if let Some((start, end)) = range.split_once("..=") {
+        let end: usize = end.parse().ok()?;
+        Some((parse_bound(start)?, Some(end.checked_add(1)?)))
+    } else if let Some((start, end)) = range.split_once("..") {
+        Some((parse_bound(start)?, parse_bound(end)?))
+    } else {
+        let index: usize = range.parse().ok()?;
+        Some((Some(index), Some(index.checked_add(1)?)))
+    }
+}
+
+fn parse_size(rest: &str) -> Option<ChunkSelector> {
+    let (comparison, size) = [
+        ("<=", Comparison::Le),
+        (">=", Comparison::Ge),
+        ("==", Comparison::Eq),
+        ("!=", Comparison::Ne),
+        ("<", Comparison::Lt),
+        (">", Comparison::Gt),
+        ("=", Comparison::Eq),
+    ]
+    .iter()
+    .find_map(|(op, comparison)| rest.strip_prefix(op).map(|size| (*comparison, size)))?;
+    Some(ChunkSelector::Size(comparison, size.parse().ok()?))
+}
+
+fn parse_term(term: &str) -> Option<ChunkSelector> {
+    if let Some(flag) = ChunkFlag::from_name(term) {
+        return Some(ChunkSelector::Flag(flag));
+    }
+    if let Some(range) = term.strip_prefix("index:") {
+        let (start, end) = parse_range(range)?;
+        return Some(ChunkSelector::Index { start, end });
+    }
+    if let Some(rest) = term.strip_prefix("size") {
+        return parse_size(rest);
+    }
+    if let Some(pattern) = term.strip_prefix("keyword:") {
+        return Some(ChunkSelector::Keyword(pattern.to_string()));
+    }
+    if term.contains(['*', '?', '[']) {
+        return Some(ChunkSelector::Glob(term.to_string()));
+    }
+    ChunkType::from_str(term).ok().map(ChunkSelector::Type)
+}
+
+impl FromStr for ChunkSelector {
+    type Err = SelectorParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser {
+            text: s,
+            chars: s.char_indices().peekable(),
+        };
+        if parser.peek_token()?.is_none() {
+            return Err(SelectorParseError::Empty);
+        }
+        let selector = parser.parse_or()?;
+        match parser.next_token()? {
+            None => Ok(selector),
+            Some((position, token)) => Err(unexpected(position, &token)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::chunk::Chunk;
+
+    fn testing_png() -> Png<'static> {
+        let chunks = [
+            ("IHDR", &[0u8; 13][..]),
+            ("tEXt", b"Title\0Dice"),
+            ("tEXt", b"Software\0pngme"),
+            ("prIv", b"secret"),
+            ("IDAT", &[0; 100]),
+            ("IEND", b""),
+        ]
+        .iter()
+        .map(|(t, d)| Chunk::new(ChunkType::from_str(t).unwrap(), d.to_vec()))
+        .collect();
+        Png::from_chunks(chunks)
+    }
+
+    fn selected(selector: &str) -> Vec<usize> {
+        let png = testing_png();
+        let selector = ChunkSelector::from_str(selector).unwrap();
+        selector.select(&png).map(|(index, _)| index).collect()
+    }
+
+    #[test]
+    fn test_exact_type() {
+        assert_eq!(selected("tEXt"), [1, 2]);
+        assert_eq!(selected("IEND"), [5]);
+        assert_eq!(
+            ChunkSelector::from_str("IDAT").unwrap().exact_type(),
+            ChunkType::from_str("IDAT").ok()
+        );
+    }
+
+    #[test]
+    fn test_glob() {
+        assert_eq!(selected("I*"), [0, 4, 5]);
+        assert_eq!(selected("?EXt"), [1, 2]);
+        assert_eq!(selected("[a-z]*"), [1, 2, 3]);
+        assert!(glob_match("[!I]*", "tEXt"));
+        assert!(!glob_match("[!I]*", "IDAT"));
+    }
+
+    #[test]
+    fn test_flags() {
+        assert_eq!(selected("ancillary && private"), [3]);
+        assert_eq!(selected("critical"), [0, 4, 5]);
+        assert_eq!(selected("!critical && !private"), [1, 2]);
+        assert_eq!(selected("unsafe-to-copy"), [0, 4, 5]);
+    }
+
+    #[test]
+    fn test_index_ranges() {
+        assert_eq!(selected("index:2"), [2]);
+        assert_eq!(selected("index:1..3"), [1, 2]);
+        assert_eq!(selected("index:1..=3"), [1, 2, 3]);
+        assert_eq!(selected("index:4.."), [4, 5]);
+        assert_eq!(selected("index:..1"), [0]);
+    }
+
+    #[test]
+    fn test_size() {
+        assert_eq!(selected("size>=100"), [4]);
+        assert_eq!(selected("size==0"), [5]);
+        assert_eq!(selected("size<10 && ancillary"), [3]);
+        assert_eq!(selected("size!=13 && critical"), [4, 5]);
+    }
+
+    #[test]
+    fn test_keyword() {
+        assert_eq!(selected("keyword:Title"), [1]);
+        assert_eq!(selected("keyword:Soft*"), [2]);
+        assert_eq!(selected("keyword:*"), [1, 2]);
+    }
+
+    #[test]
+    fn test_precedence_and_lists() {
+        assert_eq!(selected("IHDR || prIv && size>100"), [0]);
+        assert_eq!(selected("(IHDR || prIv) && size<100"), [0, 3]);
+        assert_eq!(selected("IHDR,IEND"), [0, 5]);
+        assert_eq!(selected("!(tEXt || critical)"), [3]);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(ChunkSelector::from_str(" "), Err(SelectorParseError::Empty));
+        assert_eq!(
+            ChunkSelector::from_str("tEXt &&"),
+            Err(SelectorParseError::UnexpectedEnd)
+        );
+        assert_eq!(
+            ChunkSelector::from_str("(tEXt"),
+            Err(SelectorParseError::UnexpectedEnd)
+        );
+        assert!(matches!(
+            ChunkSelector::from_str("tEXt & IHDR"),
+            Err(SelectorParseError::UnexpectedToken { position: 5, .. })
+        ));
+        assert!(matches!(
+            ChunkSelector::from_str("size>big"),
+            Err(SelectorParseError::InvalidTerm { position: 0, .. })
+        ));
+        assert!(matches!(
+            ChunkSelector::from_str("tEXt)"),
+            Err(SelectorParseError::UnexpectedToken { position: 4, .. })
+        ));
+        assert!(ChunkSelector::from_str("toolong").is_err());
+        for term in [
+            "index:18446744073709551615",
+            "index:0..=18446744073709551615",
+        ] {
+            assert!(matches!(
+                ChunkSelector::from_str(term),
+                Err(SelectorParseError::InvalidTerm { position: 0, .. })
+            ));
+        }
+    }
+}
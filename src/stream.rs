@@ -0,0 +1,621 @@
+use std::{
+    fmt::{self, Display},
+    io::{self, Read, Seek, SeekFrom, Write},
+};
+
+use crate::{
+    chunk::{Chunk, ChunkDecodeError, ChunkRef, CRC_SIZE, LEN_SIZE},
+    chunk_type::{ChunkType, CHUNK_TYPE_SIZE},
+    decoder::{ChunkDecoder, DecodeEvent},
+    limits::{DecodeLimits, LimitError},
+    png::{Png, PngDecodeError},
+};
+
+#[derive(Debug)]
+pub enum StreamError {
+    Io(io::Error),
+    Decode(PngDecodeError),
+}
+
+impl Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => writeln!(f, "Read error: {err}"),
+            Self::Decode(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl From<io::Error> for StreamError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<PngDecodeError> for StreamError {
+    fn from(err: PngDecodeError) -> Self {
+        Self::Decode(err)
+    }
+}
+
+impl From<LimitError> for StreamError {
+    fn from(err: LimitError) -> Self {
+        Self::Decode(PngDecodeError::LimitExceeded(err))
+    }
+}
+
+/// The most bytes a reader reads at once.
+const BUF_SIZE: usize = 8192;
+
+/// Where a chunk whose data was skipped lies in a stream, and the CRC stored with it, which
+/// has not been checked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkLocation {
+    pub chunk_type: ChunkType,
+    /// The offset of the chunk's length field.
+    pub offset: u64,
+    pub length: u32,
+    pub crc: u32,
+}
+
+impl ChunkLocation {
+    /// Reads the chunk from a stream in which its offset is counted from `start`, checking
+    /// its CRC.
+    pub fn read_from<R: Read + Seek>(
+        &self,
+        reader: &mut R,
+        start: u64,
+    ) -> Result<Chunk, StreamError> {
+        let data_start = start + self.offset + (LEN_SIZE + CHUNK_TYPE_SIZE) as u64;
+        reader.seek(SeekFrom::Start(data_start))?;
+        let mut data = vec![0; self.length as usize];
+        reader.read_exact(&mut data)?;
+
+        let chunk = Chunk::new(self.chunk_type, data);
+        if chunk.crc() != self.crc {
+            return Err(PngDecodeError::from(ChunkDecodeError::CrcMismatch {
+                offset: self.offset,
+                expected_crc: chunk.crc(),
+                given_crc: self.crc,
+            })
+            .into());
+        }
+        Ok(chunk)
+    }
+}
+
+/// A chunk read by a lazy reader, either in full or with its data left in the stream.
+#[derive(Debug, Clone)]
+pub enum LazyChunk {
+    Loaded(Chunk),
+    /// A chunk whose data was skipped, with its offset counted from where reading started.
+    Deferred(ChunkLocation),
+}
+
+impl LazyChunk {
+    pub fn chunk_type(&self) -> &ChunkType {
+        match self {
+            Self::Loaded(chunk) => chunk.chunk_type(),
+            Self::Deferred(location) => &location.chunk_type,
+        }
+    }
+
+    pub fn length(&self) -> u32 {
+        match self {
+            Self::Loaded(chunk) => chunk.length(),
+            Self::Deferred(location) => location.length,
+        }
+    }
+}
+
+/// A chunk being read.
+#[derive(Debug)]
+struct PartialChunk {
+    chunk_type: ChunkType,
+    offset: u64,
+    length: u32,
+    /// The data read so far, or `None` if the data is skipped.
+    data: Option<Vec<u8>>,
+}
+
+/// The state shared by the blocking and async readers: a `ChunkDecoder` and the chunk it
+/// is assembling. A reader reads into `buf`, which is never longer than what is left of the
+/// current chunk, hands the number of bytes read to `feed`, and calls `end_of_stream` when
+/// the stream runs out. Lazy readers also seek past the bytes returned by `take_skip`.
+#[derive(Debug)]
+pub(crate) struct ReadState {
+    decoder: ChunkDecoder,
+    chunk: Option<PartialChunk>,
+    buf: Box<[u8]>,
+    limits: DecodeLimits,
+    /// Whether the data of chunks of a type is skipped.
+    defer: fn(&ChunkType) -> bool,
+    skip: u64,
+}
+
+impl ReadState {
+    pub(crate) fn new(limits: DecodeLimits) -> Self {
+        Self {
+            decoder: ChunkDecoder::new().with_limits(limits),
+            chunk: None,
+            buf: vec![0; BUF_SIZE].into_boxed_slice(),
+            limits,
+            defer: |_| false,
+            skip: 0,
+        }
+    }
+
+    /// Skips the data of chunks for which `defer` returns true, which are returned as
+    /// `LazyChunk::Deferred`.
+    pub(crate) fn deferring(self, defer: fn(&ChunkType) -> bool) -> Self {
+        Self { defer, ..self }
+    }
+
+    /// Returns true after IEND or an error.
+    pub(crate) fn is_done(&self) -> bool {
+        self.decoder.is_done()
+    }
+
+    /// The buffer to read the next bytes into.
+    pub(crate) fn buf(&mut self) -> &mut [u8] {
+        let wanted = self.decoder.wanted().min(BUF_SIZE);
+        &mut self.buf[..wanted]
+    }
+
+    /// Decodes the first `read` bytes of the buffer, returning a chunk once one is
+    /// complete.
+    pub(crate) fn feed(&mut self, read: usize) -> Result<Option<LazyChunk>, PngDecodeError> {
+        let mut bytes = &self.buf[..read];
+        let mut chunk = None;
+        while !bytes.is_empty() && !self.decoder.is_done() {
+            let (used, event) = self.decoder.decode(bytes)?;
+            bytes = &bytes[used..];
+            match event {
+                Some(DecodeEvent::ChunkHeader {
+                    offset,
+                    length,
+                    chunk_type,
+                }) => {
+                    let data = match (self.defer)(&chunk_type) {
+                        true => {
+                            self.skip = self.decoder.skip_data();
+                            None
+                        }
+                        // Grow as data arrives rather than trusting the length up front
+                        false => Some(Vec::with_capacity((length as usize).min(BUF_SIZE))),
+                    };
+                    self.chunk = Some(PartialChunk {
+                        chunk_type,
+                        offset,
+                        length,
+                        data,
+                    });
+                }
+                Some(DecodeEvent::ChunkData(data)) => {
+                    let chunk = self.chunk.as_mut().unwrap();
+                    chunk.data.as_mut().unwrap().extend_from_slice(data);
+                }
+                Some(DecodeEvent::ChunkEnd { crc }) => {
+                    let partial = self.chunk.take().unwrap();
+                    chunk = Some(match partial.data {
+                        Some(data) => LazyChunk::Loaded(Chunk::new(partial.chunk_type, data)),
+                        None => LazyChunk::Deferred(ChunkLocation {
+                            chunk_type: partial.chunk_type,
+                            offset: partial.offset,
+                            length: partial.length,
+                            crc,
+                        }),
+                    });
+                }
+                Some(DecodeEvent::Signature) | None => {}
+            }
+        }
+        Ok(chunk)
+    }
+
+    /// Returns the number of bytes a lazy reader must seek past before reading on.
+    pub(crate) fn take_skip(&mut self) -> u64 {
+        std::mem::take(&mut self.skip)
+    }
+
+    pub(crate) fn end_of_stream(&mut self) -> Result<(), PngDecodeError> {
+        self.decoder.finish()
+    }
+
+    /// The most bytes that may follow the chunks read so far.
+    pub(crate) fn remaining_size(&self) -> u64 {
+        self.limits
+            .max_total_size
+            .saturating_sub(self.decoder.position())
+    }
+
+    /// Builds a PNG from the chunks read and the bytes that followed them, checking the
+    /// limits that apply to the whole file.
+    pub(crate) fn finish(
+        &self,
+        chunks: Vec<Chunk>,
+        trailing: Vec<u8>,
+    ) -> Result<Png<'static>, StreamError> {
+        self.limits
+            .check_total_size(self.decoder.position() + trailing.len() as u64)?;
+        let mut png = Png::from_chunks(chunks);
+        png.set_trailing_data(trailing);
+        self.limits.check_png(&png)?;
+        Ok(png)
+    }
+}
+
+/// The bytes written before and after the data of a chunk: its length and type, and its
+/// CRC.
+pub(crate) fn chunk_frame(chunk: &ChunkRef) -> ([u8; LEN_SIZE + CHUNK_TYPE_SIZE], [u8; CRC_SIZE]) {
+    let mut header = [0; LEN_SIZE + CHUNK_TYPE_SIZE];
+    header[..LEN_SIZE].copy_from_slice(&chunk.length().to_be_bytes());
+    header[LEN_SIZE..].copy_from_slice(&chunk.chunk_type().bytes());
+    (header, chunk.crc().to_be_bytes())
+}
+
+/// Reads the next bytes of a stream into the state, returning the chunk they completed,
+/// if any.
+fn read_step<R: Read>(
+    reader: &mut R,
+    state: &mut ReadState,
+) -> Result<Option<LazyChunk>, StreamError> {
+    loop {
+        match reader.read(state.buf()) {
+            Ok(0) => {
+                state.end_of_stream()?;
+                return Ok(None);
+            }
+            Ok(read) => return Ok(state.feed(read)?),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+/// Reads the chunks of a PNG from a stream one at a time, checking their CRCs. Reading
+/// stops after IEND, leaving anything that follows in the reader.
+#[derive(Debug)]
+pub struct ChunkReader<R> {
+    inner: R,
+    state: ReadState,
+}
+
+impl<R: Read> ChunkReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self::with_limits(inner, DecodeLimits::UNLIMITED)
+    }
+
+    /// Creates a reader that fails with `LimitExceeded` when the stream exceeds the limits.
+    pub fn with_limits(inner: R, limits: DecodeLimits) -> Self {
+        Self {
+            inner,
+            state: ReadState::new(limits),
+        }
+    }
+
+    /// Reads the next chunk, or returns `None` after IEND or at the end of the stream.
+    pub fn next_chunk(&mut self) -> Result<Option<Chunk>, StreamError> {
+        while !self.state.is_done() {
+            if let Some(LazyChunk::Loaded(chunk)) = read_step(&mut self.inner, &mut self.state)? {
+                return Ok(Some(chunk));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns the underlying reader, positioned after the last chunk read.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read> Iterator for ChunkReader<R> {
+    type Item = Result<Chunk, StreamError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_chunk().transpose()
+    }
+}
+
+/// Reads a whole PNG from a stream, including any bytes after IEND.
+pub fn read_png<R: Read>(reader: R) -> Result<Png<'static>, StreamError> {
+    read_png_with_limits(reader, &DecodeLimits::UNLIMITED)
+}
+
+/// Reads a whole PNG from an untrusted stream, failing with `LimitExceeded` as soon as the
+/// stream exceeds the limits.
+pub fn read_png_with_limits<R: Read>(
+    reader: R,
+    limits: &DecodeLimits,
+) -> Result<Png<'static>, StreamError> {
+    let mut reader = ChunkReader::with_limits(reader, *limits);
+    let chunks = reader.by_ref().collect::<Result<_, _>>()?;
+
+    // One byte past the limit is enough to tell that it was exceeded
+    let mut trailing = Vec::new();
+    let limit = reader.state.remaining_size().saturating_add(1);
+    (&mut reader.inner).take(limit).read_to_end(&mut trailing)?;
+    reader.state.finish(chunks, trailing)
+}
+
+/// A PNG read by `read_png_lazy`, whose image data is only read from the stream when it is
+/// asked for.
+#[derive(Debug)]
+pub struct LazyPng<R> {
+    inner: R,
+    /// The position in the stream where the PNG starts.
+    start: u64,
+    chunks: Vec<LazyChunk>,
+    trailing: Vec<u8>,
+}
+
+impl<R: Read + Seek> LazyPng<R> {
+    pub fn chunks(&self) -> &[LazyChunk] {
+        &self.chunks
+    }
+
+    pub fn trailing_data(&self) -> &[u8] {
+        &self.trailing
+    }
+
+    /// Returns the chunk at `index`, reading the data of a deferred chunk from the stream
+    /// and checking its CRC.
+    pub fn load_chunk(&mut self, index: usize) -> Result<Chunk, StreamError> {
+        match &self.chunks[index] {
+            LazyChunk::Loaded(chunk) => Ok(chunk.clone()),
+            LazyChunk::Deferred(location) => location.read_from(&mut self.inner, self.start),
+        }
+    }
+
+    /// Reads the data of every IDAT chunk, joined in order.
+    pub fn image_data(&mut self) -> Result<Vec<u8>, StreamError> {
+        let mut data = Vec::new();
+        for index in 0..self.chunks.len() {
+            if &self.chunks[index].chunk_type().bytes() == b"IDAT" {
+                data.extend(self.load_chunk(index)?.data());
+            }
+        }
+        Ok(data)
+    }
+
+    /// Reads every deferred chunk to build the whole PNG.
+    pub fn into_png(mut self) -> Result<Png<'static>, StreamError> {
+        let chunks = (0..self.chunks.len())
+            .map(|index| self.load_chunk(index))
+            .collect::<Result<_, _>>()?;
+        let mut png = Png::from_chunks(chunks);
+        png.set_trailing_data(self.trailing);
+        Ok(png)
+    }
+}
+
+/// Reads the chunks of a PNG from a seekable stream, seeking past the data the state
+/// defers.
+pub(crate) fn read_deferred<R: Read + Seek>(
+    reader: &mut R,
+    mut state: ReadState,
+) -> Result<Vec<LazyChunk>, StreamError> {
+    let mut chunks = Vec::new();
+    while !state.is_done() {
+        chunks.extend(read_step(reader, &mut state)?);
+        let skip = state.take_skip();
+        if skip > 0 {
+            reader.seek(SeekFrom::Current(skip as i64))?;
+        }
+    }
+    Ok(chunks)
+}
+
+/// Reads a PNG from a seekable stream without reading its image data. Other chunks are
+/// read and checked as usual, while IDAT chunks are seeked past and only their offsets,
+/// lengths and CRCs are kept.
+pub fn read_png_lazy<R: Read + Seek>(mut reader: R) -> Result<LazyPng<R>, StreamError> {
+    let start = reader.stream_position()?;
+    let state = ReadState::new(DecodeLimits::UNLIMITED)
+        .deferring(|chunk_type| &chunk_type.bytes() == b"IDAT");
+    let chunks = read_deferred(&mut reader, state)?;
+
+    let mut trailing = Vec::new();
+    reader.read_to_end(&mut trailing)?;
+    Ok(LazyPng {
+        inner: reader,
+        start,
+        chunks,
+        trailing,
+    })
+}
+
+/// Writes a PNG to a stream chunk by chunk, without building the whole file in memory.
+#[derive(Debug)]
+pub struct PngWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> PngWriter<W> {
+    /// Creates a writer, writing the PNG signature.
+    pub fn new(mut inner: W) -> io::Result<Self> {
+        inner.write_all(&Png::STANDARD_HEADER)?;
+        Ok(Self { inner })
+    }
+
+    pub fn write_chunk(&mut self, chunk: &ChunkRef) -> io::Result<()> {
+        let (header, crc) = chunk_frame(chunk);
+        self.inner.write_all(&header)?;
+        self.inner.write_all(chunk.data())?;
+        self.inner.write_all(&crc)
+    }
+
+    /// Writes bytes after the last chunk, which should be IEND.
+    pub fn write_trailing_data(&mut self, data: &[u8]) -> io::Result<()> {
+        self.inner.write_all(data)
+    }
+
+    /// Flushes the writer and returns the underlying stream.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+/// Writes a whole PNG to a stream, including any bytes after IEND.
+pub fn write_png<W: Write>(writer: W, png: &Png) -> io::Result<W> {
+    let mut writer = PngWriter::new(writer)?;
+    for chunk in png.chunks() {
+        writer.write_chunk(&chunk)?;
+    }
+    writer.write_trailing_data(png.trailing_data())?;
+    writer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const DICE: &[u8] = include_bytes!("../tests/data/dice.png");
+
+    /// A reader that returns at most a few bytes per read, like a slow network stream.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(self.0.len()).min(7);
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut bytes = DICE.to_vec();
+        bytes.extend(b"trailing");
+
+        let png = read_png(Trickle(&bytes)).unwrap();
+        assert_eq!(png.chunks().len(), 7);
+        assert_eq!(png.trailing_data(), b"trailing");
+        assert_eq!(write_png(Vec::new(), &png).unwrap(), bytes);
+    }
+
+    #[test]
+    fn test_chunk_reader_stops_after_iend() {
+        let mut bytes = DICE.to_vec();
+        bytes.extend(b"trailing");
+
+        let mut reader = ChunkReader::new(bytes.as_slice());
+        let types: Vec<_> = reader
+            .by_ref()
+            .map(|chunk| chunk.unwrap().chunk_type().to_string())
+            .collect();
+        assert_eq!(types.first().unwrap(), "IHDR");
+        assert_eq!(types.last().unwrap(), "IEND");
+        assert_eq!(reader.into_inner(), b"trailing");
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(matches!(
+            read_png(&DICE[..5]),
+            Err(StreamError::Decode(PngDecodeError::TooShort(_)))
+        ));
+
+        // Cut off in the middle of the IDAT chunk at 83
+        assert!(matches!(
+            read_png(&DICE[..1000]),
+            Err(StreamError::Decode(PngDecodeError::ChunkDecode(
+                ChunkDecodeError::InvalidChunkSize { offset: 83, .. }
+            )))
+        ));
+
+        let mut bytes = DICE.to_vec();
+        bytes[100] ^= 1;
+        assert!(matches!(
+            read_png(bytes.as_slice()),
+            Err(StreamError::Decode(PngDecodeError::ChunkDecode(
+                ChunkDecodeError::CrcMismatch { offset: 83, .. }
+            )))
+        ));
+
+        // A stream may end between chunks
+        let png = read_png(&DICE[..83]).unwrap();
+        assert_eq!(png.chunks().len(), 4);
+    }
+
+    #[test]
+    fn test_limits() {
+        let limits = DecodeLimits {
+            max_chunk_length: 4000,
+            ..DecodeLimits::default()
+        };
+        assert!(matches!(
+            read_png_with_limits(DICE, &limits),
+            Err(StreamError::Decode(PngDecodeError::ChunkDecode(
+                ChunkDecodeError::LimitExceeded { offset: 83, .. }
+            )))
+        ));
+
+        let mut bytes = DICE.to_vec();
+        bytes.extend([0; 100]);
+        let limits = DecodeLimits {
+            max_total_size: DICE.len() as u64 + 99,
+            ..DecodeLimits::default()
+        };
+        assert!(matches!(
+            read_png_with_limits(bytes.as_slice(), &limits),
+            Err(StreamError::Decode(PngDecodeError::LimitExceeded(
+                LimitError::TotalSize { .. }
+            )))
+        ));
+    }
+
+    #[test]
+    fn test_read_png_lazy() {
+        /// Fails any read that reaches into the IDAT data until it is allowed, with the
+        /// PNG starting at 4.
+        struct NoImageData(Cursor<Vec<u8>>, bool);
+
+        impl Read for NoImageData {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                let position = self.0.position();
+                let reaches_data = position + (buf.len() as u64) > 4 + 91 && position < 4 + 4772;
+                assert!(self.1 || !reaches_data);
+                self.0.read(buf)
+            }
+        }
+
+        impl Seek for NoImageData {
+            fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+                self.0.seek(pos)
+            }
+        }
+
+        let mut bytes = b"junk".to_vec();
+        bytes.extend(DICE);
+        let mut cursor = Cursor::new(bytes);
+        cursor.set_position(4);
+        let mut png = read_png_lazy(NoImageData(cursor, false)).unwrap();
+        assert_eq!(png.chunks().len(), 7);
+        assert!(matches!(
+            png.chunks()[4],
+            LazyChunk::Deferred(ChunkLocation {
+                offset: 83,
+                length: 4681,
+                ..
+            })
+        ));
+        assert_eq!(png.chunks()[5].chunk_type().to_string(), "RuSt");
+
+        png.inner.1 = true;
+        png.inner.0.get_mut()[4 + 100] ^= 1;
+        assert!(matches!(
+            png.image_data(),
+            Err(StreamError::Decode(PngDecodeError::ChunkDecode(
+                ChunkDecodeError::CrcMismatch { offset: 83, .. }
+            )))
+        ));
+        png.inner.0.get_mut()[4 + 100] ^= 1;
+        assert_eq!(png.image_data().unwrap(), &DICE[91..4772]);
+        assert_eq!(png.into_png().unwrap().as_bytes(), DICE);
+    }
+}
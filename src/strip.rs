@@ -0,0 +1,307 @@
+use std::io::{self, Write};
+
+use clap::ValueEnum;
+use serde::Serialize;
+
+use crate::{
+    chunk::{Chunk, ChunkRef},
+    chunk_type::ChunkType,
+    exif::{self, ExifError},
+    png::Png,
+    report::{self, OutputFormat, SCHEMA_VERSION},
+    selector::ChunkSelector,
+};
+
+/// A predefined set of chunks to strip. Critical chunks are never stripped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum StripPreset {
+    /// Every ancillary chunk
+    Ancillary,
+    /// Every chunk that is not safe to copy
+    Unsafe,
+    /// Every private chunk
+    Private,
+}
+
+/// Which chunks `strip` removes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StripPolicy {
+    Preset(StripPreset),
+    /// Remove the ancillary chunks matching a selector.
+    Select(ChunkSelector),
+    /// Keep only critical chunks and chunks of these types.
+    Keep(Vec<ChunkType>),
+}
+
+impl StripPolicy {
+    /// Returns true if the chunk at `index` should be removed.
+    pub fn strips(&self, index: usize, chunk: &ChunkRef) -> bool {
+        let chunk_type = chunk.chunk_type();
+        if chunk_type.is_critical() {
+            return false;
+        }
+        match self {
+            Self::Select(selector) => selector.matches(index, chunk),
+            Self::Preset(StripPreset::Ancillary) => true,
+            Self::Preset(StripPreset::Unsafe) => !chunk_type.is_safe_to_copy(),
+            Self::Preset(StripPreset::Private) => !chunk_type.is_public(),
+            Self::Keep(types) => !types.contains(chunk_type),
+        }
+    }
+}
+
+/// A chunk removed by `strip`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct StrippedChunk {
+    /// The index the chunk had before stripping.
+    pub index: usize,
+    #[serde(rename = "type")]
+    pub chunk_type: String,
+    pub length: u32,
+}
+
+/// What `strip` removed from a PNG.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct StripReport {
+    pub removed: Vec<StrippedChunk>,
+    /// The number of GPS fields removed from eXIf chunks.
+    pub gps_fields_removed: usize,
+    /// The number of bytes removed after IEND.
+    pub trailing_bytes_removed: usize,
+    /// The types of the unknown unsafe-to-copy chunks dropped because the image changed.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub dropped: Vec<String>,
+}
+
+impl StripReport {
+    pub fn is_empty(&self) -> bool {
+        self.removed.is_empty()
+            && self.gps_fields_removed == 0
+            && self.trailing_bytes_removed == 0
+            && self.dropped.is_empty()
+    }
+}
+
+/// Removes the chunks selected by `policy` from a PNG, with `gps`, the GPS fields of any
+/// remaining eXIf chunk, and with `trailing`, any bytes after IEND.
+pub fn strip(
+    png: &mut Png,
+    policy: Option<&StripPolicy>,
+    gps: bool,
+    trailing: bool,
+) -> Result<StripReport, ExifError> {
+    let mut report = StripReport::default();
+    let dropped_before = png.dropped_chunks().len();
+
+    if let Some(policy) = policy {
+        let mut index = 0;
+        report.removed = png
+            .retain_chunks(|chunk| {
+                index += 1;
+                !policy.strips(index - 1, chunk)
+            })
+            .into_iter()
+            .map(|(index, chunk)| StrippedChunk {
+                index,
+                chunk_type: chunk.chunk_type().to_string(),
+                length: chunk.length(),
+            })
+            .collect();
+    }
+
+    if gps {
+        let exif_chunks: Vec<_> = png
+            .chunks()
+            .enumerate()
+            .filter(|(_, chunk)| chunk.chunk_type().to_string() == "eXIf")
+            .map(|(index, chunk)| (index, chunk.to_chunk()))
+            .collect();
+        for (index, chunk) in exif_chunks {
+            let mut data = chunk.data().to_vec();
+            let removed = exif::strip_gps(&mut data)?;
+            if removed > 0 {
+                png.replace_chunk(index, Chunk::new(*chunk.chunk_type(), data));
+                report.gps_fields_removed += removed;
+            }
+        }
+    }
+
+    if trailing {
+        report.trailing_bytes_removed = png.strip_trailing_data().len();
+    }
+
+    report.dropped = png.dropped_chunks()[dropped_before..]
+        .iter()
+        .map(|chunk| chunk.chunk_type().to_string())
+        .collect();
+    Ok(report)
+}
+
+#[derive(Debug, Serialize)]
+struct StripDocument<'a> {
+    schema_version: u32,
+    file: &'a str,
+    #[serde(flatten)]
+    report: &'a StripReport,
+}
+
+/// Writes what was stripped from a PNG in the given format.
+pub fn write_report<W: Write>(
+    w: &mut W,
+    format: OutputFormat,
+    file: &str,
+    strip_report: &StripReport,
+) -> io::Result<()> {
+    match format {
+        OutputFormat::Text | OutputFormat::Table => {
+            for chunk in &strip_report.removed {
+                writeln!(
+                    w,
+                    "Removed {} chunk #{} ({} bytes)",
+                    chunk.chunk_type, chunk.index, chunk.length
+                )?;
+            }
+            if strip_report.gps_fields_removed > 0 {
+                writeln!(
+                    w,
+                    "Removed {} GPS fields from eXIf",
+                    strip_report.gps_fields_removed
+                )?;
+            }
+            if strip_report.trailing_bytes_removed > 0 {
+                writeln!(
+                    w,
+                    "Removed {} bytes after IEND",
+                    strip_report.trailing_bytes_removed
+                )?;
+            }
+            for chunk_type in &strip_report.dropped {
+                writeln!(
+                    w,
+                    "Dropped unsafe-to-copy chunk {chunk_type} because the image data changed"
+                )?;
+            }
+            if strip_report.is_empty() {
+                writeln!(w, "Nothing to remove")?;
+            }
+            Ok(())
+        }
+        OutputFormat::Json | OutputFormat::Ndjson => report::write_json(
+            w,
+            &StripDocument {
+                schema_version: SCHEMA_VERSION,
+                file,
+                report: strip_report,
+            },
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn testing_png() -> Png<'static> {
+        let chunks = ["IHDR", "sRGB", "tEXt", "prIV", "ruSt", "IDAT", "IEND"]
+            .iter()
+            .map(|t| Chunk::new(ChunkType::from_str(t).unwrap(), t.as_bytes().to_vec()))
+            .collect();
+        Png::from_chunks(chunks)
+    }
+
+    fn types(png: &Png) -> Vec<String> {
+        png.chunks().map(|c| c.chunk_type().to_string()).collect()
+    }
+
+    #[test]
+    fn test_presets() {
+        let mut png = testing_png();
+        let policy = StripPolicy::Preset(StripPreset::Ancillary);
+        let report = strip(&mut png, Some(&policy), false, false).unwrap();
+        assert_eq!(types(&png), ["IHDR", "IDAT", "IEND"]);
+        assert_eq!(report.removed.len(), 4);
+        assert_eq!(
+            report.removed[2],
+            StrippedChunk {
+                index: 3,
+                chunk_type: String::from("prIV"),
+                length: 4
+            }
+        );
+
+        let mut png = testing_png();
+        let policy = StripPolicy::Preset(StripPreset::Unsafe);
+        strip(&mut png, Some(&policy), false, false).unwrap();
+        assert_eq!(types(&png), ["IHDR", "tEXt", "ruSt", "IDAT", "IEND"]);
+
+        let mut png = testing_png();
+        let policy = StripPolicy::Preset(StripPreset::Private);
+        strip(&mut png, Some(&policy), false, false).unwrap();
+        assert_eq!(types(&png), ["IHDR", "sRGB", "tEXt", "IDAT", "IEND"]);
+    }
+
+    #[test]
+    fn test_selector() {
+        let mut png = testing_png();
+        let policy = StripPolicy::Select(ChunkSelector::from_str("index:..3 || IDAT").unwrap());
+        strip(&mut png, Some(&policy), false, false).unwrap();
+        assert_eq!(types(&png), ["IHDR", "prIV", "ruSt", "IDAT", "IEND"]);
+    }
+
+    #[test]
+    fn test_allow_list() {
+        let mut png = testing_png();
+        let policy = StripPolicy::Keep(vec![ChunkType::from_str("sRGB").unwrap()]);
+        strip(&mut png, Some(&policy), false, false).unwrap();
+        assert_eq!(types(&png), ["IHDR", "sRGB", "IDAT", "IEND"]);
+    }
+
+    #[test]
+    fn test_strip_gps() {
+        let mut exif = b"II\x2a\x00\x08\x00\x00\x00\x01\x00".to_vec();
+        exif.extend([0x25, 0x88, 4, 0, 1, 0, 0, 0, 26, 0, 0, 0]);
+        exif.extend([0; 4]);
+        exif.extend([1, 0]);
+        exif.extend([0x01, 0x00, 2, 0, 2, 0, 0, 0, b'N', 0, 0, 0]);
+        exif.extend([0; 4]);
+
+        let mut png = testing_png();
+        png.replace_chunk(1, Chunk::new(ChunkType::from_str("eXIf").unwrap(), exif));
+        let report = strip(&mut png, None, true, false).unwrap();
+
+        assert_eq!(report.gps_fields_removed, 1);
+        assert!(report.removed.is_empty());
+        let data = png.chunk_by_type("eXIf").unwrap().data();
+        assert!(!data.contains(&b'N'));
+    }
+
+    #[test]
+    fn test_json_report() {
+        let mut png = testing_png();
+        let policy = StripPolicy::Preset(StripPreset::Private);
+        let report = strip(&mut png, Some(&policy), false, false).unwrap();
+
+        let mut out = Vec::new();
+        write_report(&mut out, OutputFormat::Json, "a.png", &report).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(json["schema_version"], SCHEMA_VERSION);
+        assert_eq!(json["removed"][0]["type"], "prIV");
+        assert_eq!(json["removed"][1]["type"], "ruSt");
+        assert_eq!(json["gps_fields_removed"], 0);
+    }
+
+    #[test]
+    fn test_strip_trailing_data() {
+        let mut png = testing_png();
+        png.set_trailing_data(b"hidden".to_vec());
+        let kept = strip(&mut png, None, false, false).unwrap();
+        assert!(kept.is_empty());
+        assert_eq!(png.trailing_data(), b"hidden");
+
+        let report = strip(&mut png, None, false, true).unwrap();
+        assert_eq!(report.trailing_bytes_removed, 6);
+        assert!(png.trailing_data().is_empty());
+        assert_eq!(png.as_bytes(), testing_png().as_bytes());
+    }
+}
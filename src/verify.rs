@@ -0,0 +1,523 @@
+use std::{
+    fmt::{self, Display},
+    io::{self, Write},
+    ops::Range,
+};
+
+use serde::Serialize;
+
+use crate::{
+    chunk::{self, CRC_SIZE, LEN_SIZE, MAX_LEN, MIN_CHUNK_SIZE},
+    chunk_type::{ChunkType, CHUNK_TYPE_SIZE},
+    image::{self, ImageHeader},
+    png::Png,
+    report::{self, OutputFormat, SCHEMA_VERSION},
+};
+
+/// What is wrong with a PNG file at some offset.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "problem", rename_all = "snake_case")]
+pub enum ProblemKind {
+    /// The file is shorter than the PNG signature.
+    TooShort,
+    InvalidSignature,
+    /// A chunk runs past the end of the file. The data length is unknown if even the length
+    /// field is cut off.
+    TruncatedChunk {
+        length: Option<u32>,
+        available: u64,
+    },
+    LengthTooLarge {
+        length: u32,
+    },
+    /// The chunk type has bytes other than ASCII letters, shown with non-letters escaped.
+    InvalidChunkType {
+        chunk_type: String,
+    },
+    ReservedBitSet {
+        chunk_type: String,
+    },
+    /// CRCs are 8 lowercase hex digits.
+    CrcMismatch {
+        chunk_type: String,
+        expected_crc: String,
+        given_crc: String,
+    },
+    /// A chunk that must come first does not.
+    MisplacedChunk {
+        chunk_type: String,
+    },
+    MissingChunk {
+        chunk_type: String,
+    },
+    /// Other chunks come between the IDAT chunks.
+    SplitImageData,
+    InvalidImageHeader {
+        message: String,
+    },
+    /// The zlib stream in the IDAT chunks does not inflate.
+    CorruptImageData {
+        message: String,
+    },
+    /// The zlib stream in the IDAT chunks ends before its end marker.
+    IncompleteImageData,
+    /// Bytes follow the end of the zlib stream in the IDAT chunks.
+    ExtraImageData {
+        length: u64,
+    },
+    /// The inflated image data does not have the size implied by IHDR.
+    ImageDataLength {
+        expected: u64,
+        actual: u64,
+    },
+    /// Bytes follow the IEND chunk.
+    TrailingData {
+        length: u64,
+    },
+}
+
+/// A problem found by `verify`, at a byte offset in the file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Problem {
+    pub offset: u64,
+    #[serde(flatten)]
+    pub kind: ProblemKind,
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#x}: ", self.offset)?;
+        match &self.kind {
+            ProblemKind::TooShort => writeln!(f, "File too short for a PNG signature"),
+            ProblemKind::InvalidSignature => writeln!(f, "Invalid PNG signature"),
+            ProblemKind::TruncatedChunk {
+                length: Some(length),
+                available,
+            } => writeln!(
+                f,
+                "Chunk with {length} bytes of data truncated to {available} bytes"
+            ),
+            ProblemKind::TruncatedChunk {
+                length: None,
+                available,
+            } => writeln!(f, "Chunk truncated to {available} bytes"),
+            ProblemKind::LengthTooLarge { length } => {
+                writeln!(f, "Chunk length {length} exceeds maximum of {MAX_LEN}")
+            }
+            ProblemKind::InvalidChunkType { chunk_type } => {
+                writeln!(f, "Invalid chunk type {chunk_type}")
+            }
+            ProblemKind::ReservedBitSet { chunk_type } => {
+                writeln!(f, "Reserved bit set in chunk type {chunk_type}")
+            }
+            ProblemKind::CrcMismatch {
+                chunk_type,
+                expected_crc,
+                given_crc,
+            } => writeln!(
+                f,
+                "CRC mismatch in {chunk_type}: {expected_crc} (expected) vs {given_crc} (given)"
+            ),
+            ProblemKind::MisplacedChunk { chunk_type } => {
+                writeln!(f, "{chunk_type} is not the first chunk")
+            }
+            ProblemKind::MissingChunk { chunk_type } => writeln!(f, "Missing {chunk_type} chunk"),
+            ProblemKind::SplitImageData => writeln!(f, "IDAT chunks are not consecutive"),
+            ProblemKind::InvalidImageHeader { message } => writeln!(f, "{message}"),
+            ProblemKind::CorruptImageData { message } => {
+                writeln!(f, "Image data does not inflate: {message}")
+            }
+            ProblemKind::IncompleteImageData => writeln!(f, "Image data ends early"),
+            ProblemKind::ExtraImageData { length } => {
+                writeln!(f, "{length} bytes after the end of the image data")
+            }
+            ProblemKind::ImageDataLength { expected, actual } => writeln!(
+                f,
+                "Image data inflates to {actual} bytes, expected {expected} from IHDR"
+            ),
+            ProblemKind::TrailingData { length } => {
+                writeln!(f, "{length} bytes after IEND")
+            }
+        }
+    }
+}
+
+/// Everything `verify` found wrong with a PNG file.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct VerifyReport {
+    /// The number of chunks read.
+    pub chunks: usize,
+    pub problems: Vec<Problem>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    fn push(&mut self, offset: u64, kind: ProblemKind) {
+        self.problems.push(Problem { offset, kind });
+    }
+}
+
+/// A chunk as found by `verify`, which may not parse as a `ChunkRef`.
+struct RawChunk {
+    offset: u64,
+    chunk_type: [u8; CHUNK_TYPE_SIZE],
+    data: Range<usize>,
+}
+
+impl RawChunk {
+    fn is(&self, chunk_type: &str) -> bool {
+        self.chunk_type == chunk_type.as_bytes()
+    }
+}
+
+fn type_name(chunk_type: &[u8; CHUNK_TYPE_SIZE]) -> String {
+    chunk_type.escape_ascii().to_string()
+}
+
+/// Checks a whole PNG file in a single pass and reports every problem found instead of
+/// stopping at the first one: the signature, the length, type and CRC of every chunk, and
+/// bytes after IEND. If every chunk could be read, the chunk order is checked too, and the
+/// image data must inflate cleanly to the size implied by IHDR.
+pub fn verify(bytes: &[u8]) -> VerifyReport {
+    let mut report = VerifyReport::default();
+    let signature_len = Png::STANDARD_HEADER.len();
+    if bytes.len() < signature_len {
+        report.push(0, ProblemKind::TooShort);
+        return report;
+    }
+    if bytes[..signature_len] != Png::STANDARD_HEADER {
+        report.push(0, ProblemKind::InvalidSignature);
+    }
+
+    let mut chunks = Vec::new();
+    let mut pos = signature_len;
+    let mut complete = true;
+    while pos < bytes.len() {
+        let offset = pos as u64;
+        if chunks.last().is_some_and(|c: &RawChunk| c.is("IEND")) {
+            let length = (bytes.len() - pos) as u64;
+            report.push(offset, ProblemKind::TrailingData { length });
+            break;
+        }
+
+        let rest = &bytes[pos..];
+        let length = rest
+            .get(..LEN_SIZE)
+            .map(|len| u32::from_be_bytes(len.try_into().unwrap()));
+        let available = rest.len() as u64;
+        match length {
+            Some(length) if length as usize > MAX_LEN => {
+                report.push(offset, ProblemKind::LengthTooLarge { length });
+                complete = false;
+                break;
+            }
+            Some(length) if rest.len() >= MIN_CHUNK_SIZE + length as usize => {}
+            length => {
+                report.push(offset, ProblemKind::TruncatedChunk { length, available });
+                complete = false;
+                break;
+            }
+        }
+
+        let data_start = pos + LEN_SIZE + CHUNK_TYPE_SIZE;
+        let chunk = RawChunk {
+            offset,
+            chunk_type: bytes[pos + LEN_SIZE..data_start].try_into().unwrap(),
+            data: data_start..data_start + length.unwrap() as usize,
+        };
+        let chunk_type = type_name(&chunk.chunk_type);
+        match ChunkType::try_from(chunk.chunk_type) {
+            Err(_) => report.push(offset, ProblemKind::InvalidChunkType { chunk_type }),
+            Ok(valid) if !valid.is_reserved_bit_valid() => {
+                report.push(offset, ProblemKind::ReservedBitSet { chunk_type })
+            }
+            Ok(_) => {}
+        }
+
+        let crc_end = chunk.data.end + CRC_SIZE;
+        let given_crc = u32::from_be_bytes(bytes[chunk.data.end..crc_end].try_into().unwrap());
+        let expected_crc = chunk::checksum(&chunk.chunk_type, &bytes[chunk.data.clone()]);
+        if given_crc != expected_crc {
+            report.push(
+                offset,
+                ProblemKind::CrcMismatch {
+                    chunk_type: type_name(&chunk.chunk_type),
+                    expected_crc: format!("{expected_crc:08x}"),
+                    given_crc: format!("{given_crc:08x}"),
+                },
+            );
+        }
+
+        chunks.push(chunk);
+        pos = crc_end;
+    }
+    report.chunks = chunks.len();
+
+    if complete {
+        check_structure(&mut report, &chunks, bytes.len() as u64);
+        check_image_data(&mut report, &chunks, bytes);
+    }
+    report.problems.sort_by_key(|p| p.offset);
+    report
+}
+
+/// Checks that IHDR comes first and that there are consecutive IDAT chunks and an IEND
+/// chunk.
+fn check_structure(report: &mut VerifyReport, chunks: &[RawChunk], file_len: u64) {
+    let missing = |chunk_type: &str| ProblemKind::MissingChunk {
+        chunk_type: String::from(chunk_type),
+    };
+    let end = chunks
+        .iter()
+        .find(|c| c.is("IEND"))
+        .map_or(file_len, |c| c.offset);
+
+    match chunks.iter().position(|c| c.is("IHDR")) {
+        None => report.push(Png::STANDARD_HEADER.len() as u64, missing("IHDR")),
+        Some(0) => {}
+        Some(index) => report.push(
+            chunks[index].offset,
+            ProblemKind::MisplacedChunk {
+                chunk_type: String::from("IHDR"),
+            },
+        ),
+    }
+
+    let idat: Vec<_> = (0..chunks.len())
+        .filter(|&i| chunks[i].is("IDAT"))
+        .collect();
+    match idat.windows(2).find(|pair| pair[1] != pair[0] + 1) {
+        _ if idat.is_empty() => report.push(end, missing("IDAT")),
+        Some(pair) => report.push(chunks[pair[1]].offset, ProblemKind::SplitImageData),
+        None => {}
+    }
+
+    if !chunks.iter().any(|c| c.is("IEND")) {
+        report.push(file_len, missing("IEND"));
+    }
+}
+
+/// Checks that the IDAT chunks hold a single zlib stream inflating to the size implied by
+/// IHDR. Nothing is checked without IHDR or IDAT chunks.
+fn check_image_data(report: &mut VerifyReport, chunks: &[RawChunk], bytes: &[u8]) {
+    let (Some(ihdr), Some(first_idat)) = (
+        chunks.iter().find(|c| c.is("IHDR")),
+        chunks.iter().find(|c| c.is("IDAT")),
+    ) else {
+        return;
+    };
+
+    let header = match ImageHeader::try_from(&bytes[ihdr.data.clone()]) {
+        Ok(header) => header,
+        Err(err) => {
+            let message = err.to_string().trim_end().to_string();
+            report.push(ihdr.offset, ProblemKind::InvalidImageHeader { message });
+            return;
+        }
+    };
+
+    let idat: Vec<u8> = chunks
+        .iter()
+        .filter(|c| c.is("IDAT"))
+        .flat_map(|c| &bytes[c.data.clone()])
+        .copied()
+        .collect();
+    let expected = header.expected_data_len();
+    let offset = first_idat.offset;
+    let summary = match image::inflate_len(&idat, expected) {
+        Ok(summary) => summary,
+        Err(err) => {
+            let message = err.to_string();
+            report.push(offset, ProblemKind::CorruptImageData { message });
+            return;
+        }
+    };
+
+    if !summary.complete && summary.inflated <= expected {
+        report.push(offset, ProblemKind::IncompleteImageData);
+    }
+    if summary.inflated != expected {
+        let actual = summary.inflated;
+        report.push(offset, ProblemKind::ImageDataLength { expected, actual });
+    }
+    if summary.complete && summary.consumed < idat.len() as u64 {
+        let length = idat.len() as u64 - summary.consumed;
+        report.push(offset, ProblemKind::ExtraImageData { length });
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct VerifyDocument<'a> {
+    schema_version: u32,
+    file: &'a str,
+    ok: bool,
+    #[serde(flatten)]
+    report: &'a VerifyReport,
+}
+
+/// Writes the problems found in a PNG file in the given format.
+pub fn write_report<W: Write>(
+    w: &mut W,
+    format: OutputFormat,
+    file: &str,
+    verify_report: &VerifyReport,
+) -> io::Result<()> {
+    match format {
+        OutputFormat::Text | OutputFormat::Table => {
+            for problem in &verify_report.problems {
+                write!(w, "{problem}")?;
+            }
+            match verify_report.problems.len() {
+                0 => writeln!(w, "{file}: OK ({} chunks)", verify_report.chunks),
+                count => writeln!(
+                    w,
+                    "{file}: FAIL ({count} problems in {} chunks)",
+                    verify_report.chunks
+                ),
+            }
+        }
+        OutputFormat::Json | OutputFormat::Ndjson => report::write_json(
+            w,
+            &VerifyDocument {
+                schema_version: SCHEMA_VERSION,
+                file,
+                ok: verify_report.is_ok(),
+                report: verify_report,
+            },
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    use crate::chunk::Chunk;
+
+    const DICE: &[u8] = include_bytes!("../tests/data/dice.png");
+
+    fn kinds(report: &VerifyReport) -> Vec<&ProblemKind> {
+        report.problems.iter().map(|p| &p.kind).collect()
+    }
+
+    #[test]
+    fn test_valid_png() {
+        let report = verify(DICE);
+        assert!(report.is_ok(), "{:?}", report.problems);
+        assert_eq!(report.chunks, 7);
+    }
+
+    #[test]
+    fn test_reports_every_problem() {
+        let mut bytes = DICE.to_vec();
+        // The CRC of sRGB, which starts at 33 with one byte of data
+        bytes[33 + 9] ^= 1;
+        // The type of pHYs, which starts at 62
+        bytes[62 + 4..62 + 8].copy_from_slice(b"pH1s");
+        bytes.extend(b"junk");
+
+        let report = verify(&bytes);
+        let offsets: Vec<_> = report.problems.iter().map(|p| p.offset).collect();
+        assert_eq!(offsets, [33, 62, 62, 4803]);
+        assert!(matches!(
+            kinds(&report)[..],
+            [
+                ProblemKind::CrcMismatch { .. },
+                ProblemKind::InvalidChunkType { .. },
+                ProblemKind::CrcMismatch { .. },
+                ProblemKind::TrailingData { length: 4 },
+            ]
+        ));
+        assert_eq!(report.chunks, 7);
+    }
+
+    #[test]
+    fn test_truncated() {
+        let report = verify(&DICE[..2000]);
+        assert_eq!(
+            report.problems,
+            [Problem {
+                offset: 83,
+                kind: ProblemKind::TruncatedChunk {
+                    length: Some(4681),
+                    available: 1917
+                }
+            }]
+        );
+        assert_eq!(verify(&DICE[..4]).problems[0].kind, ProblemKind::TooShort);
+    }
+
+    #[test]
+    fn test_structure_and_image_data() {
+        let chunk =
+            |t: &str, data: &[u8]| Chunk::new(ChunkType::from_str(t).unwrap(), data.to_vec());
+        let png = Png::try_from(DICE).unwrap();
+        let ihdr = png.chunk_by_type("IHDR").unwrap().to_chunk();
+        let idat = image::idat_data(&png);
+        let (head, tail) = idat.split_at(idat.len() / 2);
+
+        let split = Png::from_chunks(vec![
+            chunk("teXt", b"a"),
+            ihdr.clone(),
+            chunk("IDAT", head),
+            chunk("teXt", b"b"),
+            chunk("IDAT", tail),
+        ]);
+        let report = verify(&split.as_bytes());
+        assert!(matches!(
+            kinds(&report)[..],
+            [
+                ProblemKind::MisplacedChunk { .. },
+                ProblemKind::SplitImageData,
+                ProblemKind::MissingChunk { .. },
+            ]
+        ));
+
+        let short = Png::from_chunks(vec![ihdr, chunk("IDAT", head), chunk("IEND", b"")]);
+        let report = verify(&short.as_bytes());
+        assert!(matches!(
+            kinds(&report)[..],
+            [
+                ProblemKind::IncompleteImageData,
+                ProblemKind::ImageDataLength {
+                    expected: 10050,
+                    ..
+                },
+            ]
+        ));
+    }
+
+    #[test]
+    fn test_oversized_header() {
+        // An interlaced IHDR wider than the spec allows
+        let mut bytes = DICE.to_vec();
+        bytes[16..20].copy_from_slice(&u32::MAX.to_be_bytes());
+        bytes[28] = 1;
+        let crc = chunk::checksum(b"IHDR", &bytes[16..29]);
+        bytes[29..33].copy_from_slice(&crc.to_be_bytes());
+
+        let report = verify(&bytes);
+        assert!(matches!(
+            kinds(&report)[..],
+            [ProblemKind::InvalidImageHeader { .. }]
+        ));
+    }
+
+    #[test]
+    fn test_json_report() {
+        let mut bytes = DICE.to_vec();
+        bytes[33 + 9] ^= 1;
+        let mut out = Vec::new();
+        write_report(&mut out, OutputFormat::Json, "a.png", &verify(&bytes)).unwrap();
+
+        let json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(json["ok"], false);
+        assert_eq!(json["problems"][0]["offset"], 33);
+        assert_eq!(json["problems"][0]["problem"], "crc_mismatch");
+        assert_eq!(json["problems"][0]["chunk_type"], "sRGB");
+    }
+}